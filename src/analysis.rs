@@ -0,0 +1,432 @@
+use crate::instruction::Instruction;
+use crate::platform::Platform;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// Where the recompiler expects programs, those of `Platform::Legacy`.
+pub const START: u16 = 0x200;
+
+/// A straight run of instructions with a single entry at `start`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BasicBlock {
+    pub start: u16,
+    pub instructions: Vec<(u16, Instruction)>,
+    // blocks control can flow to, subroutine calls excluded
+    pub successors: Vec<u16>,
+}
+
+impl BasicBlock {
+    /// Address following the last instruction of the block.
+    pub fn end(&self) -> u16 {
+        self.instructions
+            .last()
+            .map_or(self.start, |(address, instruction)| {
+                address + size(*instruction)
+            })
+    }
+}
+
+/// Control flow of a ROM, found by following every jump, call, return and
+/// skip from where the platform starts programs.
+pub struct Analysis {
+    // where the ROM is loaded and starts
+    start: u16,
+    rom_size: usize,
+    pub blocks: BTreeMap<u16, BasicBlock>,
+    // call graph: routine entry point to the routines it calls, the start
+    // being the main program
+    pub calls: BTreeMap<u16, BTreeSet<u16>>,
+    // BNNN instructions, their targets aren't known statically
+    pub computed_jumps: Vec<u16>,
+    // reachable addresses that don't hold a known instruction
+    pub invalid: Vec<u16>,
+    // every reachable instruction, some possibly overlapping others
+    instructions: BTreeMap<u16, Instruction>,
+    code: Vec<bool>,
+}
+
+// bytes taken up by `instruction`, MegaChip's 01NN NNNN and XO-CHIP's
+// F000 NNNN carrying the address in a second word
+fn size(instruction: Instruction) -> u16 {
+    match instruction {
+        Instruction::LongIndex(_) | Instruction::SetLongIndex => 4,
+        _ => 2,
+    }
+}
+
+// `skipped` being the bytes a skip steps over
+fn successors(address: u16, instruction: Instruction, skipped: u16) -> Vec<u16> {
+    match instruction {
+        Instruction::Jump(nnn) => vec![nnn],
+        Instruction::Call(_) => vec![address + 2],
+        Instruction::Ret | Instruction::JumpV0(_) | Instruction::Unknown(_) => vec![],
+        Instruction::Exit => vec![address],
+        Instruction::SkipEq(..)
+        | Instruction::SkipNe(..)
+        | Instruction::SkipEqReg(..)
+        | Instruction::SkipNeReg(..)
+        | Instruction::SkipKeyDown(_)
+        | Instruction::SkipKeyUp(_)
+        | Instruction::SkipKey2Down(_)
+        | Instruction::SkipKey2Up(_) => vec![address + 2, address + 2 + skipped],
+        // keeps executing itself until a key is pressed
+        Instruction::WaitKey(_) => vec![address, address + 2],
+        _ => vec![address + size(instruction)],
+    }
+}
+
+/// Whether `instruction` transfers control, and so has to be the last one of
+/// its block.
+pub fn ends_block(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Jump(_)
+            | Instruction::Call(_)
+            | Instruction::Ret
+            | Instruction::JumpV0(_)
+            | Instruction::SkipEq(..)
+            | Instruction::SkipNe(..)
+            | Instruction::SkipEqReg(..)
+            | Instruction::SkipNeReg(..)
+            | Instruction::SkipKeyDown(_)
+            | Instruction::SkipKeyUp(_)
+            | Instruction::SkipKey2Down(_)
+            | Instruction::SkipKey2Up(_)
+            | Instruction::WaitKey(_)
+            | Instruction::Unknown(_)
+    )
+}
+
+impl Analysis {
+    /// Follows `rom` as `platform` loads and decodes it.
+    pub fn new(rom: &[u8], platform: Platform) -> Analysis {
+        let start = platform.descriptor().load_address;
+        let end = start as usize + rom.len();
+        let in_rom = |address: u16| address >= start && (address as usize) + 1 < end;
+        let decode = |address: u16| {
+            let offset = (address - start) as usize;
+            platform.decode((rom[offset] as u16) << 8 | rom[offset + 1] as u16)
+        };
+        // XO-CHIP skips all of F000 NNNN
+        let skipped = |address: u16| {
+            let next = address + 2;
+            if platform.descriptor().instructions.xochip
+                && in_rom(next)
+                && decode(next) == Instruction::SetLongIndex
+            {
+                4
+            } else {
+                2
+            }
+        };
+
+        // find every reachable instruction and where blocks have to start
+        let mut instructions = BTreeMap::new();
+        let mut leaders = BTreeSet::new();
+        let mut routines = BTreeSet::new();
+        let mut pending = vec![start];
+        leaders.insert(start);
+        routines.insert(start);
+
+        while let Some(address) = pending.pop() {
+            if !in_rom(address) || instructions.contains_key(&address) {
+                continue;
+            }
+            let instruction = decode(address);
+            instructions.insert(address, instruction);
+
+            let next = successors(address, instruction, skipped(address));
+            if let Instruction::Call(nnn) = instruction {
+                routines.insert(nnn);
+                leaders.insert(nnn);
+                pending.push(nnn);
+            }
+            if ends_block(instruction) {
+                leaders.extend(next.iter().copied());
+            }
+            pending.extend(next);
+        }
+
+        // split the instructions into blocks
+        let mut code = vec![false; rom.len()];
+        let mut blocks = BTreeMap::new();
+        let mut computed_jumps = Vec::new();
+        let mut invalid = Vec::new();
+        for &leader in leaders.iter().filter(|a| instructions.contains_key(a)) {
+            let mut block = BasicBlock {
+                start: leader,
+                instructions: Vec::new(),
+                successors: Vec::new(),
+            };
+            let mut address = leader;
+            while let Some(&instruction) = instructions.get(&address) {
+                block.instructions.push((address, instruction));
+                block.successors = successors(address, instruction, skipped(address));
+                address += size(instruction);
+                if ends_block(instruction) || leaders.contains(&address) {
+                    break;
+                }
+            }
+            blocks.insert(leader, block);
+        }
+        for (&address, &instruction) in &instructions {
+            let offset = (address - start) as usize;
+            let end = (offset + size(instruction) as usize).min(rom.len());
+            for byte in &mut code[offset..end] {
+                *byte = true;
+            }
+            match instruction {
+                Instruction::JumpV0(_) => computed_jumps.push(address),
+                Instruction::Unknown(_) => invalid.push(address),
+                _ => (),
+            }
+        }
+
+        // call graph, walking each routine without following its calls
+        let mut calls = BTreeMap::new();
+        for &routine in &routines {
+            let mut callees = BTreeSet::new();
+            let mut seen = BTreeSet::new();
+            let mut pending = vec![routine];
+            while let Some(start) = pending.pop() {
+                if !seen.insert(start) {
+                    continue;
+                }
+                if let Some(block) = blocks.get(&start) {
+                    for (_, instruction) in &block.instructions {
+                        if let Instruction::Call(nnn) = instruction {
+                            callees.insert(*nnn);
+                        }
+                    }
+                    pending.extend(block.successors.iter().copied());
+                }
+            }
+            calls.insert(routine, callees);
+        }
+
+        Analysis {
+            start,
+            rom_size: rom.len(),
+            blocks,
+            calls,
+            computed_jumps,
+            invalid,
+            instructions,
+            code,
+        }
+    }
+
+    /// Whether `address` is part of a reachable instruction.
+    pub fn is_code(&self, address: u16) -> bool {
+        address >= self.start && self.code.get((address - self.start) as usize) == Some(&true)
+    }
+
+    /// Ranges of the ROM not reached as code, `(first, last)` inclusive.
+    /// Mostly sprites and other data, but code only reached through BNNN
+    /// ends up here as well.
+    pub fn data_ranges(&self) -> Vec<(u16, u16)> {
+        let mut ranges = Vec::new();
+        let mut start = None;
+        for offset in 0..=self.rom_size {
+            let address = self.start + offset as u16;
+            match (start, offset < self.rom_size && !self.code[offset]) {
+                (None, true) => start = Some(address),
+                (Some(first), false) => {
+                    ranges.push((first, address - 1));
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+        ranges
+    }
+
+    /// Listing of the ROM, code disassembled and data as bytes. Bytes of an
+    /// instruction jumped into halfway show up as data.
+    pub fn listing(&self, rom: &[u8]) -> String {
+        let mut out = String::new();
+        let mut offset = 0;
+        while offset < rom.len() {
+            let address = self.start + offset as u16;
+            if let Some(instruction) = self.instructions.get(&address) {
+                let opcode = (rom[offset] as u16) << 8 | rom[offset + 1] as u16;
+                if self.blocks.contains_key(&address) {
+                    writeln!(out, "L{:03X}:", address).unwrap();
+                }
+                writeln!(out, "    {:#05X}  {:04X}  {}", address, opcode, instruction).unwrap();
+                offset += 2;
+            } else {
+                writeln!(
+                    out,
+                    "    {:#05X}  {:02X}    DB {:#04X}",
+                    address, rom[offset], rom[offset]
+                )
+                .unwrap();
+                offset += 1;
+            }
+        }
+        out
+    }
+
+    /// Graphviz rendering of the control flow graph. Calls are dashed edges,
+    /// computed jumps point at a `?` node.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph rom {{").unwrap();
+        writeln!(out, "    node [shape=box, fontname=monospace];").unwrap();
+        for block in self.blocks.values() {
+            let mut label = format!("{:#05X}\\l", block.start);
+            for (address, instruction) in &block.instructions {
+                write!(label, "{:03X}  {}\\l", address, instruction).unwrap();
+            }
+            writeln!(out, "    b{:03X} [label=\"{}\"];", block.start, label).unwrap();
+        }
+        for block in self.blocks.values() {
+            for successor in &block.successors {
+                if self.blocks.contains_key(successor) {
+                    writeln!(out, "    b{:03X} -> b{:03X};", block.start, successor).unwrap();
+                }
+            }
+            for (address, instruction) in &block.instructions {
+                match instruction {
+                    Instruction::Call(nnn) if self.blocks.contains_key(nnn) => writeln!(
+                        out,
+                        "    b{:03X} -> b{:03X} [style=dashed];",
+                        block.start, nnn
+                    )
+                    .unwrap(),
+                    Instruction::JumpV0(_) => {
+                        writeln!(
+                            out,
+                            "    computed{:03X} [label=\"?\", shape=circle];",
+                            address
+                        )
+                        .unwrap();
+                        writeln!(out, "    b{:03X} -> computed{:03X};", block.start, address)
+                            .unwrap();
+                    }
+                    _ => (),
+                }
+            }
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Analysis;
+    use crate::instruction::Instruction;
+    use crate::platform::Platform;
+
+    // 0x200: call 0x20A
+    // 0x202: skip if V0 == 1
+    // 0x204: jump 0x200
+    // 0x206: jump V0 + 0x300
+    // 0x208: sprite data
+    // 0x20A: V0 = 1, return
+    const ROM: [u8; 14] = [
+        0x22, 0x0A, 0x30, 0x01, 0x12, 0x00, 0xB3, 0x00, 0xF0, 0x90, 0x60, 0x01, 0x00, 0xEE,
+    ];
+
+    #[test]
+    fn test_blocks() {
+        let analysis = Analysis::new(&ROM, Platform::Chip8);
+
+        let starts: Vec<u16> = analysis.blocks.keys().copied().collect();
+        assert_eq!(starts, [0x200, 0x202, 0x204, 0x206, 0x20A]);
+        assert_eq!(analysis.blocks[&0x202].successors, [0x204, 0x206]);
+        assert_eq!(
+            analysis.blocks[&0x20A].instructions,
+            [(0x20A, Instruction::Set(0, 1)), (0x20C, Instruction::Ret)]
+        );
+        assert_eq!(analysis.computed_jumps, [0x206]);
+    }
+
+    #[test]
+    fn test_call_graph() {
+        let analysis = Analysis::new(&ROM, Platform::Chip8);
+
+        assert_eq!(
+            analysis.calls[&0x200].iter().copied().collect::<Vec<_>>(),
+            [0x20A]
+        );
+        assert!(analysis.calls[&0x20A].is_empty());
+    }
+
+    #[test]
+    fn test_code_and_data() {
+        let analysis = Analysis::new(&ROM, Platform::Chip8);
+
+        assert!(analysis.is_code(0x207));
+        assert!(!analysis.is_code(0x208));
+        assert_eq!(analysis.data_ranges(), [(0x208, 0x209)]);
+    }
+
+    #[test]
+    fn test_platform() {
+        // CHIP-8X loads at 0x300 and takes BXY0 for colour, not a jump:
+        // colour zones, jump 0x300
+        let rom = [0xB1, 0x20, 0x13, 0x00];
+        let analysis = Analysis::new(&rom, Platform::Chip8X);
+
+        assert!(analysis.is_code(0x303));
+        assert!(analysis.computed_jumps.is_empty());
+        assert!(analysis.listing(&rom).contains("0x300  B120  COL V1, V2\n"));
+    }
+
+    #[test]
+    fn test_overlapping_instructions() {
+        // jump 0x201, which runs 0x0100 and then only a byte is left
+        let rom = [0x12, 0x01, 0x00];
+        let analysis = Analysis::new(&rom, Platform::Chip8);
+
+        assert!(analysis.is_code(0x202));
+        assert_eq!(
+            analysis.listing(&rom),
+            "L200:\n    0x200  1201  JP 0x201\n    0x202  00    DB 0x00\n"
+        );
+    }
+
+    #[test]
+    fn test_long_index_and_second_keypad() {
+        // I = 0x011234, skip if V0 == 0, jump 0x200, return
+        let rom = [0x01, 0x01, 0x12, 0x34, 0x30, 0x00, 0x12, 0x00, 0x00, 0xEE];
+        let analysis = Analysis::new(&rom, Platform::MegaChip);
+
+        assert_eq!(
+            analysis.blocks[&0x200].instructions,
+            [
+                (0x200, Instruction::LongIndex(0x01)),
+                (0x204, Instruction::SkipEq(0, 0))
+            ]
+        );
+        assert_eq!(analysis.blocks[&0x200].successors, [0x206, 0x208]);
+        assert!(analysis.is_code(0x203));
+
+        // CHIP-8X: skip if key V0 of the second keypad is down, jump 0x300,
+        // return
+        let rom = [0xE0, 0xF2, 0x13, 0x00, 0x00, 0xEE];
+        let analysis = Analysis::new(&rom, Platform::Chip8X);
+        assert_eq!(analysis.blocks[&0x300].successors, [0x302, 0x304]);
+        assert!(analysis.blocks.contains_key(&0x304));
+
+        // XO-CHIP: skip if V0 == 0 over I = 0x0300, return
+        let rom = [0x30, 0x00, 0xF0, 0x00, 0x03, 0x00, 0x00, 0xEE];
+        let analysis = Analysis::new(&rom, Platform::XoChip);
+        assert_eq!(analysis.blocks[&0x200].successors, [0x202, 0x206]);
+        assert!(analysis.is_code(0x205));
+        assert!(!analysis.blocks.contains_key(&0x204));
+    }
+
+    #[test]
+    fn test_dot() {
+        let dot = Analysis::new(&ROM, Platform::Chip8).to_dot();
+
+        assert!(dot.starts_with("digraph rom {"));
+        assert!(dot.contains("b202 -> b206;"));
+        assert!(dot.contains("b200 -> b20A [style=dashed];"));
+        assert!(dot.contains("b206 -> computed206;"));
+    }
+}
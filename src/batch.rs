@@ -0,0 +1,144 @@
+use crate::cpu::Cpu;
+use std::thread;
+
+/// Runs many independent machines in lockstep, one frame at a time, e.g. for
+/// training agents or fuzzing.
+///
+/// Machine `n` is seeded with `seed + n`, so a batch replays exactly for the
+/// same seed and inputs, whether it runs on one thread or several.
+pub struct Batch {
+    machines: Vec<Cpu>,
+    threads: usize,
+    // every machine's display after the last step, one after the other
+    screens: Vec<u8>,
+}
+
+impl Batch {
+    pub fn new(rom: &[u8], count: usize, seed: u64) -> Batch {
+        let machines: Vec<Cpu> = (0..count as u64)
+            .map(|n| {
+                let mut cpu = Cpu::new();
+                cpu.seed(seed.wrapping_add(n));
+                cpu.load_rom(rom);
+                cpu
+            })
+            .collect();
+        let mut batch = Batch {
+            machines,
+            threads: 1,
+            screens: Vec::new(),
+        };
+        batch.collect_screens();
+        batch
+    }
+
+    /// Splits the machines across `threads` threads on each step.
+    pub fn with_threads(mut self, threads: usize) -> Batch {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.machines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.machines.is_empty()
+    }
+
+    pub fn machine(&self, n: usize) -> &Cpu {
+        &self.machines[n]
+    }
+
+    pub fn machine_mut(&mut self, n: usize) -> &mut Cpu {
+        &mut self.machines[n]
+    }
+
+    /// Runs a frame on every machine, `keys[n]` being the keys held on
+    /// machine `n`, bit `k` standing for key `k`. Returns the displays of all
+    /// machines stacked, `screen_size()` bytes each.
+    pub fn step(&mut self, keys: &[u16]) -> &[u8] {
+        assert_eq!(keys.len(), self.machines.len(), "one key mask per machine");
+
+        if self.threads == 1 {
+            for (cpu, keys) in self.machines.iter_mut().zip(keys) {
+                run_frame(cpu, *keys);
+            }
+        } else {
+            let chunk = self.machines.len().div_ceil(self.threads).max(1);
+            thread::scope(|scope| {
+                for (machines, keys) in self.machines.chunks_mut(chunk).zip(keys.chunks(chunk)) {
+                    scope.spawn(move || {
+                        for (cpu, keys) in machines.iter_mut().zip(keys) {
+                            run_frame(cpu, *keys);
+                        }
+                    });
+                }
+            });
+        }
+
+        self.collect_screens();
+        &self.screens
+    }
+
+    /// Displays of all machines as of the last step.
+    pub fn screens(&self) -> &[u8] {
+        &self.screens
+    }
+
+    /// Bytes per machine in `screens`.
+    pub fn screen_size(&self) -> usize {
+        self.machines.first().map_or(0, |cpu| cpu.screen().len())
+    }
+
+    fn collect_screens(&mut self) {
+        self.screens.clear();
+        for cpu in &self.machines {
+            self.screens.extend_from_slice(cpu.screen());
+        }
+    }
+}
+
+fn run_frame(cpu: &mut Cpu, keys: u16) {
+    cpu.set_keys(keys);
+    cpu.run_frame();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Batch;
+
+    fn inputs(frame: u16, machines: u16) -> Vec<u16> {
+        (0..machines)
+            .map(|n| 1 << ((frame / 10 + n) % 16))
+            .collect()
+    }
+
+    #[test]
+    fn test_matches_single_machines() {
+        let rom = include_bytes!("../www/roms/TETRIS");
+        let mut batch = Batch::new(rom, 3, 10);
+        let mut single = Batch::new(rom, 1, 12);
+
+        for frame in 0..100 {
+            batch.step(&inputs(frame, 3));
+            single.step(&inputs(frame, 3)[2..]);
+        }
+        let size = batch.screen_size();
+        assert_eq!(batch.screens().len(), 3 * size);
+        assert_eq!(&batch.screens()[2 * size..], single.screens());
+        assert_eq!(batch.machine(2).v, single.machine(0).v);
+    }
+
+    #[test]
+    fn test_threads() {
+        let rom = include_bytes!("../www/roms/BLITZ");
+        let mut sequential = Batch::new(rom, 7, 0);
+        let mut parallel = Batch::new(rom, 7, 0).with_threads(3);
+
+        for frame in 0..100 {
+            let screens = sequential.step(&inputs(frame, 7)).to_vec();
+            assert_eq!(parallel.step(&inputs(frame, 7)), &screens[..]);
+        }
+    }
+}
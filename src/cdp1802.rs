@@ -0,0 +1,399 @@
+/// What the CDP1802 is wired to: memory, the N lines of the I/O instructions
+/// and the EF flag inputs.
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+
+    /// Byte on the data bus during INP `port`, 1 to 7.
+    fn input(&mut self, _port: u8) -> u8 {
+        0
+    }
+
+    /// OUT `port`, 1 to 7, with `value` on the data bus.
+    fn output(&mut self, _port: u8, _value: u8) {}
+
+    /// Whether flag input EF`n`, 1 to 4, is asserted.
+    fn flag(&mut self, _n: u8) -> bool {
+        false
+    }
+}
+
+/// RCA CDP1802 CPU core, as found in the COSMAC VIP.
+///
+/// Durations are counted in machine cycles of 8 clock pulses: two for most
+/// instructions, three for the long branches and skips, one per DMA byte or
+/// interrupt.
+#[derive(Clone, Debug, Default)]
+pub struct Cdp1802 {
+    pub r: [u16; 16],
+    // designates the program counter
+    pub p: u8,
+    // designates the data pointer
+    pub x: u8,
+    pub d: u8,
+    pub df: bool,
+    // interrupt enable
+    pub ie: bool,
+    // X and P saved by an interrupt
+    pub t: u8,
+    pub q: bool,
+    // waiting for an interrupt or DMA after IDL
+    pub idle: bool,
+}
+
+impl Cdp1802 {
+    pub fn new() -> Cdp1802 {
+        let mut cpu = Cdp1802::default();
+        cpu.reset();
+        cpu
+    }
+
+    /// What the CLEAR input does: the other registers are left as they were.
+    pub fn reset(&mut self) {
+        self.p = 0;
+        self.x = 0;
+        self.r[0] = 0;
+        self.q = false;
+        self.ie = true;
+        self.idle = false;
+    }
+
+    /// Executes one instruction, returning the machine cycles it took. An
+    /// idle CPU spends one cycle waiting.
+    pub fn step<B: Bus>(&mut self, bus: &mut B) -> u32 {
+        if self.idle {
+            return 1;
+        }
+        let opcode = self.fetch(bus);
+        let n = (opcode & 0xF) as usize;
+        let x = self.x as usize;
+
+        match opcode >> 4 {
+            // IDL, LDN
+            0x0 if n == 0 => self.idle = true,
+            0x0 => self.d = bus.read(self.r[n]),
+            // INC, DEC
+            0x1 => self.r[n] = self.r[n].wrapping_add(1),
+            0x2 => self.r[n] = self.r[n].wrapping_sub(1),
+            0x3 => {
+                let taken = self.short_condition(n, bus);
+                self.short_branch(bus, taken);
+            }
+            // LDA, STR
+            0x4 => {
+                self.d = bus.read(self.r[n]);
+                self.r[n] = self.r[n].wrapping_add(1);
+            }
+            0x5 => bus.write(self.r[n], self.d),
+            0x6 => match n {
+                // IRX
+                0 => self.r[x] = self.r[x].wrapping_add(1),
+                // OUT
+                1..=7 => {
+                    let value = bus.read(self.r[x]);
+                    bus.output(n as u8, value);
+                    self.r[x] = self.r[x].wrapping_add(1);
+                }
+                // unused on the 1802
+                8 => {}
+                // INP
+                _ => {
+                    let value = bus.input(n as u8 - 8);
+                    bus.write(self.r[x], value);
+                    self.d = value;
+                }
+            },
+            0x7 => self.execute_7(n, bus),
+            // GLO, GHI, PLO, PHI
+            0x8 => self.d = self.r[n] as u8,
+            0x9 => self.d = (self.r[n] >> 8) as u8,
+            0xA => self.r[n] = self.r[n] & 0xFF00 | self.d as u16,
+            0xB => self.r[n] = self.r[n] & 0x00FF | (self.d as u16) << 8,
+            0xC => {
+                self.long_branch(n, bus);
+                return 3;
+            }
+            // SEP, SEX
+            0xD => self.p = n as u8,
+            0xE => self.x = n as u8,
+            _ => self.execute_f(n, bus),
+        }
+        2
+    }
+
+    /// Takes an interrupt if they're enabled, saving X and P in T and
+    /// running from R1 with R2 as the data pointer. Takes one machine cycle.
+    pub fn interrupt(&mut self) -> bool {
+        if !self.ie {
+            return false;
+        }
+        self.t = self.x << 4 | self.p;
+        self.p = 1;
+        self.x = 2;
+        self.ie = false;
+        self.idle = false;
+        true
+    }
+
+    /// One DMA output cycle: the byte at R0 goes to the device and R0 moves
+    /// on to the next.
+    pub fn dma_out<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let value = bus.read(self.r[0]);
+        self.r[0] = self.r[0].wrapping_add(1);
+        self.idle = false;
+        value
+    }
+
+    fn fetch<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let p = self.p as usize;
+        let value = bus.read(self.r[p]);
+        self.r[p] = self.r[p].wrapping_add(1);
+        value
+    }
+
+    fn short_condition<B: Bus>(&self, n: usize, bus: &mut B) -> bool {
+        let condition = match n & 7 {
+            0 => true,
+            1 => self.q,
+            2 => self.d == 0,
+            3 => self.df,
+            ef => bus.flag(ef as u8 - 3),
+        };
+        // 38 is SKP, the negation of BR
+        condition != (n >= 8)
+    }
+
+    // The target replaces the low byte of the program counter once it moved
+    // past the branch byte, so a branch byte at the end of a page jumps into
+    // the next page.
+    fn short_branch<B: Bus>(&mut self, bus: &mut B, taken: bool) {
+        let target = self.fetch(bus);
+        if taken {
+            let p = self.p as usize;
+            self.r[p] = self.r[p] & 0xFF00 | target as u16;
+        }
+    }
+
+    fn long_branch<B: Bus>(&mut self, n: usize, bus: &mut B) {
+        let p = self.p as usize;
+        let (branch, taken) = match n {
+            0x0 => (true, true),
+            0x1 => (true, self.q),
+            0x2 => (true, self.d == 0),
+            0x3 => (true, self.df),
+            // NOP
+            0x4 => (false, false),
+            0x5 => (false, !self.q),
+            0x6 => (false, self.d != 0),
+            0x7 => (false, !self.df),
+            0x8 => (false, true),
+            0x9 => (true, !self.q),
+            0xA => (true, self.d != 0),
+            0xB => (true, !self.df),
+            0xC => (false, self.ie),
+            0xD => (false, self.q),
+            0xE => (false, self.d == 0),
+            _ => (false, self.df),
+        };
+        if branch && taken {
+            let high = bus.read(self.r[p]);
+            let low = bus.read(self.r[p].wrapping_add(1));
+            self.r[p] = (high as u16) << 8 | low as u16;
+        } else if branch || taken {
+            self.r[p] = self.r[p].wrapping_add(2);
+        }
+    }
+
+    fn execute_7<B: Bus>(&mut self, n: usize, bus: &mut B) {
+        let x = self.x as usize;
+        match n {
+            // RET, DIS
+            0x0 | 0x1 => {
+                let value = bus.read(self.r[x]);
+                self.r[x] = self.r[x].wrapping_add(1);
+                self.x = value >> 4;
+                self.p = value & 0xF;
+                self.ie = n == 0;
+            }
+            // LDXA
+            0x2 => {
+                self.d = bus.read(self.r[x]);
+                self.r[x] = self.r[x].wrapping_add(1);
+            }
+            // STXD
+            0x3 => {
+                bus.write(self.r[x], self.d);
+                self.r[x] = self.r[x].wrapping_sub(1);
+            }
+            // ADC, SDB, SMB
+            0x4 => self.add(bus.read(self.r[x]), self.df),
+            0x5 => self.subtract(bus.read(self.r[x]), self.d, !self.df),
+            0x7 => self.subtract(self.d, bus.read(self.r[x]), !self.df),
+            // SHRC
+            0x6 => {
+                let carry = self.d & 1 != 0;
+                self.d = self.d >> 1 | (self.df as u8) << 7;
+                self.df = carry;
+            }
+            // SAV
+            0x8 => bus.write(self.r[x], self.t),
+            // MARK
+            0x9 => {
+                self.t = self.x << 4 | self.p;
+                bus.write(self.r[2], self.t);
+                self.x = self.p;
+                self.r[2] = self.r[2].wrapping_sub(1);
+            }
+            // REQ, SEQ
+            0xA => self.q = false,
+            0xB => self.q = true,
+            // ADCI, SDBI, SMBI
+            0xC => {
+                let value = self.fetch(bus);
+                self.add(value, self.df);
+            }
+            0xD => {
+                let value = self.fetch(bus);
+                self.subtract(value, self.d, !self.df);
+            }
+            0xF => {
+                let value = self.fetch(bus);
+                self.subtract(self.d, value, !self.df);
+            }
+            // SHLC
+            _ => {
+                let carry = self.d & 0x80 != 0;
+                self.d = self.d << 1 | self.df as u8;
+                self.df = carry;
+            }
+        }
+    }
+
+    // F0 to F7 operate on the byte at R(X), F8 to FF on the byte following
+    // the instruction, except for the shifts.
+    fn execute_f<B: Bus>(&mut self, n: usize, bus: &mut B) {
+        let value = match n {
+            0x6 | 0xE => 0,
+            0x0..=0x7 => bus.read(self.r[self.x as usize]),
+            _ => self.fetch(bus),
+        };
+        match n & 7 {
+            // LDX, LDI
+            0x0 => self.d = value,
+            0x1 => self.d |= value,
+            0x2 => self.d &= value,
+            0x3 => self.d ^= value,
+            0x4 => self.add(value, false),
+            // SD, SDI
+            0x5 => self.subtract(value, self.d, false),
+            // SHR, SHL
+            0x6 if n == 0x6 => {
+                self.df = self.d & 1 != 0;
+                self.d >>= 1;
+            }
+            0x6 => {
+                self.df = self.d & 0x80 != 0;
+                self.d <<= 1;
+            }
+            // SM, SMI
+            _ => self.subtract(self.d, value, false),
+        }
+    }
+
+    fn add(&mut self, value: u8, carry: bool) {
+        let sum = self.d as u16 + value as u16 + carry as u16;
+        self.d = sum as u8;
+        self.df = sum > 0xFF;
+    }
+
+    // DF is set when there was no borrow
+    fn subtract(&mut self, minuend: u8, subtrahend: u8, borrow: bool) {
+        let difference = minuend as i16 - subtrahend as i16 - borrow as i16;
+        self.d = difference as u8;
+        self.df = difference >= 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bus, Cdp1802};
+
+    struct Ram(Vec<u8>);
+
+    impl Bus for Ram {
+        fn read(&mut self, address: u16) -> u8 {
+            self.0[address as usize]
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.0[address as usize] = value;
+        }
+    }
+
+    fn run(program: &[u8], steps: usize) -> (Cdp1802, Ram, u32) {
+        let mut ram = Ram(vec![0; 0x200]);
+        ram.0[..program.len()].copy_from_slice(program);
+        let mut cpu = Cdp1802::new();
+        let cycles = (0..steps).map(|_| cpu.step(&mut ram)).sum();
+        (cpu, ram, cycles)
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        // LDI 30, ADI E0, PLO 4, SMI 20, SHLC
+        let (cpu, _, cycles) = run(&[0xF8, 0x30, 0xFC, 0xE0, 0xA4, 0xFF, 0x20, 0x7E], 5);
+        assert_eq!(cpu.r[4], 0x10);
+        // 0x10 - 0x20 borrows, then the cleared DF is shifted in
+        assert_eq!((cpu.d, cpu.df), (0xE0, true));
+        assert_eq!(cycles, 10);
+
+        // LDI 05, SDI 07, STR 0 right past the program
+        let (cpu, ram, _) = run(&[0xF8, 0x05, 0xFD, 0x07, 0x50], 3);
+        assert_eq!((cpu.d, cpu.df), (2, true));
+        assert_eq!(ram.0[0x05], 2);
+    }
+
+    #[test]
+    fn test_branches_and_subroutines() {
+        let program = [
+            // 0x00: R3 = 0x0010, SEP 3
+            0xF8, 0x10, 0xA3, 0xD3, //
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, //
+            // 0x10: LDI 0, BZ 0x16, LDI FF
+            0xF8, 0x00, 0x32, 0x16, 0xF8, 0xFF, //
+            // 0x16: LBNZ 0x0100 not taken, LSKP over SEQ, SEQ
+            0xCA, 0x01, 0x00, 0xC8, 0x7B, 0x7B, //
+            // 0x1C: IDL
+            0x00,
+        ];
+        let (cpu, _, cycles) = run(&program, 10);
+        assert_eq!(cpu.d, 0);
+        assert!(!cpu.q);
+        assert_eq!(cpu.r[3], 0x1D);
+        assert!(cpu.idle);
+        // the long branch and skip take 3 cycles, waiting 1 each
+        assert_eq!(cycles, 2 * 6 + 3 * 2 + 2);
+    }
+
+    #[test]
+    fn test_interrupt_and_return() {
+        // R2 = 0x0100, SEX 2, R1 = 0x0040, IDL
+        let mut program = vec![0xF8, 0x01, 0xB2, 0xE2, 0xF8, 0x40, 0xA1, 0x00];
+        program.resize(0x40, 0);
+        // 0x40: DEC 2, SAV, REQ, RET
+        program.extend_from_slice(&[0x22, 0x78, 0x7A, 0x70]);
+        let (mut cpu, mut ram, _) = run(&program, 6);
+        cpu.q = true;
+
+        assert!(cpu.idle);
+        assert!(cpu.interrupt());
+        assert!(!cpu.interrupt());
+        assert_eq!((cpu.p, cpu.x, cpu.t), (1, 2, 0x20));
+        for _ in 0..4 {
+            cpu.step(&mut ram);
+        }
+        assert_eq!((cpu.p, cpu.x, cpu.r[0]), (0, 2, 8));
+        assert!(cpu.ie && !cpu.q);
+        assert_eq!(cpu.r[2], 0x0100);
+    }
+}
@@ -0,0 +1,389 @@
+use crate::cpu::Cpu;
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::str::FromStr;
+
+/// A byte a cheat can read or write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Location {
+    Memory(u32),
+    Register(usize),
+}
+
+impl Location {
+    /// Fails for an address past the end of the machine's memory.
+    pub fn check(self, cpu: &Cpu) -> Result<(), UnmappedLocation> {
+        match self {
+            Location::Memory(address) if address as usize >= cpu.memory.len() => {
+                Err(UnmappedLocation(self))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn read(self, cpu: &Cpu) -> u8 {
+        match self {
+            Location::Memory(address) => cpu.memory[address as usize],
+            Location::Register(x) => cpu.v[x],
+        }
+    }
+
+    // memory goes through the CPU so decoded instructions stay valid
+    pub fn write(self, cpu: &mut Cpu, value: u8) {
+        match self {
+            Location::Memory(address) => cpu.write_memory(address as usize, value),
+            Location::Register(x) => cpu.v[x] = value,
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Location::Memory(address) => write!(f, "{:#05X}", address),
+            Location::Register(x) => write!(f, "V{:X}", x),
+        }
+    }
+}
+
+impl FromStr for Location {
+    type Err = ();
+
+    /// `V0` to `VF`, or a hex address.
+    fn from_str(text: &str) -> Result<Location, ()> {
+        if let Some(x) = text.strip_prefix('V').or_else(|| text.strip_prefix('v')) {
+            return match usize::from_str_radix(x, 16) {
+                Ok(x) if x < 16 => Ok(Location::Register(x)),
+                _ => Err(()),
+            };
+        }
+        u32::from_str_radix(text.trim_start_matches("0x"), 16)
+            .map(Location::Memory)
+            .map_err(|_| ())
+    }
+}
+
+/// A cheat address the machine doesn't have, which depends on the platform.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UnmappedLocation(pub Location);
+
+impl fmt::Display for UnmappedLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is past the end of memory", self.0)
+    }
+}
+
+impl Error for UnmappedLocation {}
+
+/// How a value has to compare to the previous snapshot to stay a candidate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Equal(u8),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+impl Comparison {
+    fn matches(self, old: u8, new: u8) -> bool {
+        match self {
+            Comparison::Equal(value) => new == value,
+            Comparison::Changed => new != old,
+            Comparison::Unchanged => new == old,
+            Comparison::Increased => new > old,
+            Comparison::Decreased => new < old,
+        }
+    }
+}
+
+/// Narrows down where a program keeps a value, e.g. a life counter, by
+/// comparing memory and the V registers between snapshots.
+pub struct Search {
+    // memory and registers when the search started, everything being a
+    // candidate until the first filter
+    start: Option<(Vec<u8>, [u8; 16])>,
+    // candidates with their value at the last snapshot
+    candidates: Vec<(Location, u8)>,
+}
+
+impl Search {
+    /// Starts with every byte of memory and every register as a candidate.
+    pub fn new(cpu: &Cpu) -> Search {
+        Search {
+            start: Some((cpu.memory.clone(), cpu.v)),
+            candidates: Vec::new(),
+        }
+    }
+
+    /// Keeps the candidates whose current value compares to the last
+    /// snapshot as asked, and takes a new snapshot.
+    pub fn filter(&mut self, cpu: &Cpu, comparison: Comparison) {
+        match self.start.take() {
+            Some((memory, v)) => {
+                let memory = memory
+                    .into_iter()
+                    .zip(&cpu.memory)
+                    .enumerate()
+                    .filter(|&(_, (old, &new))| comparison.matches(old, new))
+                    .map(|(address, (_, &new))| (Location::Memory(address as u32), new));
+                let registers = v
+                    .iter()
+                    .zip(&cpu.v)
+                    .enumerate()
+                    .filter(|&(_, (&old, &new))| comparison.matches(old, new))
+                    .map(|(x, (_, &new))| (Location::Register(x), new));
+                self.candidates = memory.chain(registers).collect();
+            }
+            None => {
+                self.candidates
+                    .retain(|(location, old)| comparison.matches(*old, location.read(cpu)));
+                for (location, value) in self.candidates.iter_mut() {
+                    *value = location.read(cpu);
+                }
+            }
+        }
+    }
+
+    /// Number of candidates left.
+    pub fn count(&self) -> usize {
+        match &self.start {
+            Some((memory, v)) => memory.len() + v.len(),
+            None => self.candidates.len(),
+        }
+    }
+
+    /// The candidates with their value at the last snapshot, empty until
+    /// the first filter.
+    pub fn candidates(&self) -> &[(Location, u8)] {
+        &self.candidates
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheatKind {
+    // written every frame
+    Freeze,
+    // written once, when the program is loaded
+    Patch,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cheat {
+    pub kind: CheatKind,
+    pub location: Location,
+    pub value: u8,
+    pub name: String,
+}
+
+/// Why a cheat list couldn't be parsed, `line` counting from 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CheatError {
+    pub line: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl Error for CheatError {}
+
+/// Cheats of a program, saved as text with one cheat per line:
+///
+/// ```text
+/// # Tetris
+/// patch 0x2F4 0x09 start at level 9
+/// freeze V5 0x03 infinite lives
+/// ```
+///
+/// That is `freeze` or `patch`, a register or hex address, a hex value and
+/// an optional name. `#` starts a comment.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CheatList {
+    pub cheats: Vec<Cheat>,
+}
+
+impl CheatList {
+    pub fn new() -> CheatList {
+        CheatList::default()
+    }
+
+    pub fn parse(text: &str) -> Result<CheatList, CheatError> {
+        let mut cheats = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let error = |reason| CheatError {
+                line: n + 1,
+                reason,
+            };
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut fields = line.splitn(4, char::is_whitespace);
+            let kind = match fields.next() {
+                Some("freeze") => CheatKind::Freeze,
+                Some("patch") => CheatKind::Patch,
+                _ => return Err(error("expected freeze or patch")),
+            };
+            let location = fields
+                .next()
+                .and_then(|location| location.parse().ok())
+                .ok_or_else(|| error("expected a register or address"))?;
+            let value = fields
+                .next()
+                .and_then(|value| u8::from_str_radix(value.trim_start_matches("0x"), 16).ok())
+                .ok_or_else(|| error("expected a hex byte"))?;
+            let name = fields.next().unwrap_or_default().trim().to_string();
+
+            cheats.push(Cheat {
+                kind,
+                location,
+                value,
+                name,
+            });
+        }
+        Ok(CheatList { cheats })
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for cheat in &self.cheats {
+            let kind = match cheat.kind {
+                CheatKind::Freeze => "freeze",
+                CheatKind::Patch => "patch",
+            };
+            write!(text, "{} {} {:#04X}", kind, cheat.location, cheat.value).unwrap();
+            if !cheat.name.is_empty() {
+                write!(text, " {}", cheat.name).unwrap();
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Fails for the first cheat past the end of the machine's memory.
+    pub fn check(&self, cpu: &Cpu) -> Result<(), UnmappedLocation> {
+        self.cheats
+            .iter()
+            .try_for_each(|cheat| cheat.location.check(cpu))
+    }
+
+    /// Writes the patches, right after loading the program. Writes nothing
+    /// when a cheat doesn't fit the machine, see `check`.
+    pub fn apply_patches(&self, cpu: &mut Cpu) -> Result<(), UnmappedLocation> {
+        self.apply(cpu, CheatKind::Patch)
+    }
+
+    /// Writes the frozen values, once per frame.
+    pub fn apply_frame(&self, cpu: &mut Cpu) -> Result<(), UnmappedLocation> {
+        self.apply(cpu, CheatKind::Freeze)
+    }
+
+    fn apply(&self, cpu: &mut Cpu, kind: CheatKind) -> Result<(), UnmappedLocation> {
+        self.check(cpu)?;
+        for cheat in self.cheats.iter().filter(|cheat| cheat.kind == kind) {
+            cheat.location.write(cpu, cheat.value);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Cheat, CheatError, CheatKind, CheatList, Comparison, Location, Search, UnmappedLocation,
+    };
+    use crate::cpu::Cpu;
+    use crate::platform::Platform;
+
+    #[test]
+    fn test_search() {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&[]);
+        cpu.memory[0x300] = 3;
+        cpu.v[5] = 3;
+        let mut search = Search::new(&cpu);
+        assert_eq!(search.count(), cpu.memory.len() + 16);
+        assert!(search.candidates().is_empty());
+
+        search.filter(&cpu, Comparison::Equal(3));
+        assert_eq!(
+            search.candidates(),
+            [(Location::Memory(0x300), 3), (Location::Register(5), 3)]
+        );
+
+        cpu.v[5] = 2;
+        search.filter(&cpu, Comparison::Decreased);
+        assert_eq!(search.candidates(), [(Location::Register(5), 2)]);
+        search.filter(&cpu, Comparison::Unchanged);
+        assert_eq!(search.count(), 1);
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut cpu = Cpu::new();
+        // V0 = 1, jump 0x200
+        cpu.load_rom(&[0x60, 0x01, 0x12, 0x00]);
+        cpu.step_cached().unwrap();
+        let cheats = CheatList::parse("patch 0x201 0x07\nfreeze VE 0x63\n").unwrap();
+
+        cheats.apply_patches(&mut cpu).unwrap();
+        assert_eq!(cpu.v[0xE], 0);
+        cheats.apply_frame(&mut cpu).unwrap();
+        assert_eq!(cpu.v[0xE], 0x63);
+
+        // the patched instruction isn't served from the cache
+        cpu.step_cached().unwrap();
+        cpu.step_cached().unwrap();
+        assert_eq!(cpu.v[0], 7);
+    }
+
+    #[test]
+    fn test_memory_size() {
+        let cheats = CheatList::parse("patch 0xFFFF 0x07\nfreeze V0 1\n").unwrap();
+        let mut cpu = Cpu::new();
+        assert_eq!(
+            cheats.apply_patches(&mut cpu),
+            Err(UnmappedLocation(Location::Memory(0xFFFF)))
+        );
+        // nothing is written
+        assert!(cheats.apply_frame(&mut cpu).is_err());
+        assert_eq!(cpu.v[0], 0);
+
+        cpu.set_platform(Platform::MegaChip);
+        cheats.apply_patches(&mut cpu).unwrap();
+        assert_eq!(cpu.memory[0xFFFF], 7);
+    }
+
+    #[test]
+    fn test_text_format() {
+        let cheats =
+            CheatList::parse("# Tetris\npatch 2F4 9 start at level 9\nfreeze v5 0x03\n").unwrap();
+
+        assert_eq!(
+            cheats.cheats[0],
+            Cheat {
+                kind: CheatKind::Patch,
+                location: Location::Memory(0x2F4),
+                value: 9,
+                name: "start at level 9".to_string(),
+            }
+        );
+        assert_eq!(
+            cheats.to_text(),
+            "patch 0x2F4 0x09 start at level 9\nfreeze V5 0x03\n"
+        );
+        assert_eq!(CheatList::parse(&cheats.to_text()), Ok(cheats));
+        assert_eq!(
+            CheatList::parse("\nfreeze V5 zz"),
+            Err(CheatError {
+                line: 2,
+                reason: "expected a hex byte"
+            })
+        );
+    }
+}
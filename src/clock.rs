@@ -0,0 +1,140 @@
+// slowest supported speed multiplier
+const MIN_SPEED: f32 = 0.25;
+// frames emulated per host frame when running at unlimited speed
+const UNLIMITED_FRAMES: u32 = 100;
+
+/// Decides how many emulated frames to run for each frame of the host.
+///
+/// Timers tick once per emulated frame, so running faster or slower never
+/// changes how a game behaves, only how quickly it gets there.
+#[derive(Clone)]
+pub struct Clock {
+    // speed multiplier, infinite means as fast as possible
+    speed: f32,
+    paused: bool,
+    // fraction of a frame carried over between host frames
+    credit: f32,
+    // emulated frames since the last reset
+    frames: u32,
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock {
+            speed: 1.0,
+            paused: false,
+            credit: 0.0,
+            frames: 0,
+        }
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = if speed.is_nan() {
+            1.0
+        } else {
+            speed.max(MIN_SPEED)
+        };
+        self.credit = 0.0;
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Halves the speed. Unlimited drops to the fastest finite speed, as
+    /// many frames as unlimited runs.
+    pub fn slow_down(&mut self) {
+        let speed = if self.speed.is_infinite() {
+            UNLIMITED_FRAMES as f32
+        } else {
+            self.speed / 2.0
+        };
+        self.set_speed(speed);
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Number of emulated frames due for one host frame.
+    pub fn frames_due(&mut self) -> u32 {
+        if self.paused {
+            return 0;
+        }
+        if self.speed.is_infinite() {
+            return UNLIMITED_FRAMES;
+        }
+        self.credit += self.speed;
+        let due = self.credit.floor();
+        self.credit -= due;
+        due as u32
+    }
+
+    pub fn frame_done(&mut self) {
+        self.frames += 1;
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    pub fn reset(&mut self) {
+        self.credit = 0.0;
+        self.frames = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Clock;
+
+    #[test]
+    fn test_slow_motion() {
+        let mut clock = Clock::new();
+        clock.set_speed(0.25);
+        let due: Vec<u32> = (0..8).map(|_| clock.frames_due()).collect();
+        assert_eq!(due, [0, 0, 0, 1, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_fast_forward() {
+        let mut clock = Clock::new();
+        clock.set_speed(2.5);
+        let due: Vec<u32> = (0..2).map(|_| clock.frames_due()).collect();
+        assert_eq!(due, [2, 3]);
+    }
+
+    #[test]
+    fn test_speed_is_clamped() {
+        let mut clock = Clock::new();
+        clock.set_speed(0.0);
+        assert_eq!(clock.speed(), 0.25);
+    }
+
+    #[test]
+    fn test_slow_down_from_unlimited() {
+        let mut clock = Clock::new();
+        clock.set_speed(f32::INFINITY);
+        clock.slow_down();
+        assert_eq!(clock.speed(), 100.0);
+        clock.slow_down();
+        assert_eq!(clock.speed(), 50.0);
+    }
+
+    #[test]
+    fn test_paused() {
+        let mut clock = Clock::new();
+        clock.pause();
+        assert_eq!(clock.frames_due(), 0);
+        clock.resume();
+        assert_eq!(clock.frames_due(), 1);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::platform::Platform;
+use std::fmt::Write;
+
+const EXECUTED: u8 = 0x1;
+const DATA: u8 = 0x2;
+
+/// Records which bytes of memory were executed as instructions and which were
+/// read as data, by sprites, FX65 and MegaChip's palette and sound loads.
+/// Enabled through `Cpu::enable_coverage`.
+#[derive(Clone)]
+pub struct Coverage {
+    marks: Vec<u8>,
+}
+
+impl Coverage {
+    /// Keeps the first `memory_size` bytes, the ones PC can reach. Reads
+    /// past them, only MegaChip's, aren't recorded.
+    pub fn new(memory_size: usize) -> Coverage {
+        Coverage {
+            marks: vec![0; memory_size],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for mark in self.marks.iter_mut() {
+            *mark = 0;
+        }
+    }
+
+    /// Records an instruction executed at `pc` that read `len` bytes of
+    /// data from `index`, the address I pointed at before it ran.
+    pub fn record(&mut self, pc: u16, index: usize, len: usize) {
+        self.mark(pc as usize, 2, EXECUTED);
+        self.mark(index, len, DATA);
+    }
+
+    fn mark(&mut self, start: usize, len: usize, kind: u8) {
+        let end = (start + len).min(self.marks.len());
+        for mark in &mut self.marks[start.min(end)..end] {
+            *mark |= kind;
+        }
+    }
+
+    // marks of `address`, none past what is kept
+    fn marks_at(&self, address: usize) -> u8 {
+        self.marks.get(address).copied().unwrap_or(0)
+    }
+
+    pub fn is_executed(&self, address: usize) -> bool {
+        self.marks_at(address) & EXECUTED != 0
+    }
+
+    pub fn is_data(&self, address: usize) -> bool {
+        self.marks_at(address) & DATA != 0
+    }
+
+    /// Disassembly of `start..end` as `platform` decodes it, each line
+    /// prefixed with one marker per byte: `X` executed, `D` read as data, `*` both, `.` never touched.
+    pub fn annotated_disassembly(
+        &self,
+        memory: &[u8],
+        platform: Platform,
+        start: usize,
+        end: usize,
+    ) -> String {
+        let mut out = String::new();
+        for address in (start..end).step_by(2) {
+            let hi = memory[address];
+            let lo = if address + 1 < end {
+                memory[address + 1]
+            } else {
+                0
+            };
+            let markers: String = (address..(address + 2).min(end))
+                .map(|b| match self.marks_at(b) {
+                    0 => '.',
+                    EXECUTED => 'X',
+                    DATA => 'D',
+                    _ => '*',
+                })
+                .collect();
+
+            let text = if self.is_executed(address) {
+                platform.decode((hi as u16) << 8 | lo as u16).to_string()
+            } else {
+                format!("DB {:#04X}, {:#04X}", hi, lo)
+            };
+            writeln!(
+                out,
+                "{:<2} {:#05X}  {:02X}{:02X}  {}",
+                markers, address, hi, lo, text
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    /// Machine readable coverage of `start..end`, one line per run of bytes
+    /// with the same coverage: `<first> <last> <code|data|both|none>`, with
+    /// addresses in hex.
+    pub fn to_text(&self, start: usize, end: usize) -> String {
+        let mut out = String::new();
+        let mut run_start = start;
+        for address in start..end {
+            let next = address + 1;
+            if next == end || self.marks_at(next) != self.marks_at(run_start) {
+                let kind = match self.marks_at(run_start) {
+                    0 => "none",
+                    EXECUTED => "code",
+                    DATA => "data",
+                    _ => "both",
+                };
+                writeln!(out, "{:03X} {:03X} {}", run_start, address, kind).unwrap();
+                run_start = next;
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Coverage;
+    use crate::platform::Platform;
+
+    #[test]
+    fn test_coverage() {
+        let mut coverage = Coverage::new(4096);
+        // a 2 byte sprite, then a jump
+        coverage.record(0x200, 0x204, 2);
+        coverage.record(0x202, 0x204, 0);
+        let memory = {
+            let mut memory = [0; 4096];
+            memory[0x200..0x208].copy_from_slice(&[0xD0, 0x02, 0x12, 0x00, 0xF0, 0x90, 0, 0]);
+            memory
+        };
+
+        assert!(coverage.is_executed(0x201));
+        assert!(coverage.is_data(0x205));
+        assert!(!coverage.is_data(0x206));
+        // past what is kept
+        coverage.record(0x200, 0xFFFF, 4);
+        assert!(!coverage.is_data(0x1000));
+        assert_eq!(
+            coverage.to_text(0x200, 0x208),
+            "200 203 code\n204 205 data\n206 207 none\n"
+        );
+        assert_eq!(
+            coverage.annotated_disassembly(&memory, Platform::Chip8, 0x200, 0x206),
+            "XX 0x200  D002  DRW V0, V0, 2\n\
+             XX 0x202  1200  JP 0x200\n\
+             DD 0x204  F090  DB 0xF0, 0x90\n"
+        );
+    }
+}
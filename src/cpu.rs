@@ -1,46 +1,238 @@
-use crate::display::{Display, FONT_SET};
+use crate::clock::Clock;
+use crate::coverage::Coverage;
+use crate::display::Display;
+use crate::flags::{self, FlagStore, Flags, FlagsHook, JsFlags, MemoryFlags};
+use crate::font::{BuiltinFont, Font};
+use crate::instruction::Instruction;
 use crate::keypad::Keypad;
+use crate::megachip::{Blend, Sample};
+use crate::platform::{Descriptor, IndexQuirk, Platform, Quirks, HIRES_START};
+use crate::profiler::Profiler;
+use crate::timing::{self, Timing};
 use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::error::Error;
+use std::fmt;
 use wasm_bindgen::prelude::*;
 
+pub(crate) const INSTRUCTIONS_PER_FRAME: u32 = 10;
+// longest backward jump still treated as a candidate for an idle loop
+const MAX_IDLE_LOOP: u16 = 32;
+// SUPER-CHIP's high resolution display, and the bytes of its 16x16 sprites
+const HIGH_RES: (usize, usize) = (128, 64);
+const LARGE_SPRITE: usize = 32;
+
+// machine state recorded the last time a candidate idle loop jumped back
+#[derive(Clone, Copy, PartialEq)]
+struct IdleProbe {
+    // start of the loop body
+    target: u16,
+    pc: u16,
+    v: [u8; 16],
+    i: u16,
+    i_page: u8,
+}
+
+/// Why an instruction couldn't be executed. `pc` is the address of the
+/// offending instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CpuError {
+    // 2NNN with all 16 stack entries in use
+    StackOverflow {
+        pc: u16,
+    },
+    // 00EE with an empty stack
+    StackUnderflow {
+        pc: u16,
+    },
+    // an instruction fetch, sprite or register load/store past the end of memory
+    MemoryOutOfBounds {
+        pc: u16,
+        address: u16,
+    },
+    // an opcode the platform doesn't have
+    UnsupportedOpcode {
+        pc: u16,
+        opcode: u16,
+        platform: Platform,
+    },
+    // FX75 or FX85 with the flag store failing
+    FlagStorage {
+        pc: u16,
+    },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CpuError::StackOverflow { pc } => write!(f, "stack overflow at {:#05X}", pc),
+            CpuError::StackUnderflow { pc } => write!(f, "stack underflow at {:#05X}", pc),
+            CpuError::MemoryOutOfBounds { pc, address } => write!(
+                f,
+                "memory access out of bounds at {:#05X} (address {:#06X})",
+                pc, address
+            ),
+            CpuError::UnsupportedOpcode {
+                pc,
+                opcode,
+                platform,
+            } => write!(
+                f,
+                "opcode {:04X} at {:#05X} isn't supported on {}",
+                opcode, pc, platform
+            ),
+            CpuError::FlagStorage { pc } => write!(f, "flag storage failed at {:#05X}", pc),
+        }
+    }
+}
+
+impl Error for CpuError {}
+
+const STATE_MAGIC: &[u8; 4] = b"CH9S";
+const STATE_VERSION: u8 = 3;
+
+/// Why `Cpu::load_state` rejected a snapshot.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StateError {
+    // doesn't start with the snapshot magic
+    NotASnapshot,
+    UnsupportedVersion(u8),
+    // shorter or longer than a snapshot of this version
+    BadLength(usize),
+    // a stack pointer past the end of the stack
+    Corrupted,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::NotASnapshot => write!(f, "not a snapshot"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            StateError::BadLength(len) => write!(f, "snapshot has the wrong size ({} bytes)", len),
+            StateError::Corrupted => write!(f, "snapshot is corrupted"),
+        }
+    }
+}
+
+impl Error for StateError {}
+
 #[wasm_bindgen]
+#[derive(Clone)]
 pub struct Cpu {
     // index register
-    i: u16,
+    pub(crate) i: u16,
     // program counter
-    pc: u16,
-    // memory
-    memory: [u8; 4096],
+    pub(crate) pc: u16,
+    // memory, as large as the platform has
+    pub(crate) memory: Vec<u8>,
+    // decoded instructions, indexed by address
+    cache: Box<[Option<Instruction>]>,
     // registers
-    v: [u8; 16],
+    pub(crate) v: [u8; 16],
     // stack
-    stack: [u16; 16],
+    pub(crate) stack: [u16; 16],
     // stack pointer
-    sp: u8,
+    pub(crate) sp: u8,
     // delayed timer
-    dt: u8,
+    pub(crate) dt: u8,
     // random number generator
-    rng: ThreadRng,
+    rng: StdRng,
+    // seed `reset` restarts the generator from, entropy when unset
+    seed: Option<u64>,
     // display
-    display: Display,
+    pub(crate) display: Display,
     // keypad
-    keypad: Keypad,
+    pub(crate) keypad: Keypad,
+    // idle loop detection
+    idle_probe: Option<IdleProbe>,
+    // instructions skipped because the program was idle
+    idle_cycles: u64,
+    // emulation speed
+    clock: Clock,
+    // instructions executed since the last reset
+    cycles: u64,
+    // error that halted `process_opcode`, cleared by `reset`
+    fault: Option<CpuError>,
+    // execution statistics, when profiling
+    profiler: Option<Box<Profiler>>,
+    // executed and data bytes, when recording coverage
+    coverage: Option<Box<Coverage>>,
+    // addresses the program wrote since `take_writes`, when logging them
+    write_log: Option<Vec<usize>>,
+    // size of the program loaded by `load_rom`
+    rom_size: usize,
+    // cycle budget per frame, when following the VIP's timing
+    timing: Option<Timing>,
+    platform: Platform,
+    // the platform `set_platform` chose, which `load_rom` goes back to after
+    // running a HiRes program
+    selected_platform: Platform,
+    quirks: Quirks,
+    // where programs are loaded
+    load_address: u16,
+    // where the hex digits are, and which
+    font_address: u16,
+    font: Font,
+    // last value FXF8 sent to the tone generator
+    tone: u8,
+    // value waiting on the input port for FXFB
+    port_input: Option<u8>,
+    // the display as RGBA, see `update_framebuffer`
+    framebuffer: Vec<u8>,
+    // bits 16 to 23 of I, only ever set by MegaChip's 01NN NNNN
+    pub(crate) i_page: u8,
+    // digitised sound playing on MegaChip
+    pub(crate) sample: Option<Sample>,
+    // where FX75 and FX85 keep the flags, under the hash of the ROM
+    flag_store: Box<dyn FlagStore>,
+    rom_key: u64,
 }
 
 #[wasm_bindgen]
 impl Cpu {
+    /// A CHIP-8 machine keeping the quirks this interpreter always had,
+    /// `Platform::Legacy`. See `set_platform` for those of the real
+    /// platforms.
     pub fn new() -> Cpu {
+        let descriptor = Platform::Legacy.descriptor();
         Cpu {
             i: 0,
             pc: 0,
-            memory: [0; 4096],
+            memory: vec![0; descriptor.memory_size],
+            cache: vec![None; descriptor.memory_size].into_boxed_slice(),
             v: [0; 16],
             stack: [0; 16],
             sp: 0,
             dt: 0,
-            rng: rand::thread_rng(),
-            display: Display::new(),
+            rng: StdRng::from_entropy(),
+            seed: None,
+            display: Display::new(descriptor.width, descriptor.height),
             keypad: Keypad::new(),
+            idle_probe: None,
+            idle_cycles: 0,
+            clock: Clock::new(),
+            cycles: 0,
+            fault: None,
+            profiler: None,
+            coverage: None,
+            write_log: None,
+            rom_size: 0,
+            timing: None,
+            platform: Platform::Legacy,
+            selected_platform: Platform::Legacy,
+            quirks: descriptor.quirks,
+            load_address: descriptor.load_address,
+            font_address: descriptor.font_address,
+            font: platform_font(&descriptor),
+            tone: 0,
+            port_input: None,
+            framebuffer: Vec::new(),
+            i_page: 0,
+            sample: None,
+            flag_store: Box::new(MemoryFlags::new()),
+            rom_key: flags::rom_key(&[]),
         }
     }
 
@@ -56,19 +248,136 @@ impl Cpu {
         self.keypad.key_up(index);
     }
 
+    /// A key of the second CHIP-8X keypad.
+    pub fn second_key_down(&mut self, index: u8) {
+        self.keypad.second_key_down(index);
+    }
+
+    pub fn second_key_up(&mut self, index: u8) {
+        self.keypad.second_key_up(index);
+    }
+
+    /// Puts `value` on the CHIP-8X input port, for the next FXFB to read.
+    pub fn set_port_input(&mut self, value: u8) {
+        self.port_input = Some(value);
+    }
+
+    /// What the program last sent the tone generator with FXF8.
+    pub fn tone(&self) -> u8 {
+        self.tone
+    }
+
+    /// Renders the display as RGBA into the buffer behind
+    /// `framebuffer_ptr`, in colour on CHIP-8X.
+    pub fn update_framebuffer(&mut self) {
+        self.display.write_rgba(&mut self.framebuffer);
+    }
+
+    pub fn framebuffer_ptr(&self) -> *const u8 {
+        self.framebuffer.as_ptr()
+    }
+
+    /// Fills `out` with the MegaChip sound at `rate` samples per second,
+    /// silence when nothing plays.
+    pub fn fill_audio(&mut self, out: &mut [f32], rate: u32) {
+        match self.sample.as_mut() {
+            Some(sample) => {
+                if !sample.fill(out, rate) {
+                    self.sample = None;
+                }
+            }
+            None => out.iter_mut().for_each(|value| *value = 0.0),
+        }
+    }
+
     pub fn reset(&mut self) {
         self.i = 0;
-        self.pc = 0x200;
-        self.memory = [0; 4096];
+        self.i_page = 0;
+        self.pc = self.load_address;
+        self.memory.iter_mut().for_each(|byte| *byte = 0);
         self.v = [0; 16];
         self.stack = [0; 16];
         self.sp = 0;
         self.dt = 0;
-        self.rng = rand::thread_rng();
+        self.rng = match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        self.idle_probe = None;
+        self.idle_cycles = 0;
+        self.clock.reset();
+        self.cycles = 0;
+        self.fault = None;
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.clear();
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.clear();
+        }
+        // the whole memory changes, so there's nothing to log
+        if let Some(log) = self.write_log.as_mut() {
+            log.clear();
+        }
+        self.rom_size = 0;
+        if let Some(timing) = self.timing.as_mut() {
+            timing.reset();
+        }
+        self.display.planes = 1;
         self.display.cls();
-        for i in 0..80 {
-            self.memory[i] = FONT_SET[i];
+        self.display.reset_colours();
+        self.tone = 0;
+        self.port_input = None;
+        self.sample = None;
+        // back from MegaChip mode or SUPER-CHIP's high resolution
+        let descriptor = self.platform.descriptor();
+        if self.display.mega.is_some()
+            || (self.display.width, self.display.height) != (descriptor.width, descriptor.height)
+        {
+            self.display = Display::new(descriptor.width, descriptor.height);
+        }
+        let start = self.font_address as usize;
+        self.memory[start..start + self.font.bytes().len()].copy_from_slice(self.font.bytes());
+        self.invalidate_cache();
+    }
+
+    /// Resets the machine and loads a program where the platform expects
+    /// it, 0x200 for most.
+    ///
+    /// HiRes CHIP-8 programs are recognised by their entry jump and switch
+    /// plain CHIP-8 to that platform. They start at 0x2C0, past the part of
+    /// the interpreter they bring along. Other programs run on the platform
+    /// `set_platform` chose.
+    ///
+    /// Returns false, changing nothing, when the program doesn't fit in
+    /// memory past the load address.
+    pub fn load_rom(&mut self, rom: &[u8]) -> bool {
+        let detected = Platform::detect(rom);
+        let plain = matches!(self.selected_platform, Platform::Legacy | Platform::Chip8);
+        let platform = match detected {
+            Some(Platform::HiRes) if plain => Platform::HiRes,
+            _ => self.selected_platform,
+        };
+        let descriptor = platform.descriptor();
+        if rom.len() > descriptor.memory_size - descriptor.load_address as usize {
+            return false;
         }
+        if platform != self.platform {
+            self.switch_platform(platform);
+        }
+        self.reset();
+        let start = self.load_address as usize;
+        self.memory[start..start + rom.len()].copy_from_slice(rom);
+        self.rom_size = rom.len();
+        self.rom_key = flags::rom_key(rom);
+        if self.platform == Platform::HiRes && detected == Some(Platform::HiRes) {
+            self.pc = HIRES_START;
+        }
+        true
+    }
+
+    /// Size of the largest program `load_rom` takes on this platform.
+    pub fn rom_capacity(&self) -> usize {
+        self.memory.len() - self.load_address as usize
     }
 
     pub fn memory_ptr(&self) -> *const u8 {
@@ -79,12 +388,74 @@ impl Cpu {
         self.display.screen.as_ptr()
     }
 
+    pub fn display_width(&self) -> usize {
+        self.display.width
+    }
+
+    pub fn display_height(&self) -> usize {
+        self.display.height
+    }
+
+    /// Switches to the platform called `name`, see `Platform::ALL`, and
+    /// resets. Returns false, changing nothing, for an unknown name.
+    pub fn select_platform(&mut self, name: &str) -> bool {
+        match name.parse() {
+            Ok(platform) => {
+                self.set_platform(platform);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn platform_name(&self) -> String {
+        self.platform.name().to_string()
+    }
+
+    /// Switches to the built-in font called `name`, see `BuiltinFont::ALL`.
+    /// Returns false, changing nothing, for an unknown name.
+    pub fn select_font(&mut self, name: &str) -> bool {
+        match name.parse::<BuiltinFont>() {
+            Ok(font) => {
+                self.set_font(font.font());
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Switches to a font given as bytes, see `Font::from_bytes`. Returns
+    /// false, changing nothing, when it isn't one.
+    pub fn load_font(&mut self, bytes: &[u8]) -> bool {
+        match Font::from_bytes(bytes) {
+            Ok(font) => {
+                self.set_font(font);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Keeps the flags FX75 saves through `hook` instead of in memory, see
+    /// `FlagsHook`.
+    pub fn set_flags_hook(&mut self, hook: FlagsHook) {
+        self.flag_store = Box::new(JsFlags::new(hook));
+    }
+
     pub fn decrement_timers(&mut self) {
         if self.dt > 0 {
             self.dt -= 1;
         }
     }
 
+    /// Drops every decoded instruction. Needed after memory was written
+    /// from outside the interpreter, e.g. through `memory_ptr`.
+    pub fn invalidate_cache(&mut self) {
+        for entry in self.cache.iter_mut() {
+            *entry = None;
+        }
+    }
+
     pub fn current_opcode(&self) -> u16 {
         let hi = self.memory[self.pc as usize] as u16;
         let lo = self.memory[(self.pc + 1) as usize] as u16;
@@ -92,157 +463,1220 @@ impl Cpu {
         hi << 8 | lo
     }
 
+    /// Executes one instruction. On error the CPU halts until the next
+    /// `reset`, see `fault`.
     pub fn process_opcode(&mut self) {
-        let opcode = self.current_opcode();
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let y = ((opcode & 0x00F0) >> 4) as usize;
-        let vx = self.v[x];
-        let vy = self.v[y];
-        let nnn = opcode & 0x0FFF;
-        let nn = (opcode & 0x00FF) as u8;
-        let n = (opcode & 0x000F) as u8;
-
-        // break up into nibbles
-        let op_1 = (opcode & 0xF000) >> 12;
-        let op_2 = (opcode & 0x0F00) >> 8;
-        let op_3 = (opcode & 0x00F0) >> 4;
-        let op_4 = opcode & 0x000F;
+        if self.fault.is_none() {
+            self.fault = self.step().err();
+        }
+    }
+
+    /// Same as `process_opcode`, but reuses the instruction decoded the last
+    /// time this address was executed.
+    pub fn process_opcode_cached(&mut self) {
+        if self.fault.is_none() {
+            self.fault = self.step_cached().err();
+        }
+    }
+
+    pub fn enable_profiling(&mut self) {
+        if self.profiler.is_none() {
+            self.profiler = Some(Box::new(Profiler::new(self.code_size())));
+        }
+    }
+
+    pub fn disable_profiling(&mut self) {
+        self.profiler = None;
+    }
+
+    /// Summary of the `count` hottest addresses, instructions, routines and
+    /// loops, when profiling.
+    pub fn profile_report(&self, count: usize) -> Option<String> {
+        self.profiler
+            .as_ref()
+            .map(|profiler| profiler.report(count))
+    }
+
+    pub fn enable_coverage(&mut self) {
+        if self.coverage.is_none() {
+            self.coverage = Some(Box::new(Coverage::new(self.code_size())));
+        }
+    }
+
+    pub fn disable_coverage(&mut self) {
+        self.coverage = None;
+    }
+
+    /// Annotated disassembly of the loaded ROM, when recording coverage.
+    pub fn coverage_report(&self) -> Option<String> {
+        let (start, end) = self.rom_range();
+        self.coverage
+            .as_ref()
+            .map(|coverage| coverage.annotated_disassembly(&self.memory, self.platform, start, end))
+    }
+
+    /// Runs frames for as many machine cycles as the COSMAC VIP had, each
+    /// instruction costing what it took the original interpreter, instead of
+    /// a flat number of instructions.
+    pub fn enable_vip_timing(&mut self) {
+        if self.timing.is_none() {
+            self.timing = Some(Timing::new());
+        }
+    }
+
+    pub fn disable_vip_timing(&mut self) {
+        self.timing = None;
+    }
+
+    /// Describes the error that halted the CPU, if any.
+    pub fn fault(&self) -> Option<String> {
+        self.fault.map(|error| error.to_string())
+    }
+
+    /// Executes `count` instructions through the decoded instruction cache.
+    pub fn run(&mut self, count: u32) {
+        for _ in 0..count {
+            self.process_opcode_cached();
+        }
+    }
+
+    /// Runs one frame worth of instructions and ticks the timers.
+    ///
+    /// When the program is found spinning in a loop that can't make progress
+    /// before the next timer tick or key event, the rest of the frame is
+    /// skipped. Returns the number of instructions skipped that way.
+    ///
+    /// With VIP timing the frame is budgeted in machine cycles instead, and
+    /// idle loops run their course, so nothing is skipped.
+    pub fn run_frame(&mut self) -> u32 {
+        let mut idle = 0;
+        if self.timing.is_some() {
+            self.run_timed_frame();
+        } else {
+            for executed in 0..INSTRUCTIONS_PER_FRAME {
+                if self.is_idle() {
+                    idle = INSTRUCTIONS_PER_FRAME - executed;
+                    break;
+                }
+                self.process_opcode_cached();
+            }
+        }
+        // the timers and keys are about to change, loops have to be proven
+        // idle again
+        self.idle_probe = None;
+        self.idle_cycles += idle as u64;
+        self.decrement_timers();
+        self.clock.frame_done();
+        idle
+    }
+
+    /// Sets the speed multiplier, from 0.25 up to `Infinity` for unlimited.
+    pub fn set_speed(&mut self, speed: f32) {
+        self.clock.set_speed(speed);
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.clock.speed()
+    }
+
+    /// Halves the speed, unlimited included.
+    pub fn slow_down(&mut self) {
+        self.clock.slow_down();
+    }
+
+    pub fn pause(&mut self) {
+        self.clock.pause();
+    }
+
+    pub fn resume(&mut self) {
+        self.clock.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.clock.is_paused()
+    }
+
+    /// Number of frames emulated since the last reset.
+    pub fn frame_count(&self) -> u32 {
+        self.clock.frames()
+    }
+
+    /// Called once per host frame, runs as many frames as the current speed
+    /// asks for. Returns the number of frames run.
+    pub fn tick(&mut self) -> u32 {
+        let due = self.frames_due();
+        for _ in 0..due {
+            self.run_frame();
+        }
+        due
+    }
+
+    /// Runs exactly one frame, even while paused.
+    pub fn advance_frame(&mut self) {
+        self.run_frame();
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Cpu {
+        Cpu::new()
+    }
+}
+
+impl Cpu {
+    /// Executes one instruction.
+    pub fn step(&mut self) -> Result<(), CpuError> {
+        self.check_fetch()?;
+        let instruction = self.platform.decode(self.current_opcode());
+        self.run_instruction(instruction)
+    }
+
+    /// Executes one instruction through the decoded instruction cache.
+    pub fn step_cached(&mut self) -> Result<(), CpuError> {
+        self.check_fetch()?;
+        let instruction = self.decoded(self.pc);
+        self.run_instruction(instruction)
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_deref()
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_deref()
+    }
+
+    /// Logs the addresses the program writes from now on, for comparing
+    /// memory without copying it.
+    pub fn enable_write_log(&mut self) {
+        if self.write_log.is_none() {
+            self.write_log = Some(Vec::new());
+        }
+    }
+
+    pub fn disable_write_log(&mut self) {
+        self.write_log = None;
+    }
+
+    /// Addresses written since the last call or reset, in the order they
+    /// were written, repeats included. Empty when not logging.
+    pub fn take_writes(&mut self) -> Vec<usize> {
+        self.write_log
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Number of frames to run for one host frame at the current speed, for
+    /// frontends that do something between frames instead of calling `tick`.
+    pub fn frames_due(&mut self) -> u32 {
+        self.clock.frames_due()
+    }
+
+    /// Addresses of the program loaded by `load_rom`.
+    pub fn rom_range(&self) -> (usize, usize) {
+        let start = self.load_address as usize;
+        (start, start + self.rom_size)
+    }
+
+    /// Lays the machine out like `platform` and takes on its quirks, then
+    /// resets. The program has to be loaded again.
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.selected_platform = platform;
+        self.switch_platform(platform);
+    }
+
+    fn switch_platform(&mut self, platform: Platform) {
+        let descriptor = platform.descriptor();
+        self.platform = platform;
+        self.quirks = descriptor.quirks;
+        self.load_address = descriptor.load_address;
+        self.font_address = descriptor.font_address;
+        self.font = platform_font(&descriptor);
+        self.memory = vec![0; descriptor.memory_size];
+        self.cache = vec![None; self.code_size()].into_boxed_slice();
+        self.display = if descriptor.colour {
+            Display::with_colours(descriptor.width, descriptor.height)
+        } else {
+            Display::new(descriptor.width, descriptor.height)
+        };
+        if self.profiler.is_some() {
+            self.profiler = Some(Box::new(Profiler::new(self.code_size())));
+        }
+        if self.coverage.is_some() {
+            self.coverage = Some(Box::new(Coverage::new(self.code_size())));
+        }
+        self.reset();
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    /// Replaces the platform's font, in memory right away and after every
+    /// reset, until the platform changes.
+    pub fn set_font(&mut self, font: Font) {
+        self.font = font;
+        let start = self.font_address as usize;
+        for (offset, byte) in self.font.bytes().to_vec().into_iter().enumerate() {
+            self.write_memory(start + offset, byte);
+        }
+    }
 
+    pub fn font(&self) -> &Font {
+        &self.font
+    }
+
+    /// Keeps the flags FX75 saves in `store` from now on. They're kept in
+    /// memory until then, and lost with the `Cpu`.
+    pub fn set_flag_store(&mut self, store: Box<dyn FlagStore>) {
+        self.flag_store = store;
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// Overrides the quirks of the platform, e.g. for a program known to
+    /// depend on a different behaviour.
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Number of instructions executed since the last reset.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn timing(&self) -> Option<&Timing> {
+        self.timing.as_ref()
+    }
+
+    pub fn screen(&self) -> &[u8] {
+        &self.display.screen
+    }
+
+    /// Renders the display as text, one line per row.
+    pub fn render_screen(&self) -> String {
+        self.display.render()
+    }
+
+    /// Sets the state of all keys at once, bit `n` being set when key `n` is
+    /// down.
+    pub fn set_keys(&mut self, keys: u16) {
+        for (n, key) in self.keypad.keys.iter_mut().enumerate() {
+            *key = keys & 1 << n != 0;
+        }
+    }
+
+    /// Makes CXNN deterministic: restarts the random number generator from
+    /// `seed`, now and on every `reset` and `load_rom`.
+    pub fn seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Total number of instructions skipped by `run_frame` since the last reset.
+    pub fn idle_cycles(&self) -> u64 {
+        self.idle_cycles
+    }
+
+    /// Snapshot of the machine: display mode, memory, registers, stack,
+    /// timers, keypads, screen, cycle count, CHIP-8X's tone, input port and
+    /// colours, XO-CHIP's planes, and MegaChip's display state and sound.
+    /// Profiling and coverage data aren't included.
+    ///
+    /// The layout is the magic `CH9S` and a version byte, then the display
+    /// mode: width and height and a byte telling whether MegaChip mode is
+    /// on. The fields follow in declaration order, 16, 32 and 64-bit values
+    /// big-endian. The input port is a byte telling whether it holds a
+    /// value, then the value. The colours, only on displays that have them,
+    /// are the background and then the foreground of every strip. XO-CHIP
+    /// adds the selected planes, a byte. In
+    /// MegaChip mode the palette, sprite size, blending, alpha, collision
+    /// index and picture come next. Last is a byte telling whether a sound
+    /// plays, followed by its rate, looping flag, position, length and
+    /// samples.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::new();
+        state.extend_from_slice(STATE_MAGIC);
+        state.push(STATE_VERSION);
+        state.extend_from_slice(&(self.display.width as u16).to_be_bytes());
+        state.extend_from_slice(&(self.display.height as u16).to_be_bytes());
+        state.push(self.display.mega.is_some() as u8);
+        state.extend_from_slice(&self.memory);
+        state.extend_from_slice(&self.v);
+        state.extend_from_slice(&self.i.to_be_bytes());
+        state.push(self.i_page);
+        state.extend_from_slice(&self.pc.to_be_bytes());
+        for address in &self.stack {
+            state.extend_from_slice(&address.to_be_bytes());
+        }
+        state.push(self.sp);
+        state.push(self.dt);
+        state.extend(self.keypad.keys.iter().map(|key| *key as u8));
+        state.extend_from_slice(&self.display.screen);
+        state.extend_from_slice(&(self.rom_size as u32).to_be_bytes());
+        state.extend_from_slice(&self.cycles.to_be_bytes());
+        state.extend(self.keypad.second.iter().map(|key| *key as u8));
+        state.push(self.tone);
+        state.push(self.port_input.is_some() as u8);
+        state.push(self.port_input.unwrap_or(0));
+        if let Some(colours) = &self.display.colours {
+            state.push(colours.background);
+            state.extend_from_slice(&colours.foreground);
+        }
+        if self.platform.descriptor().instructions.xochip {
+            state.push(self.display.planes);
+        }
+        if let Some(mega) = &self.display.mega {
+            for colour in mega.palette.iter() {
+                state.extend_from_slice(colour);
+            }
+            state.extend_from_slice(&(mega.sprite_width as u16).to_be_bytes());
+            state.extend_from_slice(&(mega.sprite_height as u16).to_be_bytes());
+            state.push(mega.blend.mode());
+            state.push(mega.alpha);
+            state.push(mega.collision);
+            for pixel in &mega.picture {
+                state.extend_from_slice(pixel);
+            }
+        }
+        state.push(self.sample.is_some() as u8);
+        if let Some(sample) = &self.sample {
+            state.extend_from_slice(&sample.rate.to_be_bytes());
+            state.push(sample.looping as u8);
+            state.extend_from_slice(&sample.position.to_bits().to_be_bytes());
+            state.extend_from_slice(&(sample.data.len() as u32).to_be_bytes());
+            state.extend_from_slice(&sample.data);
+        }
+        state
+    }
+
+    /// Restores a snapshot taken by `save_state`, switching the display to
+    /// the mode it was taken in. Clears any fault.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        if !state.starts_with(STATE_MAGIC) {
+            return Err(StateError::NotASnapshot);
+        }
+        match state.get(STATE_MAGIC.len()) {
+            Some(&STATE_VERSION) => (),
+            Some(&version) => return Err(StateError::UnsupportedVersion(version)),
+            None => return Err(StateError::BadLength(state.len())),
+        }
+
+        let mut fields = &state[STATE_MAGIC.len() + 1..];
+        let mut take = |len: usize| {
+            if len > fields.len() {
+                return Err(StateError::BadLength(state.len()));
+            }
+            let (field, rest) = fields.split_at(len);
+            fields = rest;
+            Ok(field)
+        };
+        let width = u16::from_be_bytes([take(1)?[0], take(1)?[0]]) as usize;
+        let height = u16::from_be_bytes([take(1)?[0], take(1)?[0]]) as usize;
+        let mega = take(1)?[0] != 0;
+        let descriptor = self.platform.descriptor();
+        let mut display = if mega {
+            Display::mega()
+        } else if ![(descriptor.width, descriptor.height), HIGH_RES].contains(&(width, height)) {
+            return Err(StateError::Corrupted);
+        } else if self.display.colours.is_some() {
+            Display::with_colours(width, height)
+        } else {
+            Display::new(width, height)
+        };
+        if (display.width, display.height) != (width, height) {
+            return Err(StateError::Corrupted);
+        }
+
+        let memory = take(self.memory.len())?;
+        let v = take(16)?;
+        let i = u16::from_be_bytes([take(1)?[0], take(1)?[0]]);
+        let i_page = take(1)?[0];
+        let pc = u16::from_be_bytes([take(1)?[0], take(1)?[0]]);
+        let stack = take(32)?;
+        let sp = take(1)?[0];
+        let dt = take(1)?[0];
+        let keys = take(16)?;
+        display.screen.copy_from_slice(take(width * height)?);
+        let mut rom_size = [0; 4];
+        rom_size.copy_from_slice(take(4)?);
+        let mut cycles = [0; 8];
+        cycles.copy_from_slice(take(8)?);
+        let second_keys = take(16)?;
+        let tone = take(1)?[0];
+        let port = take(2)?;
+        let port_input = if port[0] != 0 { Some(port[1]) } else { None };
+        if let Some(colours) = display.colours.as_mut() {
+            colours.background = take(1)?[0];
+            let len = colours.foreground.len();
+            colours.foreground.copy_from_slice(take(len)?);
+        }
+        if descriptor.instructions.xochip {
+            display.planes = take(1)?[0];
+        }
+        if let Some(mega) = display.mega.as_mut() {
+            for (colour, bytes) in mega.palette.iter_mut().zip(take(256 * 4)?.chunks(4)) {
+                colour.copy_from_slice(bytes);
+            }
+            mega.sprite_width = u16::from_be_bytes([take(1)?[0], take(1)?[0]]) as usize;
+            mega.sprite_height = u16::from_be_bytes([take(1)?[0], take(1)?[0]]) as usize;
+            mega.blend = Blend::from_mode(take(1)?[0]);
+            mega.alpha = take(1)?[0];
+            mega.collision = take(1)?[0];
+            for (pixel, bytes) in mega
+                .picture
+                .iter_mut()
+                .zip(take(width * height * 3)?.chunks(3))
+            {
+                pixel.copy_from_slice(bytes);
+            }
+        }
+        let sample = if take(1)?[0] != 0 {
+            let mut rate = [0; 4];
+            rate.copy_from_slice(take(4)?);
+            let looping = take(1)?[0] != 0;
+            let mut position = [0; 8];
+            position.copy_from_slice(take(8)?);
+            let mut len = [0; 4];
+            len.copy_from_slice(take(4)?);
+            Some(Sample {
+                rate: u32::from_be_bytes(rate),
+                looping,
+                position: f64::from_bits(u64::from_be_bytes(position)),
+                data: take(u32::from_be_bytes(len) as usize)?.to_vec(),
+            })
+        } else {
+            None
+        };
+        if !fields.is_empty() {
+            return Err(StateError::BadLength(state.len()));
+        }
+        if sp as usize > self.stack.len() {
+            return Err(StateError::Corrupted);
+        }
+
+        self.memory.copy_from_slice(memory);
+        self.v.copy_from_slice(v);
+        self.i = i;
+        self.i_page = i_page;
+        self.pc = pc;
+        for (entry, bytes) in self.stack.iter_mut().zip(stack.chunks(2)) {
+            *entry = u16::from_be_bytes([bytes[0], bytes[1]]);
+        }
+        self.sp = sp;
+        self.dt = dt;
+        for (key, state) in self.keypad.keys.iter_mut().zip(keys) {
+            *key = *state != 0;
+        }
+        self.display = display;
+        self.rom_size = u32::from_be_bytes(rom_size) as usize;
+        self.cycles = u64::from_be_bytes(cycles);
+        for (key, state) in self.keypad.second.iter_mut().zip(second_keys) {
+            *key = *state != 0;
+        }
+        self.tone = tone;
+        self.port_input = port_input;
+        self.sample = sample;
+        self.idle_probe = None;
+        self.fault = None;
+        self.invalidate_cache();
+        Ok(())
+    }
+
+    fn run_timed_frame(&mut self) {
+        let mut first = true;
+        if let Some(timing) = self.timing.as_mut() {
+            timing.start_frame();
+        }
+        while self.fault.is_none() && self.timing.as_ref().is_some_and(Timing::has_budget) {
+            if self.check_fetch().is_err() {
+                self.process_opcode_cached();
+                break;
+            }
+            let instruction = self.decoded(self.pc);
+            let timing = self.timing.as_mut().unwrap();
+            // the interpreter draws right after the display interrupt
+            if matches!(instruction, Instruction::Draw(..)) && !first {
+                timing.wait_for_vblank();
+                break;
+            }
+            timing.spend(timing::cycles(instruction, &self.v));
+            self.process_opcode_cached();
+            first = false;
+        }
+    }
+
+    fn decoded(&mut self, address: u16) -> Instruction {
+        let address = address as usize;
+        match self.cache[address] {
+            Some(instruction) => instruction,
+            None => {
+                let opcode = (self.memory[address] as u16) << 8 | self.memory[address + 1] as u16;
+                let instruction = self.platform.decode(opcode);
+                self.cache[address] = Some(instruction);
+                instruction
+            }
+        }
+    }
+
+    // A loop is idle when it only polls the delay timer or the keypad and
+    // otherwise just shuffles registers around: once it comes back to its
+    // jump with the exact same registers, it will keep doing so until the
+    // timer ticks or a key changes.
+    fn is_idle(&mut self) -> bool {
+        // left the loop, it has to be proven idle again when it comes back
+        if let Some(probe) = self.idle_probe {
+            if self.pc < probe.target || self.pc > probe.pc {
+                self.idle_probe = None;
+            }
+        }
+        if self.check_fetch().is_err() {
+            return false;
+        }
+        let target = match self.decoded(self.pc) {
+            Instruction::WaitKey(_) => return !self.keypad.keys.iter().any(|key| *key),
+            Instruction::Jump(target) if target <= self.pc && self.pc - target <= MAX_IDLE_LOOP => {
+                target
+            }
+            _ => return false,
+        };
+
+        let mut address = target;
+        while address < self.pc {
+            match self.decoded(address) {
+                Instruction::Draw(..)
+                | Instruction::Cls
+                | Instruction::HiresCls
+                | Instruction::ScrollDown(_)
+                | Instruction::ScrollUp(_)
+                | Instruction::ScrollRight
+                | Instruction::ScrollLeft
+                | Instruction::LowRes
+                | Instruction::HighRes
+                | Instruction::Random(..)
+                | Instruction::Call(_)
+                | Instruction::Ret
+                | Instruction::JumpV0(_)
+                | Instruction::Bcd(_)
+                | Instruction::Store(_)
+                | Instruction::SetDelay(_)
+                | Instruction::SetSound(_)
+                | Instruction::WaitKey(_)
+                | Instruction::Background
+                | Instruction::ColourZones(..)
+                | Instruction::ColourRows(..)
+                | Instruction::Output(_)
+                | Instruction::Input(_)
+                | Instruction::MegaOff
+                | Instruction::MegaOn
+                | Instruction::LongIndex(_)
+                | Instruction::LoadPalette(_)
+                | Instruction::SpriteWidth(_)
+                | Instruction::SpriteHeight(_)
+                | Instruction::ScreenAlpha(_)
+                | Instruction::PlaySample(_)
+                | Instruction::StopSample
+                | Instruction::BlendMode(_)
+                | Instruction::CollisionColour(_)
+                | Instruction::SaveFlags(_)
+                | Instruction::SetLongIndex
+                | Instruction::Planes(_)
+                | Instruction::StoreRange(..)
+                | Instruction::Unknown(_) => return false,
+                // anything leaving the body, other than through the exit right
+                // after the loop's jump, could run code that wasn't checked
+                Instruction::Jump(nnn) if nnn < target || nnn > self.pc + 2 => return false,
+                Instruction::SkipEq(..)
+                | Instruction::SkipNe(..)
+                | Instruction::SkipEqReg(..)
+                | Instruction::SkipNeReg(..)
+                | Instruction::SkipKeyDown(_)
+                | Instruction::SkipKeyUp(_)
+                | Instruction::SkipKey2Down(_)
+                | Instruction::SkipKey2Up(_)
+                    if address + 4 > self.pc + 2 =>
+                {
+                    return false
+                }
+                _ => address += 2,
+            }
+        }
+
+        let probe = IdleProbe {
+            target,
+            pc: self.pc,
+            v: self.v,
+            i: self.i,
+            i_page: self.i_page,
+        };
+        if self.idle_probe == Some(probe) {
+            return true;
+        }
+        self.idle_probe = Some(probe);
+        false
+    }
+
+    pub(crate) fn write_memory(&mut self, address: usize, value: u8) {
+        self.memory[address] = value;
+        if let Some(log) = self.write_log.as_mut() {
+            log.push(address);
+        }
+        // an opcode spans two bytes, so the instruction starting one byte
+        // earlier is stale as well
+        if let Some(entry) = self.cache.get_mut(address) {
+            *entry = None;
+        }
+        if let Some(entry) = address.checked_sub(1).and_then(|a| self.cache.get_mut(a)) {
+            *entry = None;
+        }
+    }
+
+    fn run_instruction(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        // I as the instruction sees it, before it moves
+        let (pc, index) = (self.pc, self.index());
+        let read = match self.coverage {
+            Some(_) => self.data_len(instruction),
+            None => 0,
+        };
+        self.execute(instruction)?;
+        self.cycles += 1;
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(pc, instruction, self.cycles);
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record(pc, index, read);
+        }
+        Ok(())
+    }
+
+    // PC has to be able to move past the instruction without going over
+    // 16 bits
+    fn check_fetch(&self) -> Result<(), CpuError> {
+        if self.pc as usize + 1 < self.memory.len() && self.pc as usize + 2 <= u16::MAX as usize {
+            Ok(())
+        } else {
+            Err(CpuError::MemoryOutOfBounds {
+                pc: self.pc,
+                address: self.pc,
+            })
+        }
+    }
+
+    // Everything that can go wrong is checked before the instruction touches
+    // any state, so a failed instruction can simply be retried.
+    fn check(&self, instruction: Instruction) -> Result<(), CpuError> {
+        let pc = self.pc;
+        let access = |len: usize| {
+            if self.index() + len <= self.memory.len() {
+                Ok(())
+            } else {
+                Err(CpuError::MemoryOutOfBounds {
+                    pc,
+                    address: self.i,
+                })
+            }
+        };
+
+        match instruction {
+            _ if !self.platform.supports(instruction) => Err(CpuError::UnsupportedOpcode {
+                pc,
+                opcode: (self.memory[pc as usize] as u16) << 8
+                    | self.memory[pc as usize + 1] as u16,
+                platform: self.platform,
+            }),
+            Instruction::Ret if self.sp == 0 => Err(CpuError::StackUnderflow { pc }),
+            Instruction::Call(_) if self.sp as usize == self.stack.len() => {
+                Err(CpuError::StackOverflow { pc })
+            }
+            Instruction::LongIndex(_) | Instruction::SetLongIndex
+                if pc as usize + 3 >= self.memory.len() || pc as usize + 4 > u16::MAX as usize =>
+            {
+                Err(CpuError::MemoryOutOfBounds {
+                    pc,
+                    address: pc.wrapping_add(2),
+                })
+            }
+            Instruction::SkipEq(..)
+            | Instruction::SkipNe(..)
+            | Instruction::SkipEqReg(..)
+            | Instruction::SkipNeReg(..)
+            | Instruction::SkipKeyDown(_)
+            | Instruction::SkipKeyUp(_)
+            | Instruction::SkipKey2Down(_)
+            | Instruction::SkipKey2Up(_)
+                if pc as usize + 2 + self.skipped(pc as usize + 2) > u16::MAX as usize =>
+            {
+                Err(CpuError::MemoryOutOfBounds {
+                    pc,
+                    address: pc + 2,
+                })
+            }
+            Instruction::Draw(..)
+            | Instruction::Load(_)
+            | Instruction::LoadRange(..)
+            | Instruction::LoadPalette(_)
+            | Instruction::PlaySample(_) => access(self.data_len(instruction)),
+            Instruction::Bcd(_) => access(3),
+            Instruction::Store(x) => access(x + 1),
+            Instruction::StoreRange(x, y) => access(x.abs_diff(y) + 1),
+            _ => Ok(()),
+        }
+    }
+
+    // bytes `instruction` reads from memory at I on this platform, in the
+    // current display mode
+    fn data_len(&self, instruction: Instruction) -> usize {
+        match instruction {
+            // a sprite for each selected plane
+            Instruction::Draw(_, _, n) => match &self.display.mega {
+                Some(mega) => mega.sprite_width * mega.sprite_height,
+                None if n == 0 && self.platform.descriptor().instructions.schip => {
+                    LARGE_SPRITE * self.display.planes.count_ones() as usize
+                }
+                None => n as usize * self.display.planes.count_ones() as usize,
+            },
+            Instruction::Load(x) => x + 1,
+            Instruction::LoadRange(x, y) => x.abs_diff(y) + 1,
+            Instruction::LoadPalette(nn) => nn as usize * 4,
+            // the header of a sound that doesn't fit in memory, so that
+            // `check` rejects it
+            Instruction::PlaySample(_) => {
+                Sample::size(self.memory.get(self.index()..).unwrap_or(&[])).unwrap_or(6)
+            }
+            _ => 0,
+        }
+    }
+
+    pub(crate) fn execute(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        self.check(instruction)?;
         self.pc += 2;
 
-        match (op_1, op_2, op_3, op_4) {
-            // clears the display
-            (0, 0, 0xE, 0) => self.display.cls(),
-            // returns from a subroutine
-            (0, 0, 0xE, 0xE) => {
-                self.sp = self.sp - 1;
+        match instruction {
+            Instruction::Cls | Instruction::HiresCls => self.display.cls(),
+            Instruction::Ret => {
+                self.sp -= 1;
                 self.pc = self.stack[self.sp as usize];
             }
-            // Jumps to address
-            (0x1, _, _, _) => self.pc = nnn,
-            // Calls subroutine
-            (0x2, _, _, _) => {
+            Instruction::Jump(nnn) => self.pc = nnn,
+            Instruction::Call(nnn) => {
                 self.stack[self.sp as usize] = self.pc;
                 self.sp += 1;
                 self.pc = nnn;
             }
-            // Skips the next instruction if VX equals NN
-            (0x3, _, _, _) => {
-                if vx == nn {
-                    self.pc += 2;
+            Instruction::SkipEq(x, nn) => {
+                if self.v[x] == nn {
+                    self.skip();
                 }
             }
-            // Skips the next instruction if VX doesn't equal NN
-            (0x4, _, _, _) => {
-                if vx != nn {
-                    self.pc += 2;
+            Instruction::SkipNe(x, nn) => {
+                if self.v[x] != nn {
+                    self.skip();
                 }
             }
-            // Skips the next instruction if VX equals VY
-            (0x5, _, _, _) => {
-                if vx == vy {
-                    self.pc += 2;
+            Instruction::SkipEqReg(x, y) => {
+                if self.v[x] == self.v[y] {
+                    self.skip();
                 }
             }
-            // Set VX to NN
-            (0x6, _, _, _) => self.v[x] = nn,
-            // Adds NN to VX (Carry flag is not changed)
-            (0x7, _, _, _) => self.v[x] += nn,
-            // Sets VX to the value of VY
-            (0x8, _, _, 0x0) => self.v[x] = self.v[y],
-            // Sets VX to VX or VY (Bitwise OR operation)
-            (0x8, _, _, 0x1) => self.v[x] = self.v[x] | self.v[y],
-            // Sets VX to VX and VY (Bitwise AND operation)
-            (0x8, _, _, 0x2) => self.v[x] = self.v[x] & self.v[y],
-            // Sets VX to VX xor VY
-            (0x8, _, _, 0x3) => self.v[x] = self.v[x] ^ self.v[y],
-            // Adds VY to VX. VF is set to 1 when there's a carry, and to 0 when there isn't
-            (0x8, _, _, 0x4) => {
+            Instruction::Set(x, nn) => self.v[x] = nn,
+            Instruction::Add(x, nn) => self.v[x] = self.v[x].wrapping_add(nn),
+            Instruction::Assign(x, y) => self.v[x] = self.v[y],
+            Instruction::Or(x, y) => {
+                self.v[x] |= self.v[y];
+                self.reset_vf();
+            }
+            Instruction::And(x, y) => {
+                self.v[x] &= self.v[y];
+                self.reset_vf();
+            }
+            Instruction::Xor(x, y) => {
+                self.v[x] ^= self.v[y];
+                self.reset_vf();
+            }
+            Instruction::AddReg(x, y) => {
                 let (res, overflow) = self.v[x].overflowing_add(self.v[y]);
                 self.v[0xF] = if overflow { 1 } else { 0 };
                 self.v[x] = res;
             }
-            // VY is subtracted from VX. VF is set to 0 when there's a borrow, and 1 when there isn't
-            (0x8, _, _, 0x5) => {
+            Instruction::SubReg(x, y) => {
                 let (res, overflow) = self.v[x].overflowing_sub(self.v[y]);
                 self.v[0xF] = if overflow { 0 } else { 1 };
                 self.v[x] = res;
             }
-            // Stores the least significant bit of VX in VF and then shifts VX to the right by 1
-            (0x8, _, _, 0x6) => {
-                self.v[0xF] = self.v[x] & 0x1;
-                self.v[x] >>= 1;
+            Instruction::ShiftRight(x, y) => {
+                let value = if self.quirks.shift_vy {
+                    self.v[y]
+                } else {
+                    self.v[x]
+                };
+                if self.quirks.shift_flag_last {
+                    self.v[x] = value >> 1;
+                    self.v[0xF] = value & 0x1;
+                } else {
+                    self.v[0xF] = value & 0x1;
+                    self.v[x] = value >> 1;
+                }
             }
-            // Sets VX to VY minus VX. VF is set to 0 when there's a borrow, and 1 when there isn't
-            (0x8, _, _, 0x7) => {
+            Instruction::SubN(x, y) => {
                 let (res, overflow) = self.v[y].overflowing_sub(self.v[x]);
                 self.v[0xF] = if overflow { 0 } else { 1 };
                 self.v[x] = res;
             }
-            // Stores the most significant bit of VX in VF and then shifts VX to the left by 1
-            (0x8, _, _, 0xE) => {
-                self.v[0xF] = (self.v[x] & 0x80) >> 7;
-                self.v[x] <<= 1;
+            Instruction::ShiftLeft(x, y) => {
+                let value = if self.quirks.shift_vy {
+                    self.v[y]
+                } else {
+                    self.v[x]
+                };
+                if self.quirks.shift_flag_last {
+                    self.v[x] = value << 1;
+                    self.v[0xF] = (value & 0x80) >> 7;
+                } else {
+                    self.v[0xF] = (value & 0x80) >> 7;
+                    self.v[x] = value << 1;
+                }
             }
-            // Skips the next instruction if VX doesn't equal VY
-            (0x9, _, _, _) => {
-                if vx != vy {
-                    self.pc += 2;
+            Instruction::SkipNeReg(x, y) => {
+                if self.v[x] != self.v[y] {
+                    self.skip();
                 }
             }
-            // Sets I to the address NNN
-            (0xA, _, _, _) => self.i = nnn,
-            // Jumps to the address NNN plus V0
-            (0xB, _, _, _) => self.pc = nnn + self.v[0] as u16,
-            // Vx = rand() & NN
-            (0xC, _, _, _) => {
+            Instruction::SetIndex(nnn) => {
+                self.i = nnn;
+                self.i_page = 0;
+            }
+            Instruction::JumpV0(nnn) => {
+                let x = if self.quirks.jump_vx {
+                    (nnn >> 8) as usize
+                } else {
+                    0
+                };
+                self.pc = nnn + self.v[x] as u16;
+            }
+            Instruction::Random(x, nn) => {
                 let number = self.rng.gen_range(0, 255);
                 self.v[x] = number & nn;
             }
-            // Draw the sprite
-            (0xD, _, _, _) => {
-                let height = n;
-                let collision = self.display.draw(
-                    vx as usize,
-                    vy as usize,
-                    &self.memory[self.i as usize..(self.i + height as u16) as usize],
-                );
-                self.v[0xF] = if collision { 1 } else { 0 };
+            Instruction::Draw(x, y, _) if self.display.mega.is_some() => {
+                let index = self.index();
+                let mega = self.display.mega.as_ref().unwrap();
+                let len = mega.sprite_width * mega.sprite_height;
+                let collision = self.display.draw_mega(
+                    self.v[x] as usize,
+                    self.v[y] as usize,
+                    &self.memory[index..index + len],
+                );
+                self.v[0xF] = collision as u8;
+            }
+            Instruction::Draw(x, y, 0) if self.platform.descriptor().instructions.schip => {
+                let index = self.index();
+                let len = self.data_len(instruction);
+                let collision = self.display.draw_large(
+                    self.v[x] as usize,
+                    self.v[y] as usize,
+                    &self.memory[index..index + len],
+                    self.quirks.clip,
+                );
+                self.v[0xF] = collision as u8;
+            }
+            Instruction::Draw(x, y, _) => {
+                let len = self.data_len(instruction);
+                let collision = self.display.draw(
+                    self.v[x] as usize,
+                    self.v[y] as usize,
+                    &self.memory[self.index()..self.index() + len],
+                    self.quirks.clip,
+                );
+                self.v[0xF] = if collision { 1 } else { 0 };
+            }
+            // (Usually the next instruction is a jump to skip a code block)
+            Instruction::SkipKeyDown(x) => {
+                if self.keypad.is_key_down(self.v[x]) {
+                    self.skip();
+                }
+            }
+            Instruction::SkipKeyUp(x) => {
+                if !self.keypad.is_key_down(self.v[x]) {
+                    self.skip();
+                }
+            }
+            Instruction::GetDelay(x) => self.v[x] = self.dt,
+            // Blocking Operation. All instruction halted until next key event
+            Instruction::WaitKey(x) => {
+                self.pc -= 2;
+                for (i, key) in self.keypad.keys.iter().enumerate() {
+                    if *key {
+                        self.v[x] = i as u8;
+                        self.pc += 2;
+                    }
+                }
+            }
+            // only the low digit of VX picks the key
+            Instruction::SkipKey2Down(x) => {
+                if self.keypad.is_second_key_down(self.v[x] & 0xF) {
+                    self.skip();
+                }
+            }
+            Instruction::SkipKey2Up(x) => {
+                if !self.keypad.is_second_key_down(self.v[x] & 0xF) {
+                    self.skip();
+                }
+            }
+            Instruction::Background => self.display.cycle_background(),
+            // SUPER-CHIP scrolls by pixels of the mode it's in
+            Instruction::ScrollDown(n) => self.display.scroll(0, n as isize),
+            Instruction::ScrollUp(n) => self.display.scroll(0, -(n as isize)),
+            Instruction::ScrollRight => self.display.scroll(4, 0),
+            Instruction::ScrollLeft => self.display.scroll(-4, 0),
+            // stays on 00FD for good, the way FX0A waits for a key
+            Instruction::Exit => self.pc -= 2,
+            // MegaChip mode keeps its own display
+            Instruction::LowRes | Instruction::HighRes if self.display.mega.is_some() => (),
+            // the planes stay selected
+            Instruction::LowRes => {
+                let descriptor = self.platform.descriptor();
+                let planes = self.display.planes;
+                self.display = Display::new(descriptor.width, descriptor.height);
+                self.display.planes = planes;
+            }
+            Instruction::HighRes => {
+                let planes = self.display.planes;
+                self.display = Display::new(HIGH_RES.0, HIGH_RES.1);
+                self.display.planes = planes;
+            }
+            Instruction::MegaOn => {
+                if self.display.mega.is_none() {
+                    self.display = Display::mega();
+                }
+            }
+            Instruction::MegaOff => {
+                if self.display.mega.is_some() {
+                    let descriptor = self.platform.descriptor();
+                    self.display = Display::new(descriptor.width, descriptor.height);
+                }
+            }
+            Instruction::LongIndex(nn) => {
+                let pc = self.pc as usize;
+                self.i = (self.memory[pc] as u16) << 8 | self.memory[pc + 1] as u16;
+                self.i_page = nn;
+                self.pc += 2;
+            }
+            Instruction::SetLongIndex => {
+                let pc = self.pc as usize;
+                self.i = (self.memory[pc] as u16) << 8 | self.memory[pc + 1] as u16;
+                self.i_page = 0;
+                self.pc += 2;
+            }
+            // XO-CHIP has two planes
+            Instruction::Planes(n) => self.display.planes = n & 0x3,
+            Instruction::StoreRange(x, y) => {
+                let index = self.index();
+                for (offset, r) in register_range(x, y).enumerate() {
+                    self.write_memory(index + offset, self.v[r]);
+                }
+            }
+            Instruction::LoadRange(x, y) => {
+                let index = self.index();
+                for (offset, r) in register_range(x, y).enumerate() {
+                    self.v[r] = self.memory[index + offset];
+                }
+            }
+            Instruction::PlaySample(n) => {
+                self.sample = Some(Sample::parse(&self.memory[self.index()..], n == 0));
+            }
+            Instruction::StopSample => self.sample = None,
+            // the rest of MegaChip's settings only exist in its mode
+            Instruction::LoadPalette(nn) => {
+                let index = self.index();
+                if let Some(mega) = self.display.mega.as_mut() {
+                    mega.load_palette(&self.memory[index..], nn as usize);
+                }
+            }
+            Instruction::SpriteWidth(nn) => {
+                if let Some(mega) = self.display.mega.as_mut() {
+                    mega.sprite_width = if nn == 0 { 256 } else { nn as usize };
+                }
+            }
+            Instruction::SpriteHeight(nn) => {
+                if let Some(mega) = self.display.mega.as_mut() {
+                    mega.sprite_height = if nn == 0 { 256 } else { nn as usize };
+                }
+            }
+            Instruction::ScreenAlpha(nn) => {
+                if let Some(mega) = self.display.mega.as_mut() {
+                    mega.alpha = nn;
+                }
+            }
+            Instruction::BlendMode(n) => {
+                if let Some(mega) = self.display.mega.as_mut() {
+                    mega.blend = Blend::from_mode(n);
+                }
+            }
+            Instruction::CollisionColour(nn) => {
+                if let Some(mega) = self.display.mega.as_mut() {
+                    mega.collision = nn;
+                }
+            }
+            Instruction::AddColour(x, y) => {
+                self.v[x] = ((self.v[x] & 0x77) + (self.v[y] & 0x77)) & 0x77;
+            }
+            // VX and VX+1 hold the first column and row of 8x4 zones in their
+            // low nibble, how many more follow in their high one
+            Instruction::ColourZones(x, y) => {
+                let (columns, rows) = (self.v[x], self.v[(x + 1) & 0xF]);
+                self.display.colour_area(
+                    (columns & 0xF) as usize * 8,
+                    (rows & 0xF) as usize * 4,
+                    (columns >> 4) as usize * 8 + 8,
+                    (rows >> 4) as usize * 4 + 4,
+                    self.v[y],
+                );
+            }
+            Instruction::ColourRows(x, y, n) => self.display.colour_area(
+                self.v[x] as usize,
+                self.v[y] as usize,
+                8,
+                n as usize,
+                self.v[(x + 1) & 0xF],
+            ),
+            Instruction::Output(x) => self.tone = self.v[x],
+            Instruction::Input(x) => match self.port_input.take() {
+                Some(value) => self.v[x] = value,
+                None => self.pc -= 2,
+            },
+            Instruction::SetDelay(x) => self.dt = self.v[x],
+            // not implemented yet
+            Instruction::SetSound(_) => (),
+            Instruction::AddIndex(x) => self.i = self.i.wrapping_add(self.v[x] as u16),
+            // 5 bytes per digit
+            Instruction::Font(x) => {
+                self.i = self.font_address + (self.v[x] & 0xF) as u16 * 5;
+                self.i_page = 0;
+            }
+            // 10 bytes per digit after the small ones, whether the font has
+            // this one or not
+            Instruction::LargeFont(x) => {
+                self.i =
+                    self.font_address + self.font.large_offset() + (self.v[x] & 0xF) as u16 * 10;
+                self.i_page = 0;
+            }
+            Instruction::Bcd(x) => {
+                let i = self.index();
+                let vx = self.v[x];
+                self.write_memory(i, vx / 100);
+                self.write_memory(i + 1, vx / 10 % 10);
+                self.write_memory(i + 2, vx % 10);
+            }
+            Instruction::Store(x) => {
+                for r in 0..=x {
+                    self.write_memory(self.index() + r, self.v[r]);
+                }
+                self.advance_index(x);
+            }
+            Instruction::Load(x) => {
+                for r in 0..=x {
+                    self.v[r] = self.memory[self.index() + r];
+                }
+                self.advance_index(x);
+            }
+            Instruction::SaveFlags(x) => {
+                let mut flags = self.load_flags()?;
+                flags[..=x].copy_from_slice(&self.v[..=x]);
+                self.flag_store
+                    .save(self.rom_key, &flags)
+                    .map_err(|_| CpuError::FlagStorage { pc: self.pc - 2 })?;
+            }
+            Instruction::LoadFlags(x) => {
+                let flags = self.load_flags()?;
+                self.v[..=x].copy_from_slice(&flags[..=x]);
             }
-            // Skips the next instruction if the key stored in VX is pressed.
-            // (Usually the next instruction is a jump to skip a code block)
-            (0xE, _, 0x9, 0xE) => self.pc += if self.keypad.is_key_down(vx) { 2 } else { 0 },
-            // SKips the next instruction if the key stored in VX isn't pressed.
-            // (Usually the next instruction is a jump to skip a code block)
-            (0xE, _, 0xA, 0x1) => self.pc += if self.keypad.is_key_down(vx) { 0 } else { 2 },
-            //Sets VX to the value of the delay timer.
-            (0xF, _, 0x0, 0x7) => self.v[x] = self.dt,
-            // A key press is awaited, and then stored in VX. (Blocking Operation. All instruction halted until next key event)
-            (0xF, _, 0x0, 0xA) => {
-               self.pc -= 2;
-               for (i, key) in self.keypad.keys.iter().enumerate() {
-                   if *key == true {
-                       self.v[x] = i as u8;
-                       self.pc += 2;
-                   }
-               }
-            },
-            // Sets the delay timer to VX.
-            (0xF, _, 0x1, 0x5) => self.dt = self.v[x],
-            // not implemented yet
-            (0xF, _, 0x1, 0x8) => (),
-            // Adds VX to I. VF is not affected
-            (0xF, _, 0x1, 0xE) => self.i = self.i + self.v[x] as u16,
-            (_, _, _, _) => (),
+            Instruction::Unknown(_) => (),
+        }
+        Ok(())
+    }
+
+    // the flags saved for the loaded ROM, all zero when there are none yet
+    fn load_flags(&mut self) -> Result<Flags, CpuError> {
+        match self.flag_store.load(self.rom_key) {
+            Ok(flags) => Ok(flags.unwrap_or_default()),
+            Err(_) => Err(CpuError::FlagStorage { pc: self.pc - 2 }),
+        }
+    }
+
+    // I with the page MegaChip can put above it
+    // the memory PC can reach, 16 bits worth, MegaChip keeping only data
+    // past it
+    fn code_size(&self) -> usize {
+        self.memory.len().min(0x10000)
+    }
+
+    fn index(&self) -> usize {
+        (self.i_page as usize) << 16 | self.i as usize
+    }
+
+    // bytes a skip steps over at `address`, all four of XO-CHIP's
+    // F000 NNNN
+    fn skipped(&self, address: usize) -> usize {
+        if self.memory.get(address..address + 2) == Some(&[0xF0, 0x00])
+            && self.platform.descriptor().instructions.xochip
+        {
+            4
+        } else {
+            2
+        }
+    }
+
+    fn skip(&mut self) {
+        self.pc += self.skipped(self.pc as usize) as u16;
+    }
+
+    fn reset_vf(&mut self) {
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0;
         }
     }
+
+    fn advance_index(&mut self, x: usize) {
+        let advance = match self.quirks.load_store {
+            IndexQuirk::Unchanged => return,
+            IndexQuirk::Increment => x + 1,
+            IndexQuirk::IncrementX => x,
+        };
+        self.i = self.i.wrapping_add(advance as u16);
+    }
+}
+
+// the platform's digits, without the large ones where there's no FX30
+fn platform_font(descriptor: &Descriptor) -> Font {
+    let font = descriptor.font.font();
+    if descriptor.instructions.schip {
+        font
+    } else {
+        font.small_only()
+    }
+}
+
+// VX to VY for 5XY2 and 5XY3, going down when Y is below X
+fn register_range(x: usize, y: usize) -> Box<dyn Iterator<Item = usize>> {
+    if x <= y {
+        Box::new(x..=y)
+    } else {
+        Box::new((y..=x).rev())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use rand::Rng;
 
-    use super::Cpu;
+    use super::{Cpu, CpuError, StateError};
+    use crate::font::BuiltinFont;
+    use crate::instruction::Instruction;
+    use crate::megachip::Sample;
+    use crate::platform::Platform;
 
     // opcode: 0x00EE
     #[test]
@@ -543,6 +1977,28 @@ mod tests {
         assert_eq!(cpu.v[0xF], 1);
     }
 
+    // opcodes: 0x8FY6 and 0x8FYE
+    #[test]
+    fn test_shift_into_vf() {
+        // the shifted value ends up in VF, as it always did
+        let mut cpu = Cpu::new();
+        cpu.v[0xF] = 0x81;
+        cpu.execute(Instruction::ShiftRight(0xF, 0)).unwrap();
+        assert_eq!(cpu.v[0xF], 0x40);
+        cpu.v[0xF] = 0x81;
+        cpu.execute(Instruction::ShiftLeft(0xF, 0)).unwrap();
+        assert_eq!(cpu.v[0xF], 0x02);
+
+        // the VIP sets the flag last
+        cpu.set_platform(Platform::Chip8);
+        cpu.v[0xF] = 0x81;
+        cpu.execute(Instruction::ShiftRight(0xF, 0xF)).unwrap();
+        assert_eq!(cpu.v[0xF], 1);
+        cpu.v[0xF] = 0x81;
+        cpu.execute(Instruction::ShiftLeft(0xF, 0xF)).unwrap();
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
     // opcode: 0x9XY0
     #[test]
     fn test_vx_not_equal_vy() {
@@ -650,4 +2106,815 @@ mod tests {
         cpu.process_opcode();
         assert_eq!(cpu.i, 0xFF);
     }
+
+    // opcode: 0xFX29
+    #[test]
+    fn test_set_i_to_font_glyph() {
+        let mut cpu = Cpu::new();
+        cpu.memory[0] = 0xF0;
+        cpu.memory[1] = 0x29;
+        cpu.pc = 0;
+        cpu.v[0] = 0x1A;
+        assert_eq!(cpu.current_opcode(), 0xF029);
+
+        cpu.process_opcode();
+        assert_eq!(cpu.i, 50);
+    }
+
+    // opcode: 0xFX33
+    #[test]
+    fn test_store_bcd() {
+        let mut cpu = Cpu::new();
+        cpu.memory[0] = 0xF0;
+        cpu.memory[1] = 0x33;
+        cpu.pc = 0;
+        cpu.i = 0x300;
+        cpu.v[0] = 254;
+        assert_eq!(cpu.current_opcode(), 0xF033);
+
+        cpu.process_opcode();
+        assert_eq!(cpu.memory[0x300..0x303], [2, 5, 4]);
+    }
+
+    // opcode: 0xFX55
+    #[test]
+    fn test_store_registers() {
+        let mut cpu = Cpu::new();
+        cpu.memory[0] = 0xF2;
+        cpu.memory[1] = 0x55;
+        cpu.pc = 0;
+        cpu.i = 0x300;
+        cpu.v[0] = 1;
+        cpu.v[1] = 2;
+        cpu.v[2] = 3;
+        cpu.v[3] = 4;
+        assert_eq!(cpu.current_opcode(), 0xF255);
+
+        cpu.process_opcode();
+        assert_eq!(cpu.memory[0x300..0x304], [1, 2, 3, 0]);
+    }
+
+    // opcode: 0xFX65
+    #[test]
+    fn test_load_registers() {
+        let mut cpu = Cpu::new();
+        cpu.memory[0] = 0xF2;
+        cpu.memory[1] = 0x65;
+        cpu.memory[0x300..0x304].copy_from_slice(&[1, 2, 3, 4]);
+        cpu.pc = 0;
+        cpu.i = 0x300;
+        assert_eq!(cpu.current_opcode(), 0xF265);
+
+        cpu.process_opcode();
+        assert_eq!(cpu.v[0..4], [1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn test_cache_invalidated_by_memory_write() {
+        let mut cpu = Cpu::new();
+        // 0x200: V0 = 0x10, 0x202: store V0 at 0x205, 0x204: V1 = 0x00
+        cpu.memory[0x200..0x206].copy_from_slice(&[0x60, 0x10, 0xF0, 0x55, 0x61, 0x00]);
+        cpu.pc = 0x204;
+        cpu.process_opcode_cached();
+        assert_eq!(cpu.v[1], 0x00);
+
+        cpu.pc = 0x200;
+        cpu.i = 0x205;
+        cpu.run(3);
+        assert_eq!(cpu.v[1], 0x10);
+    }
+
+    #[test]
+    fn test_idle_loop_skips_rest_of_frame() {
+        let mut cpu = Cpu::new();
+        cpu.reset();
+        // 0x200: V0 = DT, skip if V0 == 0, jump 0x200
+        cpu.memory[0x200..0x206].copy_from_slice(&[0xF0, 0x07, 0x30, 0x00, 0x12, 0x00]);
+        cpu.dt = 2;
+
+        assert_eq!(cpu.run_frame(), 5);
+        assert_eq!(cpu.pc, 0x204);
+        // V0 still holds the previous timer value, one more lap is needed
+        assert_eq!(cpu.run_frame(), 4);
+        assert_eq!(cpu.dt, 0);
+        assert_eq!(cpu.run_frame(), 0);
+        assert_eq!(cpu.idle_cycles(), 9);
+    }
+
+    #[test]
+    fn test_busy_loop_is_not_idle() {
+        let mut cpu = Cpu::new();
+        cpu.reset();
+        // 0x200: V0 += 1, jump 0x200
+        cpu.memory[0x200..0x204].copy_from_slice(&[0x70, 0x01, 0x12, 0x00]);
+
+        assert_eq!(cpu.run_frame(), 0);
+        assert_eq!(cpu.v[0], 5);
+
+        // 0x200: I = 0x016000, jump 0x200, the second half of 01NN NNNN
+        // not being an instruction of its own
+        cpu.set_platform(Platform::MegaChip);
+        cpu.load_rom(&[0x01, 0x01, 0x60, 0x00, 0x12, 0x00]);
+        assert_eq!(cpu.run_frame(), 0);
+    }
+
+    #[test]
+    fn test_loop_leaving_its_body_is_not_idle() {
+        let mut cpu = Cpu::new();
+        cpu.reset();
+        // 0x200: V0 = DT, skip if V0 == 1, jump 0x20A, jump 0x200,
+        // 0x20A: store V0, jump 0x206
+        cpu.memory[0x200..0x20E].copy_from_slice(&[
+            0xF0, 0x07, 0x30, 0x01, 0x12, 0x0A, 0x12, 0x00, 0x60, 0x00, 0xF0, 0x55, 0x12, 0x06,
+        ]);
+        assert_eq!(cpu.run_frame(), 0);
+
+        // 0x200: V0 = DT, skip if V0 != 0, jump 0x200, 0x206: store V0,
+        // jump 0x204, the exit leading back to the loop's jump
+        cpu.reset();
+        cpu.dt = 1;
+        cpu.memory[0x200..0x20A]
+            .copy_from_slice(&[0xF0, 0x07, 0x40, 0x00, 0x12, 0x00, 0xF0, 0x55, 0x12, 0x04]);
+        assert_eq!(cpu.run_frame(), 0);
+    }
+
+    #[test]
+    fn test_vip_timing_budgets_cycles() {
+        let mut cpu = Cpu::new();
+        cpu.enable_vip_timing();
+        // 0x200: V0 += 1, jump 0x200
+        cpu.load_rom(&[0x70, 0x01, 0x12, 0x00]);
+
+        // 22 cycles a lap, the last jump overrunning the frame by 2 cycles
+        assert_eq!(cpu.run_frame(), 0);
+        assert_eq!(cpu.cycles(), 236);
+        assert_eq!(cpu.timing().unwrap().machine_cycles(), 2596);
+        cpu.run_frame();
+        assert_eq!(cpu.cycles(), 472);
+
+        cpu.disable_vip_timing();
+        cpu.run_frame();
+        assert_eq!(cpu.cycles(), 482);
+    }
+
+    #[test]
+    fn test_vip_timing_draw_waits_for_vblank() {
+        let mut cpu = Cpu::new();
+        cpu.enable_vip_timing();
+        // 0x200: V0 = 1, draw, jump 0x202
+        cpu.load_rom(&[0x60, 0x01, 0xD0, 0x05, 0x12, 0x02]);
+
+        cpu.run_frame();
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.timing().unwrap().machine_cycles(), 2594);
+        // one sprite per frame
+        cpu.run_frame();
+        assert_eq!((cpu.pc, cpu.cycles()), (0x202, 3));
+    }
+
+    #[test]
+    fn test_tick_follows_speed() {
+        let mut cpu = Cpu::new();
+        // 0x200: jump 0x200
+        cpu.load_rom(&[0x12, 0x00]);
+        cpu.set_speed(2.0);
+        cpu.tick();
+        assert_eq!(cpu.frame_count(), 2);
+
+        cpu.pause();
+        cpu.tick();
+        assert_eq!(cpu.frame_count(), 2);
+        cpu.advance_frame();
+        assert_eq!(cpu.frame_count(), 3);
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&[0x00, 0xEE]);
+
+        assert_eq!(cpu.step(), Err(CpuError::StackUnderflow { pc: 0x200 }));
+        assert_eq!(cpu.pc, 0x200);
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let mut cpu = Cpu::new();
+        // 0x200: call 0x200
+        cpu.load_rom(&[0x22, 0x00]);
+
+        for _ in 0..16 {
+            assert_eq!(cpu.step(), Ok(()));
+        }
+        assert_eq!(cpu.step(), Err(CpuError::StackOverflow { pc: 0x200 }));
+    }
+
+    #[test]
+    fn test_draw_out_of_bounds() {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&[0xD0, 0x15]);
+        cpu.i = 0xFFE;
+
+        assert_eq!(
+            cpu.step(),
+            Err(CpuError::MemoryOutOfBounds {
+                pc: 0x200,
+                address: 0xFFE
+            })
+        );
+    }
+
+    #[test]
+    fn test_end_of_address_space() {
+        let out_of_bounds =
+            |pc: u16, address: u16| Err(CpuError::MemoryOutOfBounds { pc, address });
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::MegaChip);
+        cpu.memory[0xFFFC..0x10000].copy_from_slice(&[0x01, 0x01, 0x00, 0xE0]);
+        cpu.pc = 0xFFFE;
+        assert_eq!(cpu.step(), out_of_bounds(0xFFFE, 0xFFFE));
+        // 01NN NNNN would leave PC past 16 bits
+        cpu.pc = 0xFFFC;
+        assert_eq!(cpu.step(), out_of_bounds(0xFFFC, 0xFFFE));
+
+        // and so would skipping F000 NNNN
+        cpu.set_platform(Platform::XoChip);
+        cpu.memory[0xFFFA..0x10000].copy_from_slice(&[0x30, 0x00, 0xF0, 0x00, 0x03, 0x00]);
+        cpu.pc = 0xFFFA;
+        assert_eq!(cpu.step(), out_of_bounds(0xFFFA, 0xFFFC));
+        cpu.memory[0xFFFC] = 0x60;
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.pc, 0xFFFE);
+    }
+
+    #[test]
+    fn test_unsupported_opcode() {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&[0x00, 0xFF]);
+
+        assert_eq!(
+            cpu.step(),
+            Err(CpuError::UnsupportedOpcode {
+                pc: 0x200,
+                opcode: 0x00FF,
+                platform: Platform::Legacy
+            })
+        );
+        cpu.process_opcode();
+        assert_eq!(
+            cpu.fault(),
+            Some("opcode 00FF at 0x200 isn't supported on legacy".to_string())
+        );
+    }
+
+    #[test]
+    fn test_platform_layout() {
+        let mut cpu = Cpu::new();
+        assert!(!cpu.select_platform("chip-9"));
+        assert!(cpu.select_platform("chip-8x"));
+        // 0x300: V0 = 1
+        cpu.load_rom(&[0x60, 0x01]);
+        assert_eq!((cpu.pc, cpu.rom_range()), (0x300, (0x300, 0x302)));
+        cpu.process_opcode();
+        assert_eq!(cpu.v[0], 1);
+
+        cpu.set_platform(Platform::Chip10);
+        assert_eq!((cpu.display_width(), cpu.display_height()), (128, 64));
+        assert_eq!(cpu.screen().len(), 128 * 64);
+        cpu.set_platform(Platform::MegaChip);
+        assert_eq!(cpu.memory.len(), 0x1000000);
+        assert_eq!(cpu.quirks(), Platform::MegaChip.descriptor().quirks);
+    }
+
+    #[test]
+    fn test_legacy_platform() {
+        let cpu = Cpu::new();
+        let legacy = Platform::Legacy.descriptor();
+        assert_eq!(cpu.platform(), Platform::Legacy);
+        assert_eq!(cpu.quirks(), legacy.quirks);
+        assert_eq!(cpu.font(), &BuiltinFont::Octo.font().small_only());
+
+        let mut selected = Cpu::new();
+        selected.set_platform(Platform::Legacy);
+        assert_eq!(
+            (selected.quirks(), selected.font()),
+            (cpu.quirks(), cpu.font())
+        );
+    }
+
+    #[test]
+    fn test_schip_display() {
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::Schip11);
+        // 0x200: high resolution, draw a 16x16 sprite from 0x300 at
+        // (112, 0), scroll down 2, scroll left, low resolution, exit
+        cpu.load_rom(&[
+            0x00, 0xFF, 0xA3, 0x00, 0x60, 0x70, 0xD0, 0x10, 0x00, 0xC2, 0x00, 0xFC, 0x00, 0xFE,
+            0x00, 0xFD,
+        ]);
+        cpu.memory[0x300..0x320].copy_from_slice(&[0xFF; 32]);
+        cpu.run(4);
+        assert_eq!(cpu.fault(), None);
+        assert_eq!((cpu.display_width(), cpu.display_height()), (128, 64));
+        assert_eq!(&cpu.screen()[112..128], &[1; 16]);
+        assert_eq!(&cpu.screen()[15 * 128 + 112..16 * 128], &[1; 16]);
+
+        cpu.run(2);
+        assert_eq!(&cpu.screen()[..2 * 128], &[0; 2 * 128][..]);
+        let row = &cpu.screen()[2 * 128..3 * 128];
+        assert_eq!((row[107], &row[108..124], row[124]), (0, &[1; 16][..], 0));
+
+        // 00FD stays put
+        cpu.run(3);
+        assert_eq!((cpu.display_width(), cpu.display_height()), (64, 32));
+        assert_eq!(cpu.pc, 0x20E);
+
+        // a reset goes back to low resolution as well
+        cpu.execute(Instruction::HighRes).unwrap();
+        cpu.reset();
+        assert_eq!(cpu.display_width(), 64);
+    }
+
+    #[test]
+    fn test_hires_detection() {
+        let mut cpu = Cpu::new();
+        let mut rom = vec![0; 0xC4];
+        // 0x200: jump 0x260, 0x2C0: clear the display, draw at (0, 63)
+        rom[..2].copy_from_slice(&[0x12, 0x60]);
+        rom[0xC0..].copy_from_slice(&[0x02, 0x30, 0xD0, 0x11]);
+        cpu.load_rom(&rom);
+        assert_eq!(cpu.platform(), Platform::HiRes);
+        assert_eq!((cpu.display_width(), cpu.display_height()), (64, 64));
+        assert_eq!(cpu.pc, 0x2C0);
+
+        cpu.v[1] = 63;
+        cpu.i = 0x200;
+        cpu.process_opcode();
+        cpu.process_opcode();
+        assert_eq!(cpu.fault(), None);
+        assert_eq!(
+            &cpu.screen()[63 * 64..63 * 64 + 8],
+            &[0, 0, 0, 1, 0, 0, 1, 0]
+        );
+
+        // a plain program goes back to the platform that was chosen
+        cpu.load_rom(&[0x00, 0xE0]);
+        assert_eq!(cpu.platform(), Platform::Legacy);
+        assert_eq!((cpu.display_width(), cpu.display_height()), (64, 32));
+        assert_eq!(cpu.pc, 0x200);
+        cpu.set_platform(Platform::Chip8);
+        cpu.load_rom(&rom);
+        cpu.load_rom(&[0x00, 0xE0]);
+        assert_eq!(cpu.platform(), Platform::Chip8);
+
+        // 0230 is a machine code call anywhere else
+        cpu.set_platform(Platform::Chip48);
+        cpu.load_rom(&[0x02, 0x30]);
+        assert_eq!(cpu.platform(), Platform::Chip48);
+        assert!(cpu.step().is_err());
+    }
+
+    #[test]
+    fn test_chip8x_colour() {
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::Chip8X);
+        // 0x300: next background, V0 = 0x10, V2 = green, colour two zones,
+        // draw the 0 glyph at (0, 0)
+        cpu.load_rom(&[0x02, 0xA0, 0x60, 0x10, 0x62, 0x04, 0xB0, 0x20, 0xD3, 0x35]);
+        for _ in 0..5 {
+            cpu.process_opcode();
+        }
+        assert_eq!(cpu.fault(), None);
+
+        cpu.update_framebuffer();
+        let pixel = |x: usize, y: usize| &cpu.framebuffer[(y * 64 + x) * 4..][..4];
+        // green in the zones, black background, red below
+        assert_eq!(pixel(0, 0), &[0, 0xFF, 0, 0xFF]);
+        assert_eq!(pixel(4, 0), &[0, 0, 0, 0xFF]);
+        assert_eq!(pixel(0, 4), &[0xFF, 0, 0, 0xFF]);
+        assert_eq!(cpu.framebuffer.len(), 64 * 32 * 4);
+    }
+
+    #[test]
+    fn test_chip8x_keypad_and_ports() {
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::Chip8X);
+        // 0x300: V4 = 0x35, V5 = 0x46, add colours, V6 = 2, skip if key 2
+        // of the second pad is down, clear, tone V4, read the port into V7
+        cpu.load_rom(&[
+            0x64, 0x35, 0x65, 0x46, 0x54, 0x51, 0x66, 0x02, 0xE6, 0xF2, 0x00, 0xE0, 0xF4, 0xF8,
+            0xF7, 0xFB,
+        ]);
+        cpu.second_key_down(2);
+        for _ in 0..6 {
+            cpu.process_opcode();
+        }
+        assert_eq!(cpu.v[4], 0x73);
+        assert_eq!((cpu.pc, cpu.tone()), (0x30E, 0x73));
+
+        cpu.process_opcode();
+        assert_eq!(cpu.pc, 0x30E);
+        cpu.set_port_input(0x42);
+        cpu.process_opcode();
+        assert_eq!((cpu.pc, cpu.v[7]), (0x310, 0x42));
+
+        // only the low digit picks the key
+        cpu.pc = 0x308;
+        cpu.v[6] = 0x12;
+        cpu.process_opcode();
+        assert_eq!((cpu.fault(), cpu.pc), (None, 0x30C));
+    }
+
+    #[test]
+    fn test_xochip_memory() {
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::XoChip);
+        // 0x200: I = 0x0300, V0 = 1, V1 = 2, V2 = 3, store V0 to V2, load
+        // V2 down to V0, skip the next I = 0x0400 if V0 == 3, V3 = 5
+        cpu.load_rom(&[
+            0xF0, 0x00, 0x03, 0x00, 0x60, 0x01, 0x61, 0x02, 0x62, 0x03, 0x50, 0x22, 0x52, 0x03,
+            0x30, 0x03, 0xF0, 0x00, 0x04, 0x00, 0x63, 0x05,
+        ]);
+        assert_eq!(cpu.memory.len(), 0x10000);
+        for _ in 0..8 {
+            cpu.process_opcode();
+        }
+        assert_eq!(cpu.fault(), None);
+        assert_eq!(&cpu.memory[0x300..0x303], &[1, 2, 3]);
+        assert_eq!((cpu.i, &cpu.v[..4]), (0x300, &[3, 2, 1, 5][..]));
+        assert_eq!(cpu.pc, 0x216);
+    }
+
+    #[test]
+    fn test_xochip_planes() {
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::XoChip);
+        // 0x200: both planes, I = 0x300, V1 = 1, draw a row on each at
+        // (0, 1), second plane, scroll it up 1, clear it
+        cpu.load_rom(&[
+            0xF3, 0x01, 0xA3, 0x00, 0x61, 0x01, 0xD0, 0x11, 0xF2, 0x01, 0x00, 0xD1, 0x00, 0xE0,
+        ]);
+        cpu.memory[0x300..0x302].copy_from_slice(&[0x80, 0xC0]);
+        for _ in 0..4 {
+            cpu.process_opcode();
+        }
+        assert_eq!(cpu.fault(), None);
+        assert_eq!(&cpu.screen()[64..67], &[3, 2, 0]);
+
+        cpu.process_opcode();
+        cpu.process_opcode();
+        assert_eq!(
+            (&cpu.screen()[..2], &cpu.screen()[64..66]),
+            (&[2, 2][..], &[1, 0][..])
+        );
+        cpu.update_framebuffer();
+        assert_eq!(&cpu.framebuffer[..4], &[0xFF, 0x99, 0x00, 0xFF]);
+
+        // the selected planes are part of the state
+        let state = cpu.save_state();
+        cpu.execute(Instruction::Planes(1)).unwrap();
+        cpu.load_state(&state).unwrap();
+        cpu.process_opcode();
+        assert_eq!((cpu.screen()[0], cpu.screen()[64]), (0, 1));
+    }
+
+    #[test]
+    fn test_megachip_sprites() {
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::MegaChip);
+        // 0x200: MegaChip mode, I = 0x010000, load a colour, 2x1 sprites,
+        // collide with colour 1, I = 0x220, draw twice, leave the mode
+        let mut rom = vec![
+            0x00, 0x11, 0x01, 0x01, 0x00, 0x00, 0x02, 0x01, 0x03, 0x02, 0x04, 0x01, 0x09, 0x01,
+            0xA2, 0x20, 0xD0, 0x10, 0xD0, 0x10, 0x00, 0x10,
+        ];
+        rom.resize(0x22, 0);
+        rom[0x20] = 1;
+        cpu.load_rom(&rom);
+        cpu.memory[0x10000..0x10004].copy_from_slice(&[0xFF, 0x12, 0x34, 0x56]);
+
+        for _ in 0..8 {
+            cpu.process_opcode();
+        }
+        assert_eq!(cpu.fault(), None);
+        assert_eq!((cpu.display_width(), cpu.display_height()), (256, 192));
+        assert_eq!((&cpu.screen()[..2], cpu.v[0xF]), (&[1, 0][..], 0));
+        cpu.update_framebuffer();
+        assert_eq!(
+            &cpu.framebuffer[..8],
+            &[0x12, 0x34, 0x56, 0xFF, 0, 0, 0, 0xFF]
+        );
+
+        cpu.process_opcode();
+        assert_eq!(cpu.v[0xF], 1);
+        cpu.process_opcode();
+        assert_eq!((cpu.display_width(), cpu.display_height()), (64, 32));
+    }
+
+    #[test]
+    fn test_megachip_sample() {
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::MegaChip);
+        // 0x200: I = 0x204, play once, then 8000 Hz and 2 samples
+        cpu.load_rom(&[
+            0xA2, 0x04, 0x06, 0x01, 0x1F, 0x40, 0x00, 0x00, 0x02, 0x00, 0xFF, 0x80,
+        ]);
+        cpu.process_opcode();
+        cpu.process_opcode();
+
+        let mut out = [1.0; 4];
+        cpu.fill_audio(&mut out, 8000);
+        assert_eq!(out, [127.0 / 128.0, 0.0, 0.0, 0.0]);
+        cpu.fill_audio(&mut out, 8000);
+        assert_eq!(out, [0.0; 4]);
+        assert!(cpu.sample.is_none());
+    }
+
+    #[test]
+    fn test_eti660_layout() {
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::Eti660);
+        // 0x600: V0 = 1, point I at its digit
+        assert!(cpu.load_rom(&[0x60, 0x01, 0xF0, 0x29]));
+        assert_eq!(cpu.pc, 0x600);
+        assert_eq!(cpu.screen().len(), 64 * 48);
+        cpu.process_opcode();
+        cpu.process_opcode();
+        assert_eq!(cpu.i, 0x505);
+        assert_eq!(cpu.memory[0x505..0x50A], [0x20; 5]);
+        assert_eq!(cpu.memory[..5], [0; 5]);
+
+        // a program that would fit from 0x200 doesn't from 0x600, and is
+        // left unloaded
+        assert_eq!(cpu.rom_capacity(), 0xA00);
+        assert!(!cpu.load_rom(&[0; 0xA01]));
+        assert_eq!(cpu.i, 0x505);
+    }
+
+    #[test]
+    fn test_fonts() {
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::Schip11);
+        // 0x200: V0 = 2, point I at its large digit
+        cpu.load_rom(&[0x60, 0x02, 0xF0, 0x30]);
+        cpu.process_opcode();
+        cpu.process_opcode();
+        assert_eq!(cpu.i, 100);
+        assert_eq!(cpu.memory[100], 0x3E);
+
+        assert!(!cpu.select_font("fish"));
+        assert!(cpu.select_font("vip"));
+        assert_eq!(cpu.memory[5], 0x60);
+        assert!(!cpu.load_font(&[0xFF; 10]));
+        assert!(cpu.load_font(&[0xFF; 80]));
+        cpu.reset();
+        assert_eq!(cpu.memory[..80], [0xFF; 80]);
+
+        // the platform brings its own font again
+        cpu.set_platform(Platform::Chip8);
+        assert_eq!(cpu.font(), &BuiltinFont::Vip.font());
+        cpu.load_rom(&[0xF0, 0x30]);
+        assert!(cpu.step().is_err());
+    }
+
+    #[test]
+    fn test_vip_quirks() {
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::Chip8);
+        // V1 = 0x81, V0 = V1 >> 1, VF = 1, V0 |= V1, I = 0x300, store V0..V1,
+        // draw at (0x3C, 0)
+        cpu.load_rom(&[
+            0x61, 0x81, 0x80, 0x16, 0x6F, 0x01, 0x80, 0x11, 0xA3, 0x00, 0xF1, 0x55, 0x62, 0x3C,
+            0xD2, 0x31,
+        ]);
+        cpu.process_opcode();
+        cpu.process_opcode();
+        assert_eq!((cpu.v[0], cpu.v[0xF]), (0x40, 1));
+        cpu.process_opcode();
+        cpu.process_opcode();
+        assert_eq!((cpu.v[0], cpu.v[0xF]), (0xC1, 0));
+        cpu.process_opcode();
+        cpu.process_opcode();
+        assert_eq!(cpu.i, 0x302);
+
+        // the sprite is cut off at the right edge
+        cpu.i = 0x300;
+        cpu.process_opcode();
+        cpu.process_opcode();
+        assert_eq!(&cpu.screen()[0x3C..0x40], &[1, 1, 0, 0]);
+        assert_eq!(cpu.screen()[3], 0);
+    }
+
+    #[test]
+    fn test_schip_jump_quirk() {
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::Schip11);
+        // 0x200: B310, jumping to 0x310 + V3
+        cpu.load_rom(&[0xB3, 0x10]);
+        cpu.v[0] = 1;
+        cpu.v[3] = 4;
+        cpu.process_opcode();
+        assert_eq!(cpu.pc, 0x314);
+    }
+
+    #[test]
+    fn test_rpl_flags() {
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::Schip11);
+        // 0x200: save V0 to V2, load V0 to V3, save V0 to V8
+        let rom = [0xF2, 0x75, 0xF3, 0x85, 0xF8, 0x75];
+        cpu.load_rom(&rom);
+        cpu.v[..4].copy_from_slice(&[1, 2, 3, 4]);
+        cpu.process_opcode();
+        cpu.v = [0xFF; 16];
+        cpu.process_opcode();
+        assert_eq!(cpu.v[..5], [1, 2, 3, 0, 0xFF]);
+        // the HP-48 only had 8
+        assert!(matches!(
+            cpu.step(),
+            Err(CpuError::UnsupportedOpcode { pc: 0x204, .. })
+        ));
+
+        // the flags outlive the program, but belong to it
+        cpu.load_rom(&rom[2..]);
+        cpu.process_opcode();
+        assert_eq!(cpu.v[..3], [0, 0, 0]);
+        cpu.load_rom(&rom);
+        cpu.pc = 0x202;
+        cpu.process_opcode();
+        assert_eq!(cpu.v[..3], [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_fault_halts_cpu() {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&[0x00, 0xEE]);
+
+        cpu.process_opcode();
+        cpu.process_opcode();
+        assert_eq!(cpu.fault(), Some("stack underflow at 0x200".to_string()));
+        assert_eq!(cpu.cycles(), 0);
+
+        cpu.reset();
+        assert_eq!(cpu.fault(), None);
+    }
+
+    #[test]
+    fn test_profiling() {
+        let mut cpu = Cpu::new();
+        // 0x200: call 0x204, jump 0x200, 0x204: return
+        cpu.load_rom(&[0x22, 0x04, 0x12, 0x00, 0x00, 0xEE]);
+        cpu.enable_profiling();
+        cpu.run(9);
+
+        let profiler = cpu.profiler().unwrap();
+        assert_eq!(profiler.executions(0x200), 3);
+        assert_eq!(profiler.hottest_routines(1)[0].calls, 3);
+        assert_eq!(profiler.hottest_loops(1)[0].iterations, 3);
+    }
+
+    #[test]
+    fn test_coverage() {
+        let mut cpu = Cpu::new();
+        // 0x200: I = 0x208, V0 = [I], jump 0x204, 0x206: never run
+        cpu.load_rom(&[0xA2, 0x08, 0xF0, 0x65, 0x12, 0x04, 0x00, 0xE0, 0x55]);
+        cpu.enable_coverage();
+        cpu.run(4);
+
+        let coverage = cpu.coverage().unwrap();
+        assert_eq!(
+            coverage.to_text(0x200, 0x209),
+            "200 205 code\n206 207 none\n208 208 data\n"
+        );
+
+        // FX65 moves I past the data on the VIP, which is still where I
+        // pointed while it loaded
+        cpu.set_platform(Platform::Chip8);
+        cpu.load_rom(&[0xA2, 0x08, 0xF0, 0x65, 0x12, 0x04, 0x00, 0xE0, 0x55]);
+        cpu.run(4);
+        assert_eq!(cpu.i, 0x209);
+        assert_eq!(
+            cpu.coverage().unwrap().to_text(0x200, 0x209),
+            "200 205 code\n206 207 none\n208 208 data\n"
+        );
+
+        // 0x200: I = 0x204, draw a 16x16 sprite
+        cpu.set_platform(Platform::Schip11);
+        let mut rom = vec![0xA2, 0x04, 0xD0, 0x00];
+        rom.resize(0x24, 0xFF);
+        cpu.load_rom(&rom);
+        cpu.run(2);
+        assert_eq!(
+            cpu.coverage().unwrap().to_text(0x200, 0x224),
+            "200 203 code\n204 223 data\n"
+        );
+
+        // 0x200: I = 0x204, load a palette colour
+        cpu.set_platform(Platform::MegaChip);
+        cpu.load_rom(&[0xA2, 0x04, 0x02, 0x01, 0xFF, 0x12, 0x34, 0x56]);
+        cpu.run(2);
+        assert_eq!(
+            cpu.coverage().unwrap().to_text(0x200, 0x208),
+            "200 203 code\n204 207 data\n"
+        );
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let mut cpu = Cpu::new();
+        // V0 = 5, call 0x206, 0x206: [I] = V0
+        cpu.load_rom(&[0x60, 0x05, 0x22, 0x06, 0x00, 0x00, 0xF0, 0x55]);
+        cpu.run(2);
+        cpu.key_down(3);
+        let state = cpu.save_state();
+
+        cpu.run(1);
+        cpu.load_rom(&[]);
+        assert_eq!(cpu.load_state(&state), Ok(()));
+        assert_eq!(cpu.v[0], 5);
+        assert_eq!(cpu.pc, 0x206);
+        assert_eq!((cpu.sp, cpu.stack[0]), (1, 0x204));
+        assert!(cpu.keypad.is_key_down(3));
+        assert_eq!(cpu.cycles(), 2);
+        assert_eq!(cpu.rom_range(), (0x200, 0x208));
+        assert_eq!(cpu.memory[0x206], 0xF0);
+
+        assert_eq!(cpu.load_state(b"nope"), Err(StateError::NotASnapshot));
+        assert_eq!(
+            cpu.load_state(&state[..100]),
+            Err(StateError::BadLength(100))
+        );
+    }
+
+    #[test]
+    fn test_save_and_load_chip8x_state() {
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::Chip8X);
+        // 0x300: V0 = 0x10, V2 = green, colour a zone, V3 = 0x73, tone
+        cpu.load_rom(&[0x60, 0x10, 0x62, 0x04, 0xB0, 0x20, 0x63, 0x73, 0xF3, 0xF8]);
+        cpu.run(5);
+        cpu.display.cycle_background();
+        cpu.second_key_down(5);
+        cpu.set_port_input(0x42);
+        let state = cpu.save_state();
+
+        cpu.load_rom(&[]);
+        assert_eq!(cpu.load_state(&state), Ok(()));
+        let colours = cpu.display.colours.as_ref().unwrap();
+        assert_eq!((colours.background, colours.foreground[0]), (1, 4));
+        assert!(cpu.keypad.is_second_key_down(5));
+        assert_eq!((cpu.tone(), cpu.port_input), (0x73, Some(0x42)));
+    }
+
+    #[test]
+    fn test_save_and_load_megachip_state() {
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::MegaChip);
+        // 0x200: MegaChip mode, I = 0x010000, load a colour, 2x1 sprites,
+        // collide with colour 1
+        cpu.load_rom(&[
+            0x00, 0x11, 0x01, 0x01, 0x00, 0x00, 0x02, 0x01, 0x03, 0x02, 0x04, 0x01, 0x09, 0x01,
+        ]);
+        cpu.memory[0x10000..0x10004].copy_from_slice(&[0xFF, 0x12, 0x34, 0x56]);
+        cpu.run(6);
+        cpu.sample = Some(Sample::parse(&[0x1F, 0x40, 0, 0, 2, 0, 0xFF, 0x80], true));
+        cpu.fill_audio(&mut [0.0; 3], 8000);
+        let state = cpu.save_state();
+        let mega = cpu.display.mega.clone();
+        let sample = cpu.sample.clone();
+
+        // back to the low resolution screen
+        cpu.load_rom(&[]);
+        assert_eq!(cpu.load_state(&state), Ok(()));
+        assert_eq!((cpu.display_width(), cpu.display_height()), (256, 192));
+        assert_eq!((cpu.i_page, cpu.index()), (1, 0x10000));
+        assert_eq!(cpu.display.mega, mega);
+        assert_eq!(cpu.sample, sample);
+        assert_eq!(cpu.save_state(), state);
+    }
+
+    #[test]
+    fn test_seeded_random() {
+        // VA = random & 0xFF, jump 0x200
+        let rom = [0xCA, 0xFF, 0x12, 0x00];
+        let mut cpu = Cpu::new();
+        cpu.seed(42);
+        cpu.load_rom(&rom);
+        let numbers: Vec<u8> = (0..8)
+            .map(|_| {
+                cpu.run(2);
+                cpu.v[0xA]
+            })
+            .collect();
+
+        let mut clone = cpu.clone();
+        cpu.load_rom(&rom);
+        for number in numbers {
+            cpu.run(2);
+            assert_eq!(cpu.v[0xA], number);
+        }
+        clone.run(2);
+        cpu.run(2);
+        assert_eq!(clone.v[0xA], cpu.v[0xA]);
+    }
 }
@@ -0,0 +1,209 @@
+use crate::cpu::{Cpu, CpuError, INSTRUCTIONS_PER_FRAME};
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// Why the debugger handed control back.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stop {
+    // a single step completed
+    Step,
+    // about to execute the instruction at a breakpoint
+    Breakpoint(u16),
+    // the last instruction changed a watched byte
+    Watchpoint { address: u16, old: u8, new: u8 },
+    Fault(CpuError),
+    // the instruction limit given to `run` was reached
+    Limit,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Stop::Step => write!(f, "stepped"),
+            Stop::Breakpoint(address) => write!(f, "breakpoint at {:#05X}", address),
+            Stop::Watchpoint { address, old, new } => write!(
+                f,
+                "watchpoint at {:#05X}: {:#04X} -> {:#04X}",
+                address, old, new
+            ),
+            Stop::Fault(error) => write!(f, "fault: {}", error),
+            Stop::Limit => write!(f, "instruction limit reached"),
+        }
+    }
+}
+
+/// Breakpoints, watchpoints and stepping shared by the debugger front ends.
+///
+/// The delay timer is decremented every `INSTRUCTIONS_PER_FRAME`
+/// instructions, so programs waiting on it make progress while being
+/// stepped through.
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    watchpoints: BTreeSet<u16>,
+    // instructions executed since the timers were last decremented
+    frame_instructions: u32,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn has_breakpoint(&self, address: u16) -> bool {
+        self.breakpoints.contains(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Stops execution whenever the byte at `address` changes.
+    pub fn add_watchpoint(&mut self, address: u16) {
+        self.watchpoints.insert(address);
+    }
+
+    pub fn remove_watchpoint(&mut self, address: u16) -> bool {
+        self.watchpoints.remove(&address)
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.watchpoints.iter().copied()
+    }
+
+    /// Executes one instruction, ignoring breakpoints.
+    pub fn step(&mut self, cpu: &mut Cpu) -> Stop {
+        self.step_frames(cpu, |_| ())
+    }
+
+    /// `step`, calling `end_frame` after the timers are decremented at the
+    /// end of a frame, e.g. to rewrite frozen memory.
+    pub fn step_frames(&mut self, cpu: &mut Cpu, mut end_frame: impl FnMut(&mut Cpu)) -> Stop {
+        let watched: Vec<(u16, u8)> = self
+            .watchpoints
+            .iter()
+            .filter(|&&address| (address as usize) < cpu.memory.len())
+            .map(|&address| (address, cpu.memory[address as usize]))
+            .collect();
+
+        if let Err(error) = cpu.step_cached() {
+            return Stop::Fault(error);
+        }
+        self.frame_instructions += 1;
+        let frame_ended = self.frame_instructions == INSTRUCTIONS_PER_FRAME;
+        if frame_ended {
+            self.frame_instructions = 0;
+            cpu.decrement_timers();
+        }
+
+        // what the program wrote, before `end_frame` changes anything
+        let stop = watched
+            .into_iter()
+            .find(|&(address, old)| cpu.memory[address as usize] != old)
+            .map_or(Stop::Step, |(address, old)| Stop::Watchpoint {
+                address,
+                old,
+                new: cpu.memory[address as usize],
+            });
+        if frame_ended {
+            end_frame(cpu);
+        }
+        stop
+    }
+
+    /// Executes up to `limit` instructions, stopping before any instruction
+    /// at a breakpoint. The first instruction always runs, so execution can
+    /// be resumed from a breakpoint.
+    pub fn run(&mut self, cpu: &mut Cpu, limit: u64) -> Stop {
+        self.run_frames(cpu, limit, |_| ())
+    }
+
+    /// `run`, calling `end_frame` at the end of every frame like
+    /// `step_frames`.
+    pub fn run_frames(
+        &mut self,
+        cpu: &mut Cpu,
+        limit: u64,
+        mut end_frame: impl FnMut(&mut Cpu),
+    ) -> Stop {
+        for executed in 0..limit {
+            if executed > 0 && self.has_breakpoint(cpu.pc) {
+                return Stop::Breakpoint(cpu.pc);
+            }
+            match self.step_frames(cpu, &mut end_frame) {
+                Stop::Step => (),
+                stop => return stop,
+            }
+        }
+        Stop::Limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Debugger, Stop};
+    use crate::cpu::Cpu;
+
+    // 0x200: V0 = 1
+    // 0x202: V0 += 1
+    // 0x204: I = 0x300
+    // 0x206: [I] = V0
+    // 0x208: jump 0x202
+    const ROM: [u8; 10] = [0x60, 0x01, 0x70, 0x01, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x02];
+
+    #[test]
+    fn test_breakpoint() {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&ROM);
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(0x202);
+
+        assert_eq!(debugger.run(&mut cpu, 100), Stop::Breakpoint(0x202));
+        assert_eq!(cpu.v[0], 1);
+        // resuming runs the instruction at the breakpoint
+        assert_eq!(debugger.run(&mut cpu, 100), Stop::Breakpoint(0x202));
+        assert_eq!(cpu.v[0], 2);
+
+        debugger.remove_breakpoint(0x202);
+        assert_eq!(debugger.run(&mut cpu, 3), Stop::Limit);
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&ROM);
+        let mut debugger = Debugger::new();
+        debugger.add_watchpoint(0x300);
+
+        assert_eq!(
+            debugger.run(&mut cpu, 100),
+            Stop::Watchpoint {
+                address: 0x300,
+                old: 0,
+                new: 2
+            }
+        );
+        assert_eq!(cpu.pc, 0x208);
+    }
+
+    #[test]
+    fn test_timers_tick_while_stepping() {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&ROM);
+        cpu.dt = 5;
+        let mut debugger = Debugger::new();
+
+        for _ in 0..20 {
+            assert_eq!(debugger.step(&mut cpu), Stop::Step);
+        }
+        assert_eq!(cpu.dt, 3);
+    }
+}
@@ -0,0 +1,714 @@
+use crate::cpu::{Cpu, CpuError, INSTRUCTIONS_PER_FRAME};
+use crate::display::Colours;
+use crate::megachip::{MegaDisplay, Sample};
+use crate::platform::Platform;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+
+// instructions shown before the one that diverged
+const HISTORY: usize = 16;
+// differing memory bytes listed before the rest are only counted
+const MEMORY_DIFFERENCES: usize = 8;
+
+/// Machine state compared between engines after every instruction. Memory
+/// is compared apart, only where the engines wrote to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub pc: u16,
+    pub i: u16,
+    pub i_page: u8,
+    pub v: [u8; 16],
+    pub stack: [u16; 16],
+    pub sp: u8,
+    pub dt: u8,
+    pub width: usize,
+    pub height: usize,
+    pub screen: Vec<u8>,
+    pub colours: Option<Colours>,
+    pub mega: Option<MegaDisplay>,
+    pub sample: Option<Sample>,
+}
+
+impl Snapshot {
+    pub fn of(cpu: &Cpu) -> Snapshot {
+        Snapshot {
+            pc: cpu.pc,
+            i: cpu.i,
+            i_page: cpu.i_page,
+            v: cpu.v,
+            stack: cpu.stack,
+            sp: cpu.sp,
+            dt: cpu.dt,
+            width: cpu.display.width,
+            height: cpu.display.height,
+            screen: cpu.screen().to_vec(),
+            colours: cpu.display.colours.clone(),
+            mega: cpu.display.mega.clone(),
+            sample: cpu.sample.clone(),
+        }
+    }
+
+    /// How `other` differs, one line per register, one for the resolution,
+    /// the screen and the colours, and one per MegaChip display setting and
+    /// for the sound.
+    pub fn differences(&self, other: &Snapshot) -> Vec<String> {
+        let mut differences = Vec::new();
+        let mut compare = |name: String, left: String, right: String| {
+            if left != right {
+                differences.push(format!("{}: {} vs {}", name, left, right));
+            }
+        };
+        compare(
+            "PC".to_string(),
+            format!("{:#05X}", self.pc),
+            format!("{:#05X}", other.pc),
+        );
+        compare(
+            "I".to_string(),
+            format!("{:#05X}", self.i),
+            format!("{:#05X}", other.i),
+        );
+        compare(
+            "I page".to_string(),
+            format!("{:#04X}", self.i_page),
+            format!("{:#04X}", other.i_page),
+        );
+        for x in 0..16 {
+            compare(
+                format!("V{:X}", x),
+                format!("{:#04X}", self.v[x]),
+                format!("{:#04X}", other.v[x]),
+            );
+        }
+        compare("SP".to_string(), self.sp.to_string(), other.sp.to_string());
+        for n in 0..16 {
+            compare(
+                format!("stack[{}]", n),
+                format!("{:#05X}", self.stack[n]),
+                format!("{:#05X}", other.stack[n]),
+            );
+        }
+        compare("DT".to_string(), self.dt.to_string(), other.dt.to_string());
+        let mode = |mega: &Option<MegaDisplay>| if mega.is_some() { "on" } else { "off" };
+        compare(
+            "MegaChip mode".to_string(),
+            mode(&self.mega).to_string(),
+            mode(&other.mega).to_string(),
+        );
+        compare(
+            "sample".to_string(),
+            describe_sample(self.sample.as_ref()),
+            describe_sample(other.sample.as_ref()),
+        );
+
+        compare(
+            "resolution".to_string(),
+            format!("{}x{}", self.width, self.height),
+            format!("{}x{}", other.width, other.height),
+        );
+
+        let pixels: Vec<usize> = (0..self.screen.len().min(other.screen.len()))
+            .filter(|pixel| self.screen[*pixel] != other.screen[*pixel])
+            .collect();
+        if self.screen.len() != other.screen.len() {
+            differences.push(format!(
+                "screen size: {} vs {} pixels",
+                self.screen.len(),
+                other.screen.len()
+            ));
+        } else if let Some(first) = pixels.first() {
+            differences.push(format!(
+                "screen: {} pixels differ, the first being pixel {}",
+                pixels.len(),
+                first
+            ));
+        }
+        differences.extend(colour_differences(
+            self.colours.as_ref(),
+            other.colours.as_ref(),
+        ));
+        if let (Some(left), Some(right)) = (&self.mega, &other.mega) {
+            differences.extend(mega_differences(left, right));
+        }
+        differences
+    }
+}
+
+/// How the CHIP-8X colour boards of two displays differ.
+fn colour_differences(left: Option<&Colours>, right: Option<&Colours>) -> Vec<String> {
+    let (left, right) = match (left, right) {
+        (Some(left), Some(right)) => (left, right),
+        (None, None) => return Vec::new(),
+        (left, right) => {
+            let board = |colours: Option<&Colours>| if colours.is_some() { "on" } else { "off" };
+            return vec![format!("colour board: {} vs {}", board(left), board(right))];
+        }
+    };
+    let mut differences = Vec::new();
+    if left.background != right.background {
+        differences.push(format!(
+            "background: {} vs {}",
+            left.background, right.background
+        ));
+    }
+    if left.foreground.len() != right.foreground.len() {
+        differences.push(format!(
+            "colour strips: {} vs {}",
+            left.foreground.len(),
+            right.foreground.len()
+        ));
+    } else {
+        let strips = (0..left.foreground.len())
+            .filter(|n| left.foreground[*n] != right.foreground[*n])
+            .count();
+        if strips > 0 {
+            differences.push(format!("colours: {} strips differ", strips));
+        }
+    }
+    differences
+}
+
+/// How the MegaChip settings and pictures of two displays differ.
+fn mega_differences(left: &MegaDisplay, right: &MegaDisplay) -> Vec<String> {
+    let mut differences = Vec::new();
+    let mut compare = |name: &str, left: String, right: String| {
+        if left != right {
+            differences.push(format!("{}: {} vs {}", name, left, right));
+        }
+    };
+    compare(
+        "sprite size",
+        format!("{}x{}", left.sprite_width, left.sprite_height),
+        format!("{}x{}", right.sprite_width, right.sprite_height),
+    );
+    compare(
+        "blend",
+        format!("{:?}", left.blend),
+        format!("{:?}", right.blend),
+    );
+    compare("alpha", left.alpha.to_string(), right.alpha.to_string());
+    compare(
+        "collision",
+        left.collision.to_string(),
+        right.collision.to_string(),
+    );
+
+    let colours = (0..256)
+        .filter(|n| left.palette[*n] != right.palette[*n])
+        .count();
+    if colours > 0 {
+        differences.push(format!("palette: {} colours differ", colours));
+    }
+    let pixels = (0..left.picture.len().min(right.picture.len()))
+        .filter(|pixel| left.picture[*pixel] != right.picture[*pixel])
+        .count();
+    if left.picture.len() != right.picture.len() {
+        differences.push(format!(
+            "picture size: {} vs {} pixels",
+            left.picture.len(),
+            right.picture.len()
+        ));
+    } else if pixels > 0 {
+        differences.push(format!("picture: {} pixels differ", pixels));
+    }
+    differences
+}
+
+fn describe_sample(sample: Option<&Sample>) -> String {
+    match sample {
+        Some(sample) => format!(
+            "{} of {} samples at {} Hz{}",
+            sample.position,
+            sample.data.len(),
+            sample.rate,
+            if sample.looping { ", looping" } else { "" }
+        ),
+        None => "none".to_string(),
+    }
+}
+
+/// How the memory of two engines differs at `addresses`, a few lines for the
+/// first bytes and one counting the rest.
+pub fn memory_differences(
+    left: &[u8],
+    right: &[u8],
+    addresses: impl IntoIterator<Item = usize>,
+) -> Vec<String> {
+    let mut addresses: Vec<usize> = addresses
+        .into_iter()
+        .filter(|address| left.get(*address) != right.get(*address))
+        .collect();
+    addresses.sort_unstable();
+    addresses.dedup();
+
+    let mut differences = Vec::new();
+    if left.len() != right.len() {
+        differences.push(format!("memory size: {} vs {}", left.len(), right.len()));
+    }
+    let byte = |memory: &[u8], address: usize| match memory.get(address) {
+        Some(byte) => format!("{:#04X}", byte),
+        None => "unmapped".to_string(),
+    };
+    for address in addresses.iter().take(MEMORY_DIFFERENCES) {
+        differences.push(format!(
+            "memory {:#05X}: {} vs {}",
+            address,
+            byte(left, *address),
+            byte(right, *address)
+        ));
+    }
+    if addresses.len() > MEMORY_DIFFERENCES {
+        differences.push(format!(
+            "memory: {} more bytes differ",
+            addresses.len() - MEMORY_DIFFERENCES
+        ));
+    }
+    differences
+}
+
+fn opcode_at(memory: &[u8], pc: u16) -> u16 {
+    let pc = pc as usize;
+    match memory.get(pc..pc + 2) {
+        Some(bytes) => (bytes[0] as u16) << 8 | bytes[1] as u16,
+        None => 0,
+    }
+}
+
+/// An implementation of the CHIP-8 semantics the harness can drive, one
+/// instruction at a time.
+pub trait Engine {
+    fn name(&self) -> &str;
+    /// The platform instructions are decoded for in reports.
+    fn platform(&self) -> Platform;
+    /// Makes CXNN deterministic, like `Cpu::seed`.
+    fn seed(&mut self, seed: u64);
+    fn load_rom(&mut self, rom: &[u8]);
+    fn set_keys(&mut self, keys: u16);
+    fn step(&mut self) -> Result<(), CpuError>;
+    fn decrement_timers(&mut self);
+    fn snapshot(&self) -> Snapshot;
+    fn memory(&self) -> &[u8];
+    /// Addresses written since the last call or `load_rom`, repeats
+    /// allowed.
+    fn take_writes(&mut self) -> Vec<usize>;
+}
+
+/// An engine built on `Cpu`, stepping it with `step`.
+pub struct CpuEngine {
+    pub cpu: Cpu,
+    name: &'static str,
+    step: fn(&mut Cpu) -> Result<(), CpuError>,
+}
+
+impl CpuEngine {
+    pub fn new(name: &'static str, step: fn(&mut Cpu) -> Result<(), CpuError>) -> CpuEngine {
+        let mut cpu = Cpu::new();
+        cpu.enable_write_log();
+        CpuEngine { cpu, name, step }
+    }
+
+    /// `Cpu::step`, decoding every instruction as it runs: the reference
+    /// semantics.
+    pub fn interpreter() -> CpuEngine {
+        CpuEngine::new("interpreter", Cpu::step)
+    }
+
+    /// `Cpu::step_cached`, reusing decoded instructions.
+    pub fn cached() -> CpuEngine {
+        CpuEngine::new("cached", Cpu::step_cached)
+    }
+}
+
+impl Engine for CpuEngine {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn platform(&self) -> Platform {
+        self.cpu.platform()
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.cpu.seed(seed);
+    }
+
+    fn load_rom(&mut self, rom: &[u8]) {
+        self.cpu.load_rom(rom);
+    }
+
+    fn set_keys(&mut self, keys: u16) {
+        self.cpu.set_keys(keys);
+    }
+
+    fn step(&mut self) -> Result<(), CpuError> {
+        (self.step)(&mut self.cpu)
+    }
+
+    fn decrement_timers(&mut self) {
+        self.cpu.decrement_timers();
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot::of(&self.cpu)
+    }
+
+    fn memory(&self) -> &[u8] {
+        &self.cpu.memory
+    }
+
+    fn take_writes(&mut self) -> Vec<usize> {
+        self.cpu.take_writes()
+    }
+}
+
+/// Why a script of inputs couldn't be parsed, `line` counting from 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScriptError {
+    pub line: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl Error for ScriptError {}
+
+/// Keys held over time, as text with one change per line:
+///
+/// ```text
+/// # frame keys
+/// 30 0x0010
+/// 45 0
+/// ```
+///
+/// That is the frame the keys change at and the mask of keys held from then
+/// on, bit `n` standing for key `n`, in hex. No key is held before the first
+/// change. `#` starts a comment.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Script {
+    // in order of frames
+    changes: Vec<(u32, u16)>,
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Script, ScriptError> {
+        let mut changes: Vec<(u32, u16)> = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let error = |reason| ScriptError {
+                line: n + 1,
+                reason,
+            };
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let frame = fields
+                .next()
+                .and_then(|frame| frame.parse().ok())
+                .ok_or_else(|| error("expected a frame number"))?;
+            let keys = fields
+                .next()
+                .and_then(|keys| u16::from_str_radix(keys.trim_start_matches("0x"), 16).ok())
+                .ok_or_else(|| error("expected a hex key mask"))?;
+            if fields.next().is_some() {
+                return Err(error("expected <frame> <keys>"));
+            }
+            if changes.last().is_some_and(|(last, _)| *last >= frame) {
+                return Err(error("frames have to increase"));
+            }
+            changes.push((frame, keys));
+        }
+        Ok(Script { changes })
+    }
+
+    /// Keys held during `frame`.
+    pub fn keys(&self, frame: u32) -> u16 {
+        self.changes
+            .iter()
+            .take_while(|(start, _)| *start <= frame)
+            .last()
+            .map_or(0, |(_, keys)| *keys)
+    }
+}
+
+/// The first instruction after which two engines disagree.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub engines: (String, String),
+    // the left engine's, which `opcode` and the history are decoded for
+    pub platform: Platform,
+    // instructions both engines executed before this one
+    pub instruction: u64,
+    pub frame: u32,
+    pub pc: u16,
+    pub opcode: u16,
+    pub differences: Vec<String>,
+    // the instructions leading up to it, this one last
+    pub history: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} and {} diverged at instruction {} (frame {}): {:04X} {:04X} {}",
+            self.engines.0,
+            self.engines.1,
+            self.instruction,
+            self.frame,
+            self.pc,
+            self.opcode,
+            self.platform.decode(self.opcode)
+        )?;
+        for difference in &self.differences {
+            writeln!(f, "  {}", difference)?;
+        }
+        writeln!(f, "last {} instructions:", self.history.len())?;
+        for line in &self.history {
+            writeln!(f, "  {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Divergence {}
+
+/// Runs `rom` on both engines for `frames` frames of
+/// `INSTRUCTIONS_PER_FRAME` instructions, seeded alike and with the keys of
+/// `script`, comparing their state after every instruction.
+///
+/// Returns the number of instructions run, or the first divergence. A fault
+/// is state like any other: when both engines fault the same way, the run
+/// ends there. Memory is compared in full once the ROM is loaded, then only
+/// where either engine wrote.
+pub fn compare<A: Engine, B: Engine>(
+    left: &mut A,
+    right: &mut B,
+    rom: &[u8],
+    script: &Script,
+    frames: u32,
+    seed: u64,
+) -> Result<u64, Divergence> {
+    left.seed(seed);
+    right.seed(seed);
+    left.load_rom(rom);
+    right.load_rom(rom);
+
+    let mut pc = left.snapshot().pc;
+    let loaded = memory_differences(left.memory(), right.memory(), 0..left.memory().len());
+    if !loaded.is_empty() {
+        return Err(Divergence {
+            engines: (left.name().to_string(), right.name().to_string()),
+            platform: left.platform(),
+            instruction: 0,
+            frame: 0,
+            pc,
+            opcode: opcode_at(left.memory(), pc),
+            differences: loaded,
+            history: Vec::new(),
+        });
+    }
+    let mut history = VecDeque::with_capacity(HISTORY);
+    let mut executed = 0;
+    for frame in 0..frames {
+        let keys = script.keys(frame);
+        left.set_keys(keys);
+        right.set_keys(keys);
+
+        for _ in 0..INSTRUCTIONS_PER_FRAME {
+            let opcode = opcode_at(left.memory(), pc);
+            if history.len() == HISTORY {
+                history.pop_front();
+            }
+            history.push_back(format!(
+                "{:04X} {:04X} {}",
+                pc,
+                opcode,
+                left.platform().decode(opcode)
+            ));
+
+            let results = (left.step(), right.step());
+            let after = (left.snapshot(), right.snapshot());
+            let mut differences = after.0.differences(&after.1);
+            let mut writes = left.take_writes();
+            writes.extend(right.take_writes());
+            differences.extend(memory_differences(left.memory(), right.memory(), writes));
+            if results.0 != results.1 {
+                differences.insert(
+                    0,
+                    format!("result: {} vs {}", describe(results.0), describe(results.1)),
+                );
+            }
+            if !differences.is_empty() {
+                return Err(Divergence {
+                    engines: (left.name().to_string(), right.name().to_string()),
+                    platform: left.platform(),
+                    instruction: executed,
+                    frame,
+                    pc,
+                    opcode,
+                    differences,
+                    history: history.into_iter().collect(),
+                });
+            }
+            if results.0.is_err() {
+                return Ok(executed);
+            }
+            executed += 1;
+            pc = after.0.pc;
+        }
+
+        left.decrement_timers();
+        right.decrement_timers();
+    }
+    Ok(executed)
+}
+
+fn describe(result: Result<(), CpuError>) -> String {
+    match result {
+        Ok(()) => "ok".to_string(),
+        Err(error) => error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare, CpuEngine, Script, ScriptError, Snapshot};
+    use crate::cpu::{Cpu, CpuError};
+    use crate::display::Display;
+
+    // Gets 7XNN wrong, setting VF like 8XY4 does.
+    fn broken_step(cpu: &mut Cpu) -> Result<(), CpuError> {
+        let opcode = cpu.current_opcode();
+        let x = (opcode >> 8 & 0xF) as usize;
+        let carry = cpu.v[x] as u16 + (opcode & 0xFF) > 0xFF;
+        cpu.step()?;
+        if opcode >> 12 == 7 {
+            cpu.v[0xF] = carry as u8;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cached_matches_interpreter() {
+        let script = Script::parse("20 0x10\n40 0\n60 0x40\n90 0").unwrap();
+        for rom in [
+            &include_bytes!("../www/roms/TETRIS")[..],
+            &include_bytes!("../www/roms/BLITZ")[..],
+        ] {
+            let executed = compare(
+                &mut CpuEngine::interpreter(),
+                &mut CpuEngine::cached(),
+                rom,
+                &script,
+                200,
+                1,
+            );
+            assert_eq!(executed, Ok(2000));
+        }
+    }
+
+    #[test]
+    fn test_reports_first_divergence() {
+        // V0 = 0xF0, V0 += 0x08, V0 += 0x08, jump 0x206
+        let rom = [0x60, 0xF0, 0x70, 0x08, 0x70, 0x08, 0x12, 0x06];
+        let divergence = compare(
+            &mut CpuEngine::interpreter(),
+            &mut CpuEngine::new("broken", broken_step),
+            &rom,
+            &Script::default(),
+            10,
+            0,
+        )
+        .unwrap_err();
+
+        assert_eq!((divergence.instruction, divergence.frame), (2, 0));
+        assert_eq!((divergence.pc, divergence.opcode), (0x204, 0x7008));
+        assert_eq!(divergence.differences, ["VF: 0x00 vs 0x01"]);
+        assert_eq!(divergence.history.len(), 3);
+        let report = divergence.to_string();
+        assert!(report.starts_with(
+            "interpreter and broken diverged at instruction 2 (frame 0): 0204 7008 ADD V0, 0x08"
+        ));
+        assert!(report.contains("  0200 60F0 LD V0, 0xF0\n"));
+    }
+
+    #[test]
+    fn test_reports_memory_written_differently() {
+        // Gets FX33 wrong, storing the digits in reverse.
+        fn broken_bcd(cpu: &mut Cpu) -> Result<(), CpuError> {
+            let opcode = cpu.current_opcode();
+            cpu.step()?;
+            if opcode & 0xF0FF == 0xF033 {
+                let i = cpu.i as usize;
+                let (hundreds, ones) = (cpu.memory[i], cpu.memory[i + 2]);
+                cpu.write_memory(i, ones);
+                cpu.write_memory(i + 2, hundreds);
+            }
+            Ok(())
+        }
+
+        // V0 = 123, I = 0x300, BCD V0, jump 0x206
+        let rom = [0x60, 0x7B, 0xA3, 0x00, 0xF0, 0x33, 0x12, 0x06];
+        let divergence = compare(
+            &mut CpuEngine::interpreter(),
+            &mut CpuEngine::new("broken", broken_bcd),
+            &rom,
+            &Script::default(),
+            10,
+            0,
+        )
+        .unwrap_err();
+
+        assert_eq!(divergence.pc, 0x204);
+        assert_eq!(
+            divergence.differences,
+            ["memory 0x300: 0x01 vs 0x03", "memory 0x302: 0x03 vs 0x01"]
+        );
+    }
+
+    #[test]
+    fn test_reports_resolution_and_colours() {
+        let (mut left, mut right) = (Cpu::new(), Cpu::new());
+        right.display = Display::new(128, 64);
+        let differences = Snapshot::of(&left).differences(&Snapshot::of(&right));
+        assert_eq!(
+            differences,
+            [
+                "resolution: 64x32 vs 128x64",
+                "screen size: 2048 vs 8192 pixels"
+            ]
+        );
+
+        left.display = Display::with_colours(64, 32);
+        right.display = Display::with_colours(64, 32);
+        right.display.cycle_background();
+        right.display.colour_area(0, 0, 8, 1, 4);
+        let differences = Snapshot::of(&left).differences(&Snapshot::of(&right));
+        assert_eq!(
+            differences,
+            ["background: 0 vs 1", "colours: 1 strips differ"]
+        );
+    }
+
+    #[test]
+    fn test_script() {
+        let script = Script::parse("# frame keys\n10 0x0010\n15 3\n").unwrap();
+        assert_eq!(script.keys(0), 0);
+        assert_eq!(script.keys(12), 0x10);
+        assert_eq!(script.keys(100), 3);
+        assert_eq!(
+            Script::parse("10 1\n5 0"),
+            Err(ScriptError {
+                line: 2,
+                reason: "frames have to increase"
+            })
+        );
+    }
+}
@@ -1,74 +1,297 @@
-const WIDTH: usize = 64;
-const HEIGHT: usize = 32;
+use crate::megachip::{self, MegaDisplay};
 
+// how monochrome displays look, a green phosphor
+const MONOCHROME_ON: [u8; 3] = [0x33, 0xFF, 0x66];
+const MONOCHROME_OFF: [u8; 3] = [0, 0, 0];
+// XO-CHIP's second plane and both planes together
+const SECOND_PLANE: [u8; 3] = [0xFF, 0x99, 0x00];
+const BOTH_PLANES: [u8; 3] = [0x99, 0x33, 0x00];
+
+// the VP-590 colour board: backgrounds cycle through blue, black, green and
+// red, foregrounds mix red, blue and green bits
+const BACKGROUNDS: [[u8; 3]; 4] = [[0, 0, 0x80], [0, 0, 0], [0, 0x80, 0], [0x80, 0, 0]];
+const FOREGROUNDS: [[u8; 3]; 8] = [
+    [0, 0, 0],
+    [0xFF, 0, 0],
+    [0, 0, 0xFF],
+    [0xFF, 0, 0xFF],
+    [0, 0xFF, 0],
+    [0xFF, 0xFF, 0],
+    [0, 0xFF, 0xFF],
+    [0xFF, 0xFF, 0xFF],
+];
+// foreground of every strip after a reset
+const DEFAULT_FOREGROUND: u8 = 1;
+
+/// CHIP-8X colour attributes, a layer over the pixels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Colours {
+    /// Index into blue, black, green and red.
+    pub background: u8,
+    /// Foreground of each strip of 8x1 pixels, 0 to 7, row by row.
+    pub foreground: Vec<u8>,
+}
+
+#[derive(Clone)]
 pub struct Display {
-    pub screen: [u8; WIDTH * HEIGHT],
+    pub width: usize,
+    pub height: usize,
+    /// A bit per plane, or palette indices in MegaChip mode.
+    pub screen: Vec<u8>,
+    /// The bitplanes drawing, clearing and scrolling work on, more than the
+    /// first only on XO-CHIP.
+    pub planes: u8,
+    /// Present on displays with a colour board.
+    pub colours: Option<Colours>,
+    /// Present in MegaChip mode, `screen` then holding palette indices.
+    pub mega: Option<MegaDisplay>,
 }
 
 impl Display {
-    pub fn new() -> Display {
+    pub fn new(width: usize, height: usize) -> Display {
         Display {
-            screen: [0; WIDTH * HEIGHT],
+            width,
+            height,
+            screen: vec![0; width * height],
+            planes: 1,
+            colours: None,
+            mega: None,
+        }
+    }
+
+    /// The 256x192 display of MegaChip mode.
+    pub fn mega() -> Display {
+        let mut display = Display::new(megachip::WIDTH, megachip::HEIGHT);
+        display.mega = Some(MegaDisplay::new());
+        display
+    }
+
+    /// A display with the CHIP-8X colour layer.
+    pub fn with_colours(width: usize, height: usize) -> Display {
+        let mut display = Display::new(width, height);
+        display.colours = Some(Colours {
+            background: 0,
+            foreground: Vec::new(),
+        });
+        display.reset_colours();
+        display
+    }
+
+    /// Back to a blue background and red foreground, as after power on.
+    pub fn reset_colours(&mut self) {
+        let strips = self.width.div_ceil(8) * self.height;
+        if let Some(colours) = self.colours.as_mut() {
+            colours.background = 0;
+            colours.foreground = vec![DEFAULT_FOREGROUND; strips];
+        }
+    }
+
+    /// Steps to the next background colour, wrapping after red.
+    pub fn cycle_background(&mut self) {
+        if let Some(colours) = self.colours.as_mut() {
+            colours.background = (colours.background + 1) % BACKGROUNDS.len() as u8;
+        }
+    }
+
+    /// Sets the foreground of the strips covering `width` by `height` pixels
+    /// from (`x`, `y`), wrapping around the edges.
+    pub fn colour_area(&mut self, x: usize, y: usize, width: usize, height: usize, colour: u8) {
+        let strips = self.width.div_ceil(8);
+        let (columns, rows) = (strips.min(width.div_ceil(8)), self.height.min(height));
+        if let Some(colours) = self.colours.as_mut() {
+            for row in y..y + rows {
+                for column in x / 8..x / 8 + columns {
+                    let strip = row % self.height * strips + column % strips;
+                    colours.foreground[strip] = colour % FOREGROUNDS.len() as u8;
+                }
+            }
+        }
+    }
+
+    /// The display as RGBA, 4 bytes per pixel row by row, written to `out`.
+    pub fn write_rgba(&self, out: &mut Vec<u8>) {
+        out.clear();
+        if let Some(mega) = &self.mega {
+            for pixel in &mega.picture {
+                out.extend(
+                    pixel
+                        .iter()
+                        .map(|c| (*c as u32 * mega.alpha as u32 / 255) as u8),
+                );
+                out.push(0xFF);
+            }
+            return;
+        }
+        let strips = self.width.div_ceil(8);
+        for (n, pixel) in self.screen.iter().enumerate() {
+            let colour = match &self.colours {
+                Some(colours) if *pixel == 1 => {
+                    let strip = n / self.width * strips + n % self.width / 8;
+                    FOREGROUNDS[colours.foreground[strip] as usize]
+                }
+                Some(colours) => BACKGROUNDS[colours.background as usize],
+                None => match pixel {
+                    0 => MONOCHROME_OFF,
+                    1 => MONOCHROME_ON,
+                    2 => SECOND_PLANE,
+                    _ => BOTH_PLANES,
+                },
+            };
+            out.extend_from_slice(&colour);
+            out.push(0xFF);
         }
     }
 
     pub fn set_pixel(&mut self, x: usize, y: usize, on: bool) {
-        self.screen[x + y * WIDTH] = on as u8;
+        self.screen[x + y * self.width] = on as u8;
     }
 
-    fn get_pixel(&mut self, x: usize, y: usize) -> bool {
-        self.screen[x + y * WIDTH] == 1
+    // the bits of `screen` the selected planes cover
+    fn plane_mask(&self) -> u8 {
+        if self.mega.is_some() {
+            0xFF
+        } else {
+            self.planes
+        }
     }
 
     pub fn cls(&mut self) {
-        for x in 0..WIDTH {
-            for y in 0..HEIGHT {
-                self.set_pixel(x, y, false);
+        let keep = !self.plane_mask();
+        self.screen.iter_mut().for_each(|pixel| *pixel &= keep);
+        if let Some(mega) = self.mega.as_mut() {
+            mega.picture.iter_mut().for_each(|pixel| *pixel = [0; 3]);
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut text = String::with_capacity((self.width + 1) * self.height);
+        for row in self.screen.chunks(self.width) {
+            text.extend(row.iter().map(|pixel| if *pixel != 0 { '#' } else { ' ' }));
+            text.push('\n');
+        }
+        text
+    }
+
+    /// XORs a sprite onto the screen, returning whether a pixel was turned
+    /// off. Where the sprite starts wraps around, the pixels going past the
+    /// edges wrap as well unless `clip` is set. With several planes
+    /// selected, the sprite holds the rows of each in turn.
+    pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8], clip: bool) -> bool {
+        self.draw_planes(x, y, sprite, 1, clip)
+    }
+
+    /// Like `draw`, for SUPER-CHIP's 16x16 sprites of two bytes a row.
+    pub fn draw_large(&mut self, x: usize, y: usize, sprite: &[u8], clip: bool) -> bool {
+        self.draw_planes(x, y, sprite, 2, clip)
+    }
+
+    fn draw_planes(
+        &mut self,
+        x: usize,
+        y: usize,
+        sprite: &[u8],
+        row_bytes: usize,
+        clip: bool,
+    ) -> bool {
+        let planes = self.planes;
+        let count = planes.count_ones() as usize;
+        if count == 0 {
+            return false;
+        }
+        let mut parts = sprite.chunks((sprite.len() / count).max(1));
+        let mut collision = false;
+        for plane in (0..8).map(|n| 1 << n).filter(|plane| planes & plane != 0) {
+            if let Some(part) = parts.next() {
+                collision |= self.draw_rows(x, y, part, row_bytes, plane, clip);
             }
         }
+        collision
     }
 
-    pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
-        let rows = sprite.len();
+    fn draw_rows(
+        &mut self,
+        x: usize,
+        y: usize,
+        sprite: &[u8],
+        row_bytes: usize,
+        plane: u8,
+        clip: bool,
+    ) -> bool {
+        let (x, y) = (x % self.width, y % self.height);
         let mut collision = false;
 
-        for j in 0..rows {
-            let row = sprite[j];
+        for (j, bytes) in sprite.chunks(row_bytes).enumerate() {
+            let row = bytes.iter().fold(0u16, |row, byte| row << 8 | *byte as u16);
 
-            for i in 0..8 {
-                let new_value = row >> (7 - i) & 0x01;
+            for i in 0..8 * bytes.len() {
+                let new_value = row >> (8 * bytes.len() - 1 - i) & 0x01;
+                if clip && (x + i >= self.width || y + j >= self.height) {
+                    continue;
+                }
                 if new_value == 1 {
-                    let xi = (x + i) % WIDTH;
-                    let yj = (y + j) % HEIGHT;
+                    let xi = (x + i) % self.width;
+                    let yj = (y + j) % self.height;
 
-                    let old_value = self.get_pixel(xi, yj);
-                    if old_value {
+                    let pixel = &mut self.screen[xi + yj * self.width];
+                    if *pixel & plane != 0 {
                         collision = true
                     }
-                    self.set_pixel(xi, yj, (new_value == 1) ^ old_value);
+                    *pixel ^= plane;
                 }
             }
         }
 
         collision
     }
+
+    /// Moves the picture on the selected planes `dx` pixels right and `dy`
+    /// down. What moves past the edges is lost and what comes in is blank.
+    pub fn scroll(&mut self, dx: isize, dy: isize) {
+        let mask = self.plane_mask();
+        let moved = scrolled(&self.screen, self.width, dx, dy);
+        for (pixel, moved) in self.screen.iter_mut().zip(moved) {
+            *pixel = *pixel & !mask | moved & mask;
+        }
+        if let Some(mega) = self.mega.as_mut() {
+            mega.picture = scrolled(&mega.picture, self.width, dx, dy);
+        }
+    }
+
+    /// Draws a MegaChip sprite of palette indices, `sprite_width` by
+    /// `sprite_height`, index 0 being transparent. Returns whether a pixel
+    /// of the collision colour was drawn over. Sprites are cut off at the
+    /// edges.
+    pub fn draw_mega(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
+        let mega = self.mega.as_mut().expect("MegaChip mode");
+        let width = mega.sprite_width.max(1);
+        let mut collision = false;
+        for (n, index) in sprite.iter().enumerate() {
+            let (xi, yj) = (x + n % width, y + n / width);
+            if *index == 0 || xi >= self.width || yj >= self.height {
+                continue;
+            }
+            let target = xi + yj * self.width;
+            if mega.collision != 0 && self.screen[target] == mega.collision {
+                collision = true;
+            }
+            self.screen[target] = *index;
+            let colour = mega.palette[*index as usize];
+            mega.picture[target] = mega.blend.mix(mega.picture[target], colour);
+        }
+        collision
+    }
 }
 
-pub static FONT_SET: [u8; 80] = [
-    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-    0x20, 0x60, 0x20, 0x20, 0x70, // 1
-    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
-];
+fn scrolled<T: Copy + Default>(pixels: &[T], width: usize, dx: isize, dy: isize) -> Vec<T> {
+    let height = pixels.len() / width;
+    let mut moved = vec![T::default(); pixels.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let (from_x, from_y) = (x as isize - dx, y as isize - dy);
+            if (0..width as isize).contains(&from_x) && (0..height as isize).contains(&from_y) {
+                moved[x + y * width] = pixels[from_x as usize + from_y as usize * width];
+            }
+        }
+    }
+    moved
+}
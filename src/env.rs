@@ -0,0 +1,209 @@
+use crate::cpu::Cpu;
+use std::sync::Arc;
+
+/// Memory and V registers at the end of a step, as seen by reward and
+/// termination functions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MachineState {
+    pub memory: Vec<u8>,
+    pub v: [u8; 16],
+}
+
+impl MachineState {
+    fn of(cpu: &Cpu) -> MachineState {
+        MachineState {
+            memory: cpu.memory.to_vec(),
+            v: cpu.v,
+        }
+    }
+}
+
+/// Reward of a step, given the state before and after it.
+pub type RewardFn = Arc<dyn Fn(&MachineState, &MachineState) -> f32 + Send + Sync>;
+/// Whether the episode is over.
+pub type DoneFn = Arc<dyn Fn(&MachineState) -> bool + Send + Sync>;
+
+/// Gym-style environment running a ROM for reinforcement learning.
+///
+/// Actions are the keys held during a step, bit `n` standing for key `n`.
+/// Observations are the 64x32 display, one byte per pixel. Episodes are
+/// deterministic for a given seed, and cloning an environment forks the
+/// episode, the ROM and the reward functions being shared.
+#[derive(Clone)]
+pub struct Env {
+    rom: Arc<[u8]>,
+    cpu: Cpu,
+    reward: RewardFn,
+    done: DoneFn,
+    max_frames: Option<u32>,
+    // frames run since the last reset
+    frames: u32,
+    previous: MachineState,
+}
+
+impl Env {
+    /// An environment without rewards whose episodes never end.
+    pub fn new(rom: &[u8]) -> Env {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(rom);
+        let previous = MachineState::of(&cpu);
+        Env {
+            rom: rom.into(),
+            cpu,
+            reward: Arc::new(|_, _| 0.0),
+            done: Arc::new(|_| false),
+            max_frames: None,
+            frames: 0,
+            previous,
+        }
+    }
+
+    pub fn with_reward<F>(mut self, reward: F) -> Env
+    where
+        F: Fn(&MachineState, &MachineState) -> f32 + Send + Sync + 'static,
+    {
+        self.reward = Arc::new(reward);
+        self
+    }
+
+    pub fn with_done<F>(mut self, done: F) -> Env
+    where
+        F: Fn(&MachineState) -> bool + Send + Sync + 'static,
+    {
+        self.done = Arc::new(done);
+        self
+    }
+
+    /// Ends episodes after `frames` frames.
+    pub fn with_max_frames(mut self, frames: u32) -> Env {
+        self.max_frames = Some(frames);
+        self
+    }
+
+    /// Starts a new episode, returning the first observation.
+    pub fn reset(&mut self, seed: u64) -> Vec<u8> {
+        self.cpu.seed(seed);
+        self.cpu.load_rom(&self.rom);
+        self.frames = 0;
+        self.previous = MachineState::of(&self.cpu);
+        self.observation()
+    }
+
+    /// Holds `keys` down for `frames` frames, returning the observation, the
+    /// reward over those frames and whether the episode is over. Stops early
+    /// when the episode ends, e.g. because the program faulted.
+    pub fn step(&mut self, keys: u16, frames: u32) -> (Vec<u8>, f32, bool) {
+        self.cpu.set_keys(keys);
+
+        let mut done = false;
+        for _ in 0..frames {
+            self.cpu.run_frame();
+            self.frames += 1;
+            done = self.cpu.fault().is_some()
+                || matches!(self.max_frames, Some(max) if self.frames >= max)
+                || (self.done)(&MachineState::of(&self.cpu));
+            if done {
+                break;
+            }
+        }
+
+        let state = MachineState::of(&self.cpu);
+        let reward = (self.reward)(&self.previous, &state);
+        self.previous = state;
+        (self.observation(), reward, done)
+    }
+
+    pub fn observation(&self) -> Vec<u8> {
+        self.cpu.screen().to_vec()
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    /// Frames run since the last reset.
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Env;
+
+    // 0x200: V0 = random
+    // 0x202: V1 = 1
+    // 0x204: skip if key V1 is up
+    // 0x206: V2 += 1
+    // 0x208: I = 0x300
+    // 0x20A: [I] = V0..V2
+    // 0x20C: jump 0x204
+    const ROM: [u8; 14] = [
+        0xC0, 0xFF, 0x61, 0x01, 0xE1, 0xA1, 0x72, 0x01, 0xA3, 0x00, 0xF2, 0x55, 0x12, 0x04,
+    ];
+
+    fn env() -> Env {
+        Env::new(&ROM)
+            .with_reward(|before, after| after.v[2] as f32 - before.v[2] as f32)
+            .with_done(|state| state.memory[0x302] >= 30)
+    }
+
+    #[test]
+    fn test_reward_and_done() {
+        let mut env = env();
+        env.reset(1);
+
+        let (observation, reward, done) = env.step(0, 1);
+        assert_eq!(observation.len(), 64 * 32);
+        assert_eq!((reward, done), (0.0, false));
+
+        let (_, reward, done) = env.step(1 << 1, 2);
+        assert!(reward > 0.0);
+        assert!(!done);
+
+        let mut steps = 0;
+        while !env.step(1 << 1, 1).2 {
+            steps += 1;
+        }
+        assert!(steps < 20);
+        assert!(env.frames() < 30);
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let mut a = env();
+        let mut b = env();
+        a.reset(7);
+        b.reset(7);
+        a.step(0, 3);
+        b.step(0, 3);
+        assert_eq!(a.cpu().v[0], b.cpu().v[0]);
+
+        let mut fork = a.clone();
+        assert_eq!(a.step(1 << 1, 2), fork.step(1 << 1, 2));
+        assert_eq!(a.cpu().v, fork.cpu().v);
+
+        // another seed gets another random number
+        let seeds: Vec<u8> = (0..8)
+            .map(|seed| {
+                a.reset(seed);
+                a.step(0, 1);
+                a.cpu().v[0]
+            })
+            .collect();
+        assert!(seeds.iter().any(|v| *v != seeds[0]));
+    }
+
+    #[test]
+    fn test_pong_replays() {
+        let episode = |seed| {
+            let mut env = Env::new(include_bytes!("../www/roms/PONG"));
+            env.reset(seed);
+            (0..200u16)
+                .map(|n| env.step(if n % 40 < 20 { 1 << 1 } else { 1 << 4 }, 2).0)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(episode(3), episode(3));
+    }
+}
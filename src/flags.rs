@@ -0,0 +1,212 @@
+//! Storage for the SUPER-CHIP "RPL user flags", which FX75 and FX85 save
+//! registers to and restore them from. On the HP-48 they outlived the
+//! program, so games keep high scores in them.
+//!
+//! Flags are kept per program, under a hash of the ROM, see `rom_key`.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+#[cfg(not(target_arch = "wasm32"))]
+use std::fs;
+use std::io;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::PathBuf;
+use wasm_bindgen::prelude::*;
+
+/// Number of flags. The HP-48 had 8, XO-CHIP extends them to 16.
+pub const FLAG_COUNT: usize = 16;
+
+pub type Flags = [u8; FLAG_COUNT];
+
+/// Where the flags of every program are kept.
+pub trait FlagStore: Send {
+    /// The flags the program `key` saved, None when it never saved any.
+    fn load(&mut self, key: u64) -> io::Result<Option<Flags>>;
+
+    fn save(&mut self, key: u64, flags: &Flags) -> io::Result<()>;
+
+    /// A store holding the same flags, for `Cpu` to clone with.
+    fn box_clone(&self) -> Box<dyn FlagStore>;
+}
+
+impl Clone for Box<dyn FlagStore> {
+    fn clone(&self) -> Box<dyn FlagStore> {
+        self.box_clone()
+    }
+}
+
+/// FNV-1a hash of `rom`, the key its flags are stored under.
+pub fn rom_key(rom: &[u8]) -> u64 {
+    rom.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3)
+    })
+}
+
+/// Keeps the flags until the store is dropped. The default.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryFlags {
+    flags: HashMap<u64, Flags>,
+}
+
+impl MemoryFlags {
+    pub fn new() -> MemoryFlags {
+        MemoryFlags::default()
+    }
+}
+
+impl FlagStore for MemoryFlags {
+    fn load(&mut self, key: u64) -> io::Result<Option<Flags>> {
+        Ok(self.flags.get(&key).copied())
+    }
+
+    fn save(&mut self, key: u64, flags: &Flags) -> io::Result<()> {
+        self.flags.insert(key, *flags);
+        Ok(())
+    }
+
+    fn box_clone(&self) -> Box<dyn FlagStore> {
+        Box::new(self.clone())
+    }
+}
+
+/// Keeps the flags of every program in a file of its own in a directory,
+/// named after the key in hex.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Clone, Debug)]
+pub struct FileFlags {
+    dir: PathBuf,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FileFlags {
+    /// Stores flags in `dir`, which is created on the first save.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> FileFlags {
+        FileFlags { dir: dir.into() }
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.flags", key))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FlagStore for FileFlags {
+    fn load(&mut self, key: u64) -> io::Result<Option<Flags>> {
+        match fs::read(self.path(key)) {
+            Ok(bytes) => Ok(Some(pad(&bytes))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&mut self, key: u64, flags: &Flags) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(key), flags)
+    }
+
+    fn box_clone(&self) -> Box<dyn FlagStore> {
+        Box::new(self.clone())
+    }
+}
+
+#[wasm_bindgen]
+extern "C" {
+    /// A JS object keeping the flags, with `load(key)` returning the bytes
+    /// saved for `key`, or undefined, and `save(key, flags)`. Keys are the
+    /// ROM hash as 16 hex digits.
+    #[derive(Clone, Debug)]
+    pub type FlagsHook;
+
+    #[wasm_bindgen(method, catch)]
+    fn load(this: &FlagsHook, key: &str) -> Result<Option<Vec<u8>>, JsValue>;
+
+    #[wasm_bindgen(method, catch)]
+    fn save(this: &FlagsHook, key: &str, flags: &[u8]) -> Result<(), JsValue>;
+}
+
+thread_local! {
+    // JS values can't leave the thread they were made on, so the hooks stay
+    // here and `JsFlags` only keeps their index
+    static HOOKS: RefCell<Vec<FlagsHook>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Hands the flags to a JS hook, see `FlagsHook`. The hook can only be
+/// reached from the thread that made the store.
+#[derive(Clone, Debug)]
+pub struct JsFlags {
+    hook: usize,
+}
+
+impl JsFlags {
+    pub fn new(hook: FlagsHook) -> JsFlags {
+        let hook = HOOKS.with(|hooks| {
+            let mut hooks = hooks.borrow_mut();
+            hooks.push(hook);
+            hooks.len() - 1
+        });
+        JsFlags { hook }
+    }
+
+    fn call<T>(&self, f: impl FnOnce(&FlagsHook) -> Result<T, JsValue>) -> io::Result<T> {
+        // out of the cell, in case the hook makes another store
+        let hook = HOOKS.with(|hooks| hooks.borrow().get(self.hook).cloned());
+        match hook {
+            Some(hook) => f(&hook).map_err(|e| io::Error::other(format!("{:?}", e))),
+            None => Err(io::Error::other("the flags hook is on another thread")),
+        }
+    }
+}
+
+impl FlagStore for JsFlags {
+    fn load(&mut self, key: u64) -> io::Result<Option<Flags>> {
+        let bytes = self.call(|hook| hook.load(&format!("{:016x}", key)))?;
+        Ok(bytes.map(|bytes| pad(&bytes)))
+    }
+
+    fn save(&mut self, key: u64, flags: &Flags) -> io::Result<()> {
+        self.call(|hook| hook.save(&format!("{:016x}", key), flags))
+    }
+
+    fn box_clone(&self) -> Box<dyn FlagStore> {
+        Box::new(self.clone())
+    }
+}
+
+// flags stored with fewer bytes get zeros for the rest
+fn pad(bytes: &[u8]) -> Flags {
+    let mut flags = [0; FLAG_COUNT];
+    let len = bytes.len().min(FLAG_COUNT);
+    flags[..len].copy_from_slice(&bytes[..len]);
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rom_key, FileFlags, FlagStore, MemoryFlags};
+    use std::fs;
+
+    #[test]
+    fn test_rom_key() {
+        assert_eq!(rom_key(b""), 0xCBF2_9CE4_8422_2325);
+        assert_eq!(rom_key(b"a"), 0xAF63_DC4C_8601_EC8C);
+        assert_ne!(rom_key(&[0x12, 0x00]), rom_key(&[0x00, 0x12]));
+    }
+
+    #[test]
+    fn test_stores() {
+        let dir = std::env::temp_dir().join(format!("chip-9-flags-{}", std::process::id()));
+        let mut stores: Vec<Box<dyn FlagStore>> =
+            vec![Box::new(MemoryFlags::new()), Box::new(FileFlags::new(&dir))];
+        for store in stores.iter_mut() {
+            assert_eq!(store.load(1).unwrap(), None);
+            store.save(1, &[7; 16]).unwrap();
+            assert_eq!(store.load(1).unwrap(), Some([7; 16]));
+            assert_eq!(store.load(2).unwrap(), None);
+            // clones see what was saved before
+            assert_eq!(store.clone().load(1).unwrap(), Some([7; 16]));
+        }
+        // a file survives the store
+        assert_eq!(FileFlags::new(&dir).load(1).unwrap(), Some([7; 16]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
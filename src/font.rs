@@ -0,0 +1,290 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+const SMALL: usize = 80;
+// 10 bytes a digit, SUPER-CHIP having 0 to 9 and Octo all 16
+const LARGE_DECIMAL: usize = 100;
+const LARGE_HEX: usize = 160;
+
+/// Hex digits FX29 and FX30 point at: 4x5 pixel ones in 5 bytes each, and
+/// optionally 8x10 ones in 10 bytes each, laid out in memory in that order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Font {
+    bytes: Vec<u8>,
+}
+
+impl Font {
+    /// A font of 80 bytes of small digits, followed by 100 bytes of large
+    /// ones for 0 to 9 or 160 for all of them, or by nothing.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Font, FontLengthError> {
+        match bytes.len() {
+            SMALL => (),
+            len if len == SMALL + LARGE_DECIMAL || len == SMALL + LARGE_HEX => (),
+            len => return Err(FontLengthError(len)),
+        }
+        Ok(Font {
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    /// The same font without large digits.
+    pub fn small_only(&self) -> Font {
+        Font {
+            bytes: self.bytes[..SMALL].to_vec(),
+        }
+    }
+
+    /// The font as it's copied into memory.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Offset of the large digits from the start of the font.
+    pub fn large_offset(&self) -> u16 {
+        SMALL as u16
+    }
+
+    /// How many large digits there are, 0, 10 or 16.
+    pub fn large_digits(&self) -> usize {
+        (self.bytes.len() - SMALL) / 10
+    }
+}
+
+/// A font length `Font::from_bytes` doesn't take.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FontLengthError(pub usize);
+
+impl fmt::Display for FontLengthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "a font is {} bytes, or {} or {} with large digits, not {}",
+            SMALL,
+            SMALL + LARGE_DECIMAL,
+            SMALL + LARGE_HEX,
+            self.0
+        )
+    }
+}
+
+impl Error for FontLengthError {}
+
+/// The fonts interpreters shipped with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BuiltinFont {
+    Octo,
+    Vip,
+    Dream6800,
+    Eti660,
+    Schip,
+}
+
+impl BuiltinFont {
+    pub const ALL: [BuiltinFont; 5] = [
+        BuiltinFont::Octo,
+        BuiltinFont::Vip,
+        BuiltinFont::Dream6800,
+        BuiltinFont::Eti660,
+        BuiltinFont::Schip,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BuiltinFont::Octo => "octo",
+            BuiltinFont::Vip => "vip",
+            BuiltinFont::Dream6800 => "dream-6800",
+            BuiltinFont::Eti660 => "eti-660",
+            BuiltinFont::Schip => "schip",
+        }
+    }
+
+    pub fn font(self) -> Font {
+        let (small, large): (&[u8], &[u8]) = match self {
+            BuiltinFont::Octo => (&OCTO, &OCTO_LARGE),
+            BuiltinFont::Vip => (&VIP, &[]),
+            BuiltinFont::Dream6800 => (&DREAM_6800, &[]),
+            BuiltinFont::Eti660 => (&ETI_660, &[]),
+            BuiltinFont::Schip => (&OCTO, &SCHIP_LARGE),
+        };
+        Font::from_bytes(&[small, large].concat()).unwrap()
+    }
+}
+
+impl fmt::Display for BuiltinFont {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A font name that isn't in `BuiltinFont::ALL`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnknownFont(pub String);
+
+impl fmt::Display for UnknownFont {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = BuiltinFont::ALL.iter().map(|font| font.name()).collect();
+        write!(
+            f,
+            "unknown font {}, expected one of {}",
+            self.0,
+            names.join(", ")
+        )
+    }
+}
+
+impl Error for UnknownFont {}
+
+impl FromStr for BuiltinFont {
+    type Err = UnknownFont;
+
+    /// A font by name, ignoring case.
+    fn from_str(name: &str) -> Result<BuiltinFont, UnknownFont> {
+        BuiltinFont::ALL
+            .iter()
+            .copied()
+            .find(|font| font.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| UnknownFont(name.to_string()))
+    }
+}
+
+// the digits most interpreters use, Octo and SUPER-CHIP among them, and
+// the ones this one always had
+static OCTO: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+// CHIPOS on the DREAM 6800, 3 pixels wide
+static DREAM_6800: [u8; 80] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+    0x40, 0x40, 0x40, 0x40, 0x40, // 1
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+    0x80, 0xA0, 0xA0, 0xE0, 0x20, // 4
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+    0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0xC0, 0xA0, 0xE0, 0xA0, 0xC0, // B
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0xC0, 0xA0, 0xA0, 0xA0, 0xC0, // D
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+// the ETI-660 interpreter, also 3 pixels wide
+static ETI_660: [u8; 80] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+    0x20, 0x20, 0x20, 0x20, 0x20, // 1
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+    0xA0, 0xA0, 0xE0, 0x20, 0x20, // 4
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+    0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0x80, 0x80, 0xE0, 0xA0, 0xE0, // B
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0x20, 0x20, 0xE0, 0xA0, 0xE0, // D
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+// the COSMAC VIP interpreter
+static VIP: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x60, 0x20, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0x70, 0x10, 0xF0, // 3
+    0xA0, 0xA0, 0xF0, 0x20, 0x20, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x10, 0x10, 0x10, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xF0, 0x50, 0x70, 0x50, 0xF0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xF0, 0x50, 0x50, 0x50, 0xF0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+// SUPER-CHIP 1.1 large digits, 0 to 9 only
+static SCHIP_LARGE: [u8; 100] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+];
+
+// Octo's large digits, all 16
+static OCTO_LARGE: [u8; 160] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+#[cfg(test)]
+mod tests {
+    use super::{BuiltinFont, Font, FontLengthError};
+
+    #[test]
+    fn test_builtin_fonts() {
+        for font in BuiltinFont::ALL {
+            assert_eq!(font.name().parse(), Ok(font));
+            assert!(font.font().bytes().len() >= 80);
+        }
+        assert_eq!(BuiltinFont::Dream6800.font().bytes()[5..10], [0x40; 5]);
+        assert_eq!(BuiltinFont::Schip.font().large_digits(), 10);
+        assert_eq!(BuiltinFont::Octo.font().large_digits(), 16);
+        assert_eq!(BuiltinFont::Vip.font().large_digits(), 0);
+        assert!("fish".parse::<BuiltinFont>().is_err());
+    }
+
+    #[test]
+    fn test_custom_font() {
+        let font = Font::from_bytes(&[0xAA; 180]).unwrap();
+        assert_eq!((font.large_offset(), font.large_digits()), (80, 10));
+        assert_eq!(font.small_only().bytes(), &[0xAA; 80][..]);
+        assert_eq!(Font::from_bytes(&[0; 81]), Err(FontLengthError(81)));
+    }
+}
@@ -0,0 +1,542 @@
+//! GDB remote serial protocol server exposing a `Cpu` as a debug target.
+//!
+//! Registers, in `g` packet order: V0 to VF (8 bits), I and PC (16 bits,
+//! big-endian like the rest of the machine) and SP (8 bits). The register
+//! layout is also described by `target.xml`, served through `qXfer`.
+//! Memory is the 4 KiB address space. Software breakpoints (`Z0`/`Z1`),
+//! write watchpoints (`Z2`), single step, continue and Ctrl-C interrupts
+//! are supported.
+//!
+//! CHIP-8 isn't an architecture GDB knows, so frontends need a generic
+//! target description; with GDB itself, `set endian big` before connecting.
+
+use crate::cpu::Cpu;
+use crate::debugger::{Debugger, Stop};
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+// instructions executed between checks for an interrupt from the client
+const CONTINUE_CHUNK: u64 = 10_000;
+const INTERRUPT: u8 = 0x03;
+// V0..VF, I, PC, SP
+const REGISTER_COUNT: usize = 19;
+
+/// Waits for a single debugger connection on `address` and serves it until
+/// the client kills the target or detaches.
+pub fn serve<A: ToSocketAddrs>(cpu: &mut Cpu, address: A) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    let (stream, _) = listener.accept()?;
+    GdbStub::new(stream).run(cpu)
+}
+
+pub struct GdbStub {
+    stream: TcpStream,
+    debugger: Debugger,
+    // bytes received but not consumed yet
+    input: Vec<u8>,
+    no_ack: bool,
+    last_stop: String,
+}
+
+enum Packet {
+    Command(String),
+    Interrupt,
+}
+
+impl GdbStub {
+    pub fn new(stream: TcpStream) -> GdbStub {
+        GdbStub {
+            stream,
+            debugger: Debugger::new(),
+            input: Vec::new(),
+            no_ack: false,
+            last_stop: "S05".to_string(),
+        }
+    }
+
+    /// Handles packets until the client disconnects, kills the target or
+    /// detaches.
+    pub fn run(&mut self, cpu: &mut Cpu) -> io::Result<()> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(Packet::Command(packet)) => packet,
+                // nothing is running, so the target is already stopped
+                Some(Packet::Interrupt) => {
+                    self.send("S02")?;
+                    continue;
+                }
+                None => return Ok(()),
+            };
+
+            if packet == "k" {
+                return Ok(());
+            }
+            let reply = self.handle(cpu, &packet)?;
+            self.send(&reply)?;
+            match packet.as_str() {
+                "QStartNoAckMode" => self.no_ack = true,
+                _ if packet.starts_with('D') => return Ok(()),
+                _ => (),
+            }
+        }
+    }
+
+    fn handle(&mut self, cpu: &mut Cpu, packet: &str) -> io::Result<String> {
+        let command = packet.get(..1).unwrap_or("");
+        let args = packet.get(1..).unwrap_or("");
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => read_registers(cpu),
+            "G" => ok_or_error(write_registers(cpu, args)),
+            "p" => match parse_hex(args).and_then(|n| read_register(cpu, n as usize)) {
+                Some(value) => value,
+                None => "E01".to_string(),
+            },
+            "P" => ok_or_error(
+                args.split_once('=')
+                    .and_then(|(n, value)| write_register(cpu, parse_hex(n)? as usize, value)),
+            ),
+            "m" => match parse_pair(args).and_then(|(address, len)| read_memory(cpu, address, len))
+            {
+                Some(bytes) => bytes,
+                None => "E01".to_string(),
+            },
+            "M" => ok_or_error(args.split_once(':').and_then(|(range, data)| {
+                let (address, len) = parse_pair(range)?;
+                write_memory(cpu, address, len, data)
+            })),
+            "c" => {
+                set_resume_address(cpu, args);
+                self.resume(cpu, false)?
+            }
+            "s" => {
+                set_resume_address(cpu, args);
+                self.resume(cpu, true)?
+            }
+            "Z" | "z" => self.set_point(command == "Z", args),
+            "H" => "OK".to_string(),
+            "D" => "OK".to_string(),
+            "q" | "Q" => query(packet),
+            "v" => match packet {
+                "vCont?" => "vCont;c;C;s;S".to_string(),
+                // a single thread, so the first action applies to it
+                _ if packet.starts_with("vCont;c") || packet.starts_with("vCont;C") => {
+                    self.resume(cpu, false)?
+                }
+                _ if packet.starts_with("vCont;s") || packet.starts_with("vCont;S") => {
+                    self.resume(cpu, true)?
+                }
+                _ => String::new(),
+            },
+            // an empty reply tells the client the packet isn't supported
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn resume(&mut self, cpu: &mut Cpu, single_step: bool) -> io::Result<String> {
+        let stop = if single_step {
+            Some(self.debugger.step(cpu))
+        } else {
+            self.continue_execution(cpu)?
+        };
+        self.last_stop = match stop {
+            Some(Stop::Breakpoint(_)) => "T05swbreak:;".to_string(),
+            Some(Stop::Watchpoint { address, .. }) => format!("T05watch:{:x};", address),
+            // faults are reported as SIGSEGV
+            Some(Stop::Fault(_)) => "S0b".to_string(),
+            Some(Stop::Step) | Some(Stop::Limit) => "S05".to_string(),
+            None => "S02".to_string(),
+        };
+        Ok(self.last_stop.clone())
+    }
+
+    // Runs until something stops the CPU, or returns `None` when the client
+    // interrupted it.
+    fn continue_execution(&mut self, cpu: &mut Cpu) -> io::Result<Option<Stop>> {
+        let mut stop = self.debugger.run(cpu, CONTINUE_CHUNK);
+        while stop == Stop::Limit {
+            if self.poll_interrupt()? {
+                return Ok(None);
+            }
+            stop = if self.debugger.has_breakpoint(cpu.pc) {
+                Stop::Breakpoint(cpu.pc)
+            } else {
+                self.debugger.run(cpu, CONTINUE_CHUNK)
+            };
+        }
+        Ok(Some(stop))
+    }
+
+    // Z<type>,<address>,<kind>: 0 and 1 are breakpoints, 2 write watchpoints
+    // of `kind` bytes.
+    fn set_point(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let (kind, address, len) = match (
+            fields.next(),
+            fields.next().and_then(parse_hex),
+            fields.next().and_then(parse_hex),
+        ) {
+            (Some(kind), Some(address), Some(len)) => (kind, address as u16, len as u16),
+            _ => return "E01".to_string(),
+        };
+
+        match kind {
+            "0" | "1" if insert => self.debugger.add_breakpoint(address),
+            "0" | "1" => {
+                self.debugger.remove_breakpoint(address);
+            }
+            "2" => {
+                for address in address..address.saturating_add(len.max(1)) {
+                    if insert {
+                        self.debugger.add_watchpoint(address);
+                    } else {
+                        self.debugger.remove_watchpoint(address);
+                    }
+                }
+            }
+            _ => return String::new(),
+        }
+        "OK".to_string()
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.input.is_empty() {
+            let mut buffer = [0; 1024];
+            let n = self.stream.read(&mut buffer)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.input.extend_from_slice(&buffer[..n]);
+        }
+        Ok(Some(self.input.remove(0)))
+    }
+
+    // Reads the next packet, acknowledging it, and skipping acks sent by the
+    // client. `None` once the connection is closed.
+    fn read_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(INTERRUPT) => return Ok(Some(Packet::Interrupt)),
+                Some(b'$') => (),
+                Some(_) => continue,
+            }
+
+            let mut data = Vec::new();
+            let mut escaped = false;
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') if !escaped => break,
+                    Some(b'}') if !escaped => escaped = true,
+                    Some(byte) if escaped => {
+                        data.push(byte ^ 0x20);
+                        escaped = false;
+                    }
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                *digit = match self.read_byte()? {
+                    Some(byte) => byte,
+                    None => return Ok(None),
+                };
+            }
+
+            if self.no_ack {
+                return Ok(Some(Packet::Command(
+                    String::from_utf8_lossy(&data).into_owned(),
+                )));
+            }
+            let expected = format!(
+                "{:02x}",
+                data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+            );
+            if checksum.eq_ignore_ascii_case(expected.as_bytes()) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(Packet::Command(
+                    String::from_utf8_lossy(&data).into_owned(),
+                )));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, checksum);
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            // retransmit until the client acknowledges
+            loop {
+                match self.read_byte()? {
+                    Some(b'+') => return Ok(()),
+                    Some(b'-') => break,
+                    Some(_) => (),
+                    None => return Ok(()),
+                }
+            }
+        }
+    }
+
+    // Whether the client sent a Ctrl-C, without blocking.
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        if self.input.is_empty() {
+            let mut buffer = [0; 1024];
+            self.stream.set_nonblocking(true)?;
+            let result = self.stream.read(&mut buffer);
+            self.stream.set_nonblocking(false)?;
+            match result {
+                Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        match self.input.iter().position(|&byte| byte == INTERRUPT) {
+            Some(index) => {
+                self.input.remove(index);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+fn query(packet: &str) -> String {
+    match packet {
+        _ if packet.starts_with("qSupported") => {
+            "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+;swbreak+".to_string()
+        }
+        "QStartNoAckMode" => "OK".to_string(),
+        "qAttached" => "1".to_string(),
+        "qC" => "QC1".to_string(),
+        "qfThreadInfo" => "m1".to_string(),
+        "qsThreadInfo" => "l".to_string(),
+        _ if packet.starts_with("qXfer:features:read:") => {
+            let args = &packet["qXfer:features:read:".len()..];
+            match args.split_once(':') {
+                Some(("target.xml", range)) => match parse_pair(range) {
+                    Some((offset, len)) => {
+                        let xml = target_xml();
+                        let start = (offset as usize).min(xml.len());
+                        let end = (start + len as usize).min(xml.len());
+                        let more = if end < xml.len() { 'm' } else { 'l' };
+                        format!("{}{}", more, &xml[start..end])
+                    }
+                    None => "E01".to_string(),
+                },
+                _ => "E00".to_string(),
+            }
+        }
+        _ => String::new(),
+    }
+}
+
+/// Register layout served to the client.
+pub fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><feature name=\"org.chip8.core\">",
+    );
+    for x in 0..16 {
+        write!(xml, "<reg name=\"v{:x}\" bitsize=\"8\" type=\"uint8\"/>", x).unwrap();
+    }
+    xml.push_str(
+        "<reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>\
+         <reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>\
+         <reg name=\"sp\" bitsize=\"8\" type=\"uint8\"/>\
+         </feature></target>",
+    );
+    xml
+}
+
+fn read_register(cpu: &Cpu, n: usize) -> Option<String> {
+    match n {
+        0..=15 => Some(format!("{:02x}", cpu.v[n])),
+        16 => Some(format!("{:04x}", cpu.i)),
+        17 => Some(format!("{:04x}", cpu.pc)),
+        18 => Some(format!("{:02x}", cpu.sp)),
+        _ => None,
+    }
+}
+
+fn read_registers(cpu: &Cpu) -> String {
+    (0..REGISTER_COUNT)
+        .filter_map(|n| read_register(cpu, n))
+        .collect()
+}
+
+fn write_register(cpu: &mut Cpu, n: usize, value: &str) -> Option<()> {
+    let value = parse_hex(value)?;
+    match n {
+        0..=15 => cpu.v[n] = value as u8,
+        16 => cpu.i = value as u16,
+        17 => cpu.pc = value as u16,
+        18 if value as usize <= cpu.stack.len() => cpu.sp = value as u8,
+        _ => return None,
+    }
+    Some(())
+}
+
+fn write_registers(cpu: &mut Cpu, data: &str) -> Option<()> {
+    let mut offset = 0;
+    for n in 0..REGISTER_COUNT {
+        let width = if n == 16 || n == 17 { 4 } else { 2 };
+        write_register(cpu, n, data.get(offset..offset + width)?)?;
+        offset += width;
+    }
+    Some(())
+}
+
+fn read_memory(cpu: &Cpu, address: u32, len: u32) -> Option<String> {
+    let start = address as usize;
+    let end = (start + len as usize).min(cpu.memory.len());
+    if start >= end {
+        return None;
+    }
+    Some(
+        cpu.memory[start..end]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+    )
+}
+
+fn write_memory(cpu: &mut Cpu, address: u32, len: u32, data: &str) -> Option<()> {
+    let start = address as usize;
+    if start + len as usize > cpu.memory.len() || data.len() != len as usize * 2 {
+        return None;
+    }
+    let bytes = (0..len as usize)
+        .map(|n| u8::from_str_radix(data.get(n * 2..n * 2 + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    for (offset, byte) in bytes.into_iter().enumerate() {
+        cpu.write_memory(start + offset, byte);
+    }
+    Some(())
+}
+
+fn set_resume_address(cpu: &mut Cpu, args: &str) {
+    if let Some(address) = parse_hex(args) {
+        cpu.pc = address as u16;
+    }
+}
+
+fn ok_or_error(result: Option<()>) -> String {
+    match result {
+        Some(()) => "OK".to_string(),
+        None => "E01".to_string(),
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+// "<address>,<length>"
+fn parse_pair(text: &str) -> Option<(u32, u32)> {
+    let (address, len) = text.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(len)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GdbStub;
+    use crate::cpu::Cpu;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn request(&mut self, data: &str) -> String {
+            let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+            write!(self.stream, "${}#{:02x}", data, checksum).unwrap();
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            let mut reply = Vec::new();
+            let mut byte = [0];
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                match byte[0] {
+                    b'+' if reply.is_empty() => (),
+                    b'#' => break,
+                    b'$' => reply.clear(),
+                    b => reply.push(b),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut client = Client {
+                stream: TcpStream::connect(address).unwrap(),
+            };
+            assert!(client
+                .request("qSupported:swbreak+")
+                .contains("qXfer:features:read+"));
+            assert!(client
+                .request("qXfer:features:read:target.xml:0,fff")
+                .starts_with("l<?xml"));
+            assert_eq!(client.request("?"), "S05");
+            assert_eq!(
+                client.request("g"),
+                format!("{}0000020000", "00".repeat(16))
+            );
+
+            assert_eq!(client.request("s"), "S05");
+            assert_eq!(client.request("p0"), "01");
+            assert_eq!(client.request("p11"), "0202");
+
+            assert_eq!(client.request("Z0,204,2"), "OK");
+            assert_eq!(client.request("c"), "T05swbreak:;");
+            assert_eq!(client.request("p0"), "02");
+            assert_eq!(client.request("c"), "T05swbreak:;");
+            assert_eq!(client.request("p0"), "03");
+            assert_eq!(client.request("z0,204,2"), "OK");
+
+            assert_eq!(client.request("m200,4"), "60017001");
+            assert_eq!(client.request("M300,2:abcd"), "OK");
+            assert_eq!(client.request("m300,2"), "abcd");
+            assert_eq!(client.request("P5=7f"), "OK");
+            assert_eq!(client.request("p5"), "7f");
+
+            // loops forever until interrupted
+            client.stream.write_all(b"$c#63").unwrap();
+            client.stream.write_all(&[0x03]).unwrap();
+            assert_eq!(client.reply(), "S02");
+
+            client.stream.write_all(b"$k#6b").unwrap();
+        });
+
+        let mut cpu = Cpu::new();
+        // V0 = 1, V0 += 1, jump 0x202
+        cpu.load_rom(&[0x60, 0x01, 0x70, 0x01, 0x12, 0x02]);
+        let (stream, _) = listener.accept().unwrap();
+        GdbStub::new(stream).run(&mut cpu).unwrap();
+        client.join().unwrap();
+
+        assert_eq!(cpu.v[5], 0x7F);
+        assert_eq!(cpu.memory[0x300], 0xAB);
+    }
+}
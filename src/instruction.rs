@@ -0,0 +1,416 @@
+use std::fmt;
+
+/// A decoded CHIP-8 instruction.
+///
+/// Operands are already split out of the opcode so the interpreter doesn't
+/// have to re-read memory and shift nibbles every time the same address is
+/// executed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    // 00E0: clears the display
+    Cls,
+    // 00EE: returns from a subroutine
+    Ret,
+    // 0230: clears the 64x64 display of HiRes CHIP-8
+    HiresCls,
+    // 00CN: scrolls the display down N pixels
+    ScrollDown(u8),
+    // 00DN: scrolls the selected planes up N pixels
+    ScrollUp(u8),
+    // 00FB: scrolls the display right 4 pixels
+    ScrollRight,
+    // 00FC: scrolls the display left 4 pixels
+    ScrollLeft,
+    // 00FD: exits the interpreter
+    Exit,
+    // 00FE: switches to the 64x32 display
+    LowRes,
+    // 00FF: switches to the 128x64 display
+    HighRes,
+    // 02A0: steps the CHIP-8X background colour
+    Background,
+    // 0010: leaves MegaChip mode
+    MegaOff,
+    // 0011: enters MegaChip mode, a 256x192 display of palette indices
+    MegaOn,
+    // 01NN NNNN: sets I to the 24-bit NNNNNN, the low 16 bits coming from
+    // the next word
+    LongIndex(u8),
+    // 02NN: loads NN colours from I into the palette
+    LoadPalette(u8),
+    // 03NN: sets the sprite width, 0 meaning 256
+    SpriteWidth(u8),
+    // 04NN: sets the sprite height, 0 meaning 256
+    SpriteHeight(u8),
+    // 05NN: fades the screen to alpha NN
+    ScreenAlpha(u8),
+    // 060N: plays the digitised sound at I, once if N is 1, looping if 0
+    PlaySample(u8),
+    // 0700: stops the sound
+    StopSample,
+    // 080N: selects how sprites are blended
+    BlendMode(u8),
+    // 09NN: sets the colour index that collides
+    CollisionColour(u8),
+    // 1NNN: jumps to address NNN
+    Jump(u16),
+    // 2NNN: calls subroutine at NNN
+    Call(u16),
+    // 3XNN: skips the next instruction if VX equals NN
+    SkipEq(usize, u8),
+    // 4XNN: skips the next instruction if VX doesn't equal NN
+    SkipNe(usize, u8),
+    // 5XY0: skips the next instruction if VX equals VY
+    SkipEqReg(usize, usize),
+    // 6XNN: sets VX to NN
+    Set(usize, u8),
+    // 7XNN: adds NN to VX (carry flag is not changed)
+    Add(usize, u8),
+    // 8XY0: sets VX to the value of VY
+    Assign(usize, usize),
+    // 8XY1: sets VX to VX or VY
+    Or(usize, usize),
+    // 8XY2: sets VX to VX and VY
+    And(usize, usize),
+    // 8XY3: sets VX to VX xor VY
+    Xor(usize, usize),
+    // 8XY4: adds VY to VX, VF is set to the carry
+    AddReg(usize, usize),
+    // 8XY5: subtracts VY from VX, VF is set to 0 on borrow
+    SubReg(usize, usize),
+    // 8XY6: shifts VX right by 1, VF is set to the shifted out bit
+    ShiftRight(usize, usize),
+    // 8XY7: sets VX to VY minus VX, VF is set to 0 on borrow
+    SubN(usize, usize),
+    // 8XYE: shifts VX left by 1, VF is set to the shifted out bit
+    ShiftLeft(usize, usize),
+    // 9XY0: skips the next instruction if VX doesn't equal VY
+    SkipNeReg(usize, usize),
+    // ANNN: sets I to NNN
+    SetIndex(u16),
+    // BNNN: jumps to NNN plus V0
+    JumpV0(u16),
+    // CXNN: sets VX to rand() & NN
+    Random(usize, u8),
+    // DXYN: draws an N rows sprite from I at (VX, VY)
+    Draw(usize, usize, u8),
+    // EX9E: skips the next instruction if the key in VX is pressed
+    SkipKeyDown(usize),
+    // EXA1: skips the next instruction if the key in VX isn't pressed
+    SkipKeyUp(usize),
+    // FX07: sets VX to the delay timer
+    GetDelay(usize),
+    // FX0A: waits for a key press and stores it in VX
+    WaitKey(usize),
+    // FX15: sets the delay timer to VX
+    SetDelay(usize),
+    // FX18: sets the sound timer to VX
+    SetSound(usize),
+    // FX1E: adds VX to I
+    AddIndex(usize),
+    // FX29: points I at the font glyph for the low digit of VX
+    Font(usize),
+    // FX30: points I at the large font glyph for the low digit of VX
+    LargeFont(usize),
+    // FX33: stores the BCD representation of VX at I, I+1 and I+2
+    Bcd(usize),
+    // FX55: stores V0 to VX in memory starting at I
+    Store(usize),
+    // FX65: fills V0 to VX from memory starting at I
+    Load(usize),
+    // FX75: saves V0 to VX to the RPL user flags
+    SaveFlags(usize),
+    // FX85: fills V0 to VX from the RPL user flags
+    LoadFlags(usize),
+    // 5XY1 on CHIP-8X: adds each octal digit of VY to VX, without carries
+    AddColour(usize, usize),
+    // BXY0 on CHIP-8X: colours the zones given by VX and VX+1 with VY
+    ColourZones(usize, usize),
+    // BXYN on CHIP-8X: colours 8xN pixels at (VX, VY) with VX+1
+    ColourRows(usize, usize, u8),
+    // EXF2: skips the next instruction if key VX of the second keypad is down
+    SkipKey2Down(usize),
+    // EXF5: skips the next instruction if key VX of the second keypad is up
+    SkipKey2Up(usize),
+    // FXF8: outputs VX to port 3, the tone generator
+    Output(usize),
+    // FXFB: waits for input on port 3 and stores it in VX
+    Input(usize),
+    // F000 NNNN on XO-CHIP: sets I to the 16-bit NNNN from the next word
+    SetLongIndex,
+    // FN01 on XO-CHIP: selects the bitplanes in the mask N
+    Planes(u8),
+    // 5XY2 on XO-CHIP: stores VX to VY at I, in that order, leaving I be
+    StoreRange(usize, usize),
+    // 5XY3 on XO-CHIP: fills VX to VY from I, in that order, leaving I be
+    LoadRange(usize, usize),
+    // anything the interpreter doesn't know about
+    Unknown(u16),
+}
+
+impl Instruction {
+    pub fn decode(opcode: u16) -> Instruction {
+        let x = ((opcode & 0x0F00) >> 8) as usize;
+        let y = ((opcode & 0x00F0) >> 4) as usize;
+        let nnn = opcode & 0x0FFF;
+        let nn = (opcode & 0x00FF) as u8;
+        let n = (opcode & 0x000F) as u8;
+
+        // break up into nibbles
+        let op_1 = (opcode & 0xF000) >> 12;
+        let op_2 = (opcode & 0x0F00) >> 8;
+        let op_3 = (opcode & 0x00F0) >> 4;
+        let op_4 = opcode & 0x000F;
+
+        match (op_1, op_2, op_3, op_4) {
+            (0, 0, 0xE, 0) => Instruction::Cls,
+            (0, 0, 0xE, 0xE) => Instruction::Ret,
+            (0, 0, 0xC, _) => Instruction::ScrollDown(op_4 as u8),
+            (0, 0, 0xD, _) => Instruction::ScrollUp(op_4 as u8),
+            (0, 0, 0xF, 0xB) => Instruction::ScrollRight,
+            (0, 0, 0xF, 0xC) => Instruction::ScrollLeft,
+            (0, 0, 0xF, 0xD) => Instruction::Exit,
+            (0, 0, 0xF, 0xE) => Instruction::LowRes,
+            (0, 0, 0xF, 0xF) => Instruction::HighRes,
+            (0, 0x2, 0x3, 0) => Instruction::HiresCls,
+            (0, 0x2, 0xA, 0) => Instruction::Background,
+            (0, 0, 0x1, 0) => Instruction::MegaOff,
+            (0, 0, 0x1, 0x1) => Instruction::MegaOn,
+            (0, 0x1, _, _) => Instruction::LongIndex(nn),
+            (0, 0x2, _, _) => Instruction::LoadPalette(nn),
+            (0, 0x3, _, _) => Instruction::SpriteWidth(nn),
+            (0, 0x4, _, _) => Instruction::SpriteHeight(nn),
+            (0, 0x5, _, _) => Instruction::ScreenAlpha(nn),
+            (0, 0x6, 0, _) => Instruction::PlaySample(op_4 as u8),
+            (0, 0x7, 0, 0) => Instruction::StopSample,
+            (0, 0x8, 0, _) => Instruction::BlendMode(op_4 as u8),
+            (0, 0x9, _, _) => Instruction::CollisionColour(nn),
+            (0x1, _, _, _) => Instruction::Jump(nnn),
+            (0x2, _, _, _) => Instruction::Call(nnn),
+            (0x3, _, _, _) => Instruction::SkipEq(x, nn),
+            (0x4, _, _, _) => Instruction::SkipNe(x, nn),
+            (0x5, _, _, _) => Instruction::SkipEqReg(x, y),
+            (0x6, _, _, _) => Instruction::Set(x, nn),
+            (0x7, _, _, _) => Instruction::Add(x, nn),
+            (0x8, _, _, 0x0) => Instruction::Assign(x, y),
+            (0x8, _, _, 0x1) => Instruction::Or(x, y),
+            (0x8, _, _, 0x2) => Instruction::And(x, y),
+            (0x8, _, _, 0x3) => Instruction::Xor(x, y),
+            (0x8, _, _, 0x4) => Instruction::AddReg(x, y),
+            (0x8, _, _, 0x5) => Instruction::SubReg(x, y),
+            (0x8, _, _, 0x6) => Instruction::ShiftRight(x, y),
+            (0x8, _, _, 0x7) => Instruction::SubN(x, y),
+            (0x8, _, _, 0xE) => Instruction::ShiftLeft(x, y),
+            (0x9, _, _, _) => Instruction::SkipNeReg(x, y),
+            (0xA, _, _, _) => Instruction::SetIndex(nnn),
+            (0xB, _, _, _) => Instruction::JumpV0(nnn),
+            (0xC, _, _, _) => Instruction::Random(x, nn),
+            (0xD, _, _, _) => Instruction::Draw(x, y, n),
+            (0xE, _, 0x9, 0xE) => Instruction::SkipKeyDown(x),
+            (0xE, _, 0xA, 0x1) => Instruction::SkipKeyUp(x),
+            (0xE, _, 0xF, 0x2) => Instruction::SkipKey2Down(x),
+            (0xE, _, 0xF, 0x5) => Instruction::SkipKey2Up(x),
+            (0xF, 0, 0, 0) => Instruction::SetLongIndex,
+            (0xF, _, 0x0, 0x1) => Instruction::Planes(op_2 as u8),
+            (0xF, _, 0x0, 0x7) => Instruction::GetDelay(x),
+            (0xF, _, 0x0, 0xA) => Instruction::WaitKey(x),
+            (0xF, _, 0x1, 0x5) => Instruction::SetDelay(x),
+            (0xF, _, 0x1, 0x8) => Instruction::SetSound(x),
+            (0xF, _, 0x1, 0xE) => Instruction::AddIndex(x),
+            (0xF, _, 0x2, 0x9) => Instruction::Font(x),
+            (0xF, _, 0x3, 0x0) => Instruction::LargeFont(x),
+            (0xF, _, 0x3, 0x3) => Instruction::Bcd(x),
+            (0xF, _, 0x5, 0x5) => Instruction::Store(x),
+            (0xF, _, 0x6, 0x5) => Instruction::Load(x),
+            (0xF, _, 0x7, 0x5) => Instruction::SaveFlags(x),
+            (0xF, _, 0x8, 0x5) => Instruction::LoadFlags(x),
+            (0xF, _, 0xF, 0x8) => Instruction::Output(x),
+            (0xF, _, 0xF, 0xB) => Instruction::Input(x),
+            (_, _, _, _) => Instruction::Unknown(opcode),
+        }
+    }
+
+    /// The nibble pattern the instruction was decoded from, e.g. `8XY4`.
+    pub fn pattern(&self) -> &'static str {
+        match self {
+            Instruction::Cls => "00E0",
+            Instruction::HiresCls => "0230",
+            Instruction::ScrollDown(_) => "00CN",
+            Instruction::ScrollUp(_) => "00DN",
+            Instruction::ScrollRight => "00FB",
+            Instruction::ScrollLeft => "00FC",
+            Instruction::Exit => "00FD",
+            Instruction::LowRes => "00FE",
+            Instruction::HighRes => "00FF",
+            Instruction::Background => "02A0",
+            Instruction::MegaOff => "0010",
+            Instruction::MegaOn => "0011",
+            Instruction::LongIndex(_) => "01NN",
+            Instruction::LoadPalette(_) => "02NN",
+            Instruction::SpriteWidth(_) => "03NN",
+            Instruction::SpriteHeight(_) => "04NN",
+            Instruction::ScreenAlpha(_) => "05NN",
+            Instruction::PlaySample(_) => "060N",
+            Instruction::StopSample => "0700",
+            Instruction::BlendMode(_) => "080N",
+            Instruction::CollisionColour(_) => "09NN",
+            Instruction::Ret => "00EE",
+            Instruction::Jump(_) => "1NNN",
+            Instruction::Call(_) => "2NNN",
+            Instruction::SkipEq(..) => "3XNN",
+            Instruction::SkipNe(..) => "4XNN",
+            Instruction::SkipEqReg(..) => "5XY0",
+            Instruction::Set(..) => "6XNN",
+            Instruction::Add(..) => "7XNN",
+            Instruction::Assign(..) => "8XY0",
+            Instruction::Or(..) => "8XY1",
+            Instruction::And(..) => "8XY2",
+            Instruction::Xor(..) => "8XY3",
+            Instruction::AddReg(..) => "8XY4",
+            Instruction::SubReg(..) => "8XY5",
+            Instruction::ShiftRight(..) => "8XY6",
+            Instruction::SubN(..) => "8XY7",
+            Instruction::ShiftLeft(..) => "8XYE",
+            Instruction::SkipNeReg(..) => "9XY0",
+            Instruction::SetIndex(_) => "ANNN",
+            Instruction::JumpV0(_) => "BNNN",
+            Instruction::Random(..) => "CXNN",
+            Instruction::Draw(..) => "DXYN",
+            Instruction::SkipKeyDown(_) => "EX9E",
+            Instruction::SkipKeyUp(_) => "EXA1",
+            Instruction::GetDelay(_) => "FX07",
+            Instruction::WaitKey(_) => "FX0A",
+            Instruction::SetDelay(_) => "FX15",
+            Instruction::SetSound(_) => "FX18",
+            Instruction::AddIndex(_) => "FX1E",
+            Instruction::Font(_) => "FX29",
+            Instruction::LargeFont(_) => "FX30",
+            Instruction::Bcd(_) => "FX33",
+            Instruction::Store(_) => "FX55",
+            Instruction::Load(_) => "FX65",
+            Instruction::SaveFlags(_) => "FX75",
+            Instruction::LoadFlags(_) => "FX85",
+            Instruction::AddColour(..) => "5XY1",
+            Instruction::ColourZones(..) => "BXY0",
+            Instruction::ColourRows(..) => "BXYN",
+            Instruction::SkipKey2Down(_) => "EXF2",
+            Instruction::SkipKey2Up(_) => "EXF5",
+            Instruction::Output(_) => "FXF8",
+            Instruction::Input(_) => "FXFB",
+            Instruction::SetLongIndex => "F000",
+            Instruction::Planes(_) => "FN01",
+            Instruction::StoreRange(..) => "5XY2",
+            Instruction::LoadRange(..) => "5XY3",
+            Instruction::Unknown(_) => "????",
+        }
+    }
+}
+
+// Disassembles in the usual Cowgod mnemonics, e.g. `LD V0, 0x55`.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Cls | Instruction::HiresCls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::ScrollDown(n) => write!(f, "SCD {}", n),
+            Instruction::ScrollUp(n) => write!(f, "SCU {}", n),
+            Instruction::ScrollRight => write!(f, "SCR"),
+            Instruction::ScrollLeft => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::LowRes => write!(f, "LOW"),
+            Instruction::HighRes => write!(f, "HIGH"),
+            Instruction::Jump(nnn) => write!(f, "JP {:#05X}", nnn),
+            Instruction::Call(nnn) => write!(f, "CALL {:#05X}", nnn),
+            Instruction::SkipEq(x, nn) => write!(f, "SE V{:X}, {:#04X}", x, nn),
+            Instruction::SkipNe(x, nn) => write!(f, "SNE V{:X}, {:#04X}", x, nn),
+            Instruction::SkipEqReg(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::Set(x, nn) => write!(f, "LD V{:X}, {:#04X}", x, nn),
+            Instruction::Add(x, nn) => write!(f, "ADD V{:X}, {:#04X}", x, nn),
+            Instruction::Assign(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::SubReg(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::ShiftRight(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::SubN(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::ShiftLeft(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SkipNeReg(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::SetIndex(nnn) => write!(f, "LD I, {:#05X}", nnn),
+            Instruction::JumpV0(nnn) => write!(f, "JP V0, {:#05X}", nnn),
+            Instruction::Random(x, nn) => write!(f, "RND V{:X}, {:#04X}", x, nn),
+            Instruction::Draw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipKeyDown(x) => write!(f, "SKP V{:X}", x),
+            Instruction::SkipKeyUp(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::GetDelay(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::WaitKey(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::SetDelay(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::SetSound(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddIndex(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::Font(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::LargeFont(x) => write!(f, "LD HF, V{:X}", x),
+            Instruction::Bcd(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::Store(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::Load(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::SaveFlags(x) => write!(f, "LD R, V{:X}", x),
+            Instruction::LoadFlags(x) => write!(f, "LD V{:X}, R", x),
+            Instruction::Background => write!(f, "BGC"),
+            Instruction::MegaOff => write!(f, "MEGAOFF"),
+            Instruction::MegaOn => write!(f, "MEGAON"),
+            Instruction::LongIndex(nn) => write!(f, "LDHI {:#04X}", nn),
+            Instruction::LoadPalette(nn) => write!(f, "LDPAL {}", nn),
+            Instruction::SpriteWidth(nn) => write!(f, "SPRW {}", nn),
+            Instruction::SpriteHeight(nn) => write!(f, "SPRH {}", nn),
+            Instruction::ScreenAlpha(nn) => write!(f, "ALPHA {:#04X}", nn),
+            Instruction::PlaySample(n) => write!(f, "DIGISND {}", n),
+            Instruction::StopSample => write!(f, "STOPSND"),
+            Instruction::BlendMode(n) => write!(f, "BMODE {}", n),
+            Instruction::CollisionColour(nn) => write!(f, "CCOL {}", nn),
+            Instruction::AddColour(x, y) => write!(f, "ADD V{:X}, V{:X}, C", x, y),
+            Instruction::ColourZones(x, y) => write!(f, "COL V{:X}, V{:X}", x, y),
+            Instruction::ColourRows(x, y, n) => write!(f, "COL V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipKey2Down(x) => write!(f, "SKP2 V{:X}", x),
+            Instruction::SkipKey2Up(x) => write!(f, "SKNP2 V{:X}", x),
+            Instruction::Output(x) => write!(f, "OUT V{:X}", x),
+            Instruction::Input(x) => write!(f, "INP V{:X}", x),
+            Instruction::SetLongIndex => write!(f, "LD I, LONG"),
+            Instruction::Planes(n) => write!(f, "PLANE {}", n),
+            Instruction::StoreRange(x, y) => write!(f, "LD [I], V{:X}-V{:X}", x, y),
+            Instruction::LoadRange(x, y) => write!(f, "LD V{:X}-V{:X}, [I]", x, y),
+            Instruction::Unknown(opcode) => write!(f, "DW {:#06X}", opcode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Instruction;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(Instruction::decode(0x00E0), Instruction::Cls);
+        assert_eq!(Instruction::decode(0x0230), Instruction::HiresCls);
+        assert_eq!(Instruction::decode(0x00C4), Instruction::ScrollDown(4));
+        assert_eq!(Instruction::decode(0x00FF), Instruction::HighRes);
+        assert_eq!(Instruction::decode(0x00D2), Instruction::ScrollUp(2));
+        assert_eq!(Instruction::decode(0xF000), Instruction::SetLongIndex);
+        assert_eq!(Instruction::decode(0xF201), Instruction::Planes(2));
+        assert_eq!(Instruction::decode(0x1655), Instruction::Jump(0x655));
+        assert_eq!(Instruction::decode(0x8AB4), Instruction::AddReg(0xA, 0xB));
+        assert_eq!(Instruction::decode(0xD125), Instruction::Draw(1, 2, 5));
+        assert_eq!(Instruction::decode(0xF355), Instruction::Store(3));
+        assert_eq!(Instruction::decode(0xF775), Instruction::SaveFlags(7));
+        assert_eq!(Instruction::decode(0xF285), Instruction::LoadFlags(2));
+        assert_eq!(Instruction::decode(0x8AB8), Instruction::Unknown(0x8AB8));
+    }
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(Instruction::decode(0x6055).to_string(), "LD V0, 0x55");
+        assert_eq!(Instruction::decode(0x2655).to_string(), "CALL 0x655");
+        assert_eq!(Instruction::decode(0xDAB5).to_string(), "DRW VA, VB, 5");
+        assert_eq!(Instruction::decode(0xF265).to_string(), "LD V2, [I]");
+        assert_eq!(Instruction::decode(0xF375).to_string(), "LD R, V3");
+        assert_eq!(Instruction::decode(0x8AB8).to_string(), "DW 0x8AB8");
+    }
+}
@@ -1,10 +1,16 @@
+#[derive(Clone)]
 pub struct Keypad {
     pub keys: [bool; 16],
+    /// The second hex keypad of CHIP-8X, only read by its own opcodes.
+    pub second: [bool; 16],
 }
 
 impl Keypad {
     pub fn new() -> Keypad {
-        Keypad { keys: [false; 16] }
+        Keypad {
+            keys: [false; 16],
+            second: [false; 16],
+        }
     }
 
     pub fn key_down(&mut self, index: u8) {
@@ -18,4 +24,16 @@ impl Keypad {
     pub fn is_key_down(&self, index: u8) -> bool {
         self.keys[index as usize]
     }
+
+    pub fn second_key_down(&mut self, index: u8) {
+        self.second[index as usize] = true;
+    }
+
+    pub fn second_key_up(&mut self, index: u8) {
+        self.second[index as usize] = false;
+    }
+
+    pub fn is_second_key_down(&self, index: u8) -> bool {
+        self.second[index as usize]
+    }
 }
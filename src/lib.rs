@@ -1,9 +1,33 @@
+pub mod analysis;
+pub mod batch;
+pub mod cdp1802;
+pub mod cheat;
+mod clock;
+pub mod coverage;
 mod cpu;
+pub mod debugger;
+pub mod difftest;
 mod display;
+pub mod env;
+pub mod flags;
+pub mod font;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod gdbstub;
+mod instruction;
 mod keypad;
+pub mod megachip;
+pub mod monitor;
+pub mod platform;
+pub mod profiler;
+pub mod recompiler;
+pub mod timing;
+pub mod trace;
+pub mod trigger;
+pub mod tui;
 mod utils;
+pub mod vip;
 
-use cpu::Cpu;
+pub use cpu::{Cpu, CpuError, StateError};
 use wasm_bindgen::prelude::*;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
@@ -1,29 +1,567 @@
-use rand::prelude::*;
+use chip_9::analysis::Analysis;
+use chip_9::cheat::CheatList;
+use chip_9::difftest::{self, CpuEngine, Script};
+use chip_9::flags::FileFlags;
+use chip_9::font::{BuiltinFont, Font};
+use chip_9::gdbstub;
+use chip_9::monitor::Monitor;
+use chip_9::platform::Platform;
+use chip_9::recompiler;
+use chip_9::trace::Tracer;
+use chip_9::trigger::TriggerSet;
+use chip_9::tui::Tui;
+use chip_9::vip::{self, Vip};
+use chip_9::Cpu;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{self, Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
-fn main() {
-    let mut memory: [u8; 4096] = [0; 4096];
+const HOST_FRAME: Duration = Duration::from_micros(16_667);
+
+fn usage() -> ! {
+    eprintln!("usage: chip-9 run <rom> [--speed <multiplier|unlimited>] [--frames <count>]");
+    eprintln!("                  [--cheats <file>] [--triggers <file>] [--vip-timing]");
+    eprintln!("                  [--platform <name>] [--font <name|file>] [--flags <dir>]");
+    eprintln!("       chip-9 trace <rom> <instructions>");
+    eprintln!("       chip-9 profile <rom> <frames>");
+    eprintln!("       chip-9 coverage <rom> <frames> [<coverage file>]");
+    eprintln!("       chip-9 analyze <rom> [--dot] [--platform <name>]");
+    eprintln!("       chip-9 recompile <rom>");
+    eprintln!("       chip-9 difftest <rom> <frames> [<input script>] [--platform <name>]");
+    eprintln!("                       [--seed <n>]");
+    eprintln!("       chip-9 gdb <rom> [<port>]");
+    eprintln!("       chip-9 monitor <rom> [<script>]");
+    eprintln!("       chip-9 tui <rom>");
+    eprintln!("       chip-9 vip <monitor> <interpreter> <rom> <frames>");
+    process::exit(1);
+}
+
+fn read_rom(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    })
+}
+
+// reads a file there's only `size` bytes of room for
+fn read_sized(path: &str, size: usize) -> Vec<u8> {
+    let bytes = read_rom(path);
+    if bytes.len() > size {
+        eprintln!(
+            "{}: {} bytes, the most that fits is {}",
+            path,
+            bytes.len(),
+            size
+        );
+        process::exit(1);
+    }
+    bytes
+}
+
+fn load_rom(cpu: &mut Cpu, path: &str) {
+    let rom = read_sized(path, cpu.rom_capacity());
+    cpu.load_rom(&rom);
+}
+
+// where SUPER-CHIP flags outlive runs by default: the user's data directory,
+// following the XDG base directories, or the roaming application data on
+// Windows. Without any of those they only last the run.
+fn flags_dir() -> Option<PathBuf> {
+    let data = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(data.join("chip-9").join("flags"))
+}
+
+fn parse_platform(name: &str) -> Platform {
+    name.parse().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    })
+}
+
+fn parse_speed(speed: &str) -> f32 {
+    match speed {
+        "unlimited" => f32::INFINITY,
+        _ => speed.parse().unwrap_or_else(|_| usage()),
+    }
+}
+
+// Commands typed on stdin while a ROM is running:
+//   p        pause / resume
+//   n        advance one frame
+//   + / -    double / halve the speed
+//   s <x>    set the speed multiplier
+//   d <key>  press a key (hex), u <key> releases it
+//   d2 <key> press a key of the second CHIP-8X keypad, u2 <key> releases it
+//   i <byte> put a byte (hex) on the CHIP-8X input port
+//   q        quit
+fn run(path: &str, options: &[&str]) {
+    let mut cpu = Cpu::new();
+    if let Some(dir) = flags_dir() {
+        cpu.set_flag_store(Box::new(FileFlags::new(dir)));
+    }
+    let mut frame_limit: Option<u32> = None;
+    let mut font = None;
+    let mut cheats = CheatList::new();
+    let mut triggers = TriggerSet::default();
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        if *option == "--vip-timing" {
+            cpu.enable_vip_timing();
+            continue;
+        }
+        match (*option, options.next()) {
+            ("--speed", Some(speed)) => cpu.set_speed(parse_speed(speed)),
+            ("--platform", Some(name)) => cpu.set_platform(parse_platform(name)),
+            // a built-in font, or one read from a file when it looks like a
+            // path
+            ("--font", Some(name)) => {
+                font = Some(match name.parse::<BuiltinFont>() {
+                    Ok(builtin) => builtin.font(),
+                    Err(_) if name.contains(path::is_separator) || Path::new(name).exists() => {
+                        Font::from_bytes(&read_rom(name)).unwrap_or_else(|e| {
+                            eprintln!("{}: {}", name, e);
+                            process::exit(1);
+                        })
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        process::exit(1);
+                    }
+                })
+            }
+            // SUPER-CHIP flags kept somewhere other than the user's data
+            // directory
+            ("--flags", Some(dir)) => cpu.set_flag_store(Box::new(FileFlags::new(dir))),
+            ("--frames", Some(frames)) => {
+                frame_limit = Some(frames.parse().unwrap_or_else(|_| usage()))
+            }
+            ("--cheats", Some(path)) => {
+                let text = fs::read_to_string(path).unwrap_or_else(|e| {
+                    eprintln!("{}: {}", path, e);
+                    process::exit(1);
+                });
+                cheats = CheatList::parse(&text).unwrap_or_else(|e| {
+                    eprintln!("{}: {}", path, e);
+                    process::exit(1);
+                });
+            }
+            ("--triggers", Some(path)) => {
+                let text = fs::read_to_string(path).unwrap_or_else(|e| {
+                    eprintln!("{}: {}", path, e);
+                    process::exit(1);
+                });
+                triggers = TriggerSet::parse(&text).unwrap_or_else(|e| {
+                    eprintln!("{}: {}", path, e);
+                    process::exit(1);
+                });
+            }
+            _ => usage(),
+        }
+    }
+
+    // after the platform, which brings its own
+    if let Some(font) = font {
+        cpu.set_font(font);
+    }
+    load_rom(&mut cpu, path);
+    cheats.apply_patches(&mut cpu).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
+    let (commands, input) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            match line {
+                Ok(line) => {
+                    if commands.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+
+    loop {
+        for command in input.try_iter() {
+            let words: Vec<&str> = command.split_whitespace().collect();
+            match words.as_slice() {
+                ["p"] if cpu.is_paused() => cpu.resume(),
+                ["p"] => cpu.pause(),
+                ["n"] => run_frame(&mut cpu, &cheats, &mut triggers),
+                ["+"] => cpu.set_speed(cpu.speed() * 2.0),
+                ["-"] => cpu.slow_down(),
+                ["s", speed] => cpu.set_speed(parse_speed(speed)),
+                ["d", key] | ["u", key] => match u8::from_str_radix(key, 16) {
+                    Ok(key) if key < 16 && words[0] == "d" => cpu.key_down(key),
+                    Ok(key) if key < 16 => cpu.key_up(key),
+                    _ => eprintln!("unknown key: {}", key),
+                },
+                ["d2", key] | ["u2", key] => match u8::from_str_radix(key, 16) {
+                    Ok(key) if key < 16 && words[0] == "d2" => cpu.second_key_down(key),
+                    Ok(key) if key < 16 => cpu.second_key_up(key),
+                    _ => eprintln!("unknown key: {}", key),
+                },
+                ["i", byte] => match u8::from_str_radix(byte, 16) {
+                    Ok(byte) => cpu.set_port_input(byte),
+                    Err(_) => eprintln!("not a hex byte: {}", byte),
+                },
+                ["q"] => return,
+                _ => eprintln!("unknown command: {}", command),
+            }
+        }
+
+        // freezes and triggers see every emulated frame, however many a tick
+        // runs
+        let mut due = cpu.frames_due();
+        if let Some(limit) = frame_limit {
+            // stop on the frame asked for even at unlimited speed
+            due = due.min(limit.saturating_sub(cpu.frame_count()));
+        }
+        for _ in 0..due {
+            run_frame(&mut cpu, &cheats, &mut triggers);
+        }
+
+        if let Some(limit) = frame_limit {
+            if cpu.frame_count() >= limit {
+                print!("{}", cpu.render_screen());
+                return;
+            }
+        } else {
+            // clear the terminal and draw the screen from the top left
+            print!("\x1B[2J\x1B[H{}", cpu.render_screen());
+            println!(
+                "frame {}  speed {}x{}",
+                cpu.frame_count(),
+                cpu.speed(),
+                if cpu.is_paused() { "  (paused)" } else { "" }
+            );
+        }
+
+        if cpu.speed().is_finite() {
+            thread::sleep(HOST_FRAME);
+        }
+    }
+}
+
+// Runs one frame, even while paused, then rewrites the freezes and reports
+// the triggers that fired. The cheats were checked against the memory size
+// along with the patches.
+fn run_frame(cpu: &mut Cpu, cheats: &CheatList, triggers: &mut TriggerSet) {
+    cpu.run_frame();
+    cheats.apply_frame(cpu).ok();
+    for event in triggers.evaluate(cpu) {
+        eprintln!("frame {}: {}", event.frame, event.name);
+    }
+}
+
+// Traces the first instructions of a ROM to stdout, ticking the timers every
+// frame's worth of instructions like `run_frame` does.
+fn trace(path: &str, count: &str) {
+    let count: u32 = count.parse().unwrap_or_else(|_| usage());
+    let mut cpu = Cpu::new();
+    load_rom(&mut cpu, path);
+
+    let stdout = io::stdout();
+    let mut tracer = Tracer::new(stdout.lock());
+    for executed in 1..=count {
+        if let Err(e) = tracer.step(&mut cpu) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        if executed % 10 == 0 {
+            cpu.decrement_timers();
+        }
+    }
+}
+
+fn profile(path: &str, frames: &str) {
+    let frames: u32 = frames.parse().unwrap_or_else(|_| usage());
+    let mut cpu = Cpu::new();
+    load_rom(&mut cpu, path);
+    cpu.enable_profiling();
 
-    memory[0] = 0xA2;
-    memory[1] = 0xF0;
-    let k1 = memory[0] as u16;
-    let k2 = memory[1] as u16;
-    let kk = k1 << 8 | k2;
-    //println!("Hello, world! {}::{}::{}", memory[0], memory[1], kk);
-    println!(
-        "Hello, world! {:#X}::{:#X}::{:#X}",
-        memory[0], memory[1], kk
-    );
+    for _ in 0..frames {
+        cpu.run_frame();
+    }
+    if let Some(fault) = cpu.fault() {
+        eprintln!("{}", fault);
+    }
+    print!("{}", cpu.profile_report(10).unwrap_or_default());
+}
+
+fn coverage(path: &str, frames: &str, output: Option<&str>) {
+    let frames: u32 = frames.parse().unwrap_or_else(|_| usage());
+    let mut cpu = Cpu::new();
+    load_rom(&mut cpu, path);
+    cpu.enable_coverage();
+
+    for _ in 0..frames {
+        cpu.run_frame();
+    }
+    if let Some(fault) = cpu.fault() {
+        eprintln!("{}", fault);
+    }
+    print!("{}", cpu.coverage_report().unwrap_or_default());
+
+    if let (Some(output), Some(coverage)) = (output, cpu.coverage()) {
+        let (start, end) = cpu.rom_range();
+        if let Err(e) = fs::write(output, coverage.to_text(start, end)) {
+            eprintln!("{}: {}", output, e);
+            process::exit(1);
+        }
+    }
+}
+
+// Prints the control flow of a ROM as a listing, or as a Graphviz graph.
+fn analyze(path: &str, options: &[&str]) {
+    let rom = read_rom(path);
+    let mut platform = Platform::Legacy;
+    let mut dot = false;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        if *option == "--dot" {
+            dot = true;
+            continue;
+        }
+        match (*option, options.next()) {
+            ("--platform", Some(name)) => platform = parse_platform(name),
+            _ => usage(),
+        }
+    }
+    let analysis = Analysis::new(&rom, platform);
+    if dot {
+        print!("{}", analysis.to_dot());
+    } else {
+        print!("{}", analysis.listing(&rom));
+    }
+}
 
-    let a1: u8 = 11;
-    let a2: u8 = 12;
+// Checks the cached interpreter against the reference one, instruction by
+// instruction, on the given platform and with CXNN seeded alike.
+fn difftest(path: &str, frames: &str, options: &[&str]) {
+    let frames: u32 = frames.parse().unwrap_or_else(|_| usage());
+    let (script, options) = match options {
+        [script, options @ ..] if !script.starts_with("--") => (Some(*script), options),
+        _ => (None, options),
+    };
+    let mut interpreter = CpuEngine::interpreter();
+    let mut cached = CpuEngine::cached();
+    let mut seed = 0;
+    for option in options.chunks(2) {
+        match option {
+            ["--platform", name] => {
+                let platform = parse_platform(name);
+                interpreter.cpu.set_platform(platform);
+                cached.cpu.set_platform(platform);
+            }
+            ["--seed", value] => seed = value.parse().unwrap_or_else(|_| usage()),
+            _ => usage(),
+        }
+    }
+    let script = match script {
+        Some(script) => {
+            let text = fs::read_to_string(script).unwrap_or_else(|e| {
+                eprintln!("{}: {}", script, e);
+                process::exit(1);
+            });
+            Script::parse(&text).unwrap_or_else(|e| {
+                eprintln!("{}: {}", script, e);
+                process::exit(1);
+            })
+        }
+        None => Script::default(),
+    };
 
-    let (aa, overflow) = a1.overflowing_sub(a2);
+    match difftest::compare(
+        &mut interpreter,
+        &mut cached,
+        &read_rom(path),
+        &script,
+        frames,
+        seed,
+    ) {
+        Ok(executed) => println!("no divergence in {} instructions", executed),
+        Err(divergence) => {
+            print!("{}", divergence);
+            process::exit(1);
+        }
+    }
+}
 
-    println!("{}::{}", aa, overflow);
+// Serves a single GDB remote protocol session on localhost.
+fn gdb(path: &str, port: &str) {
+    let port: u16 = port.parse().unwrap_or_else(|_| usage());
+    let mut cpu = Cpu::new();
+    load_rom(&mut cpu, path);
 
-    let mut rng = rand::thread_rng();
-    let number = rng.gen_range(1, 7);
-    println!("{}", number);
+    eprintln!("waiting for gdb on 127.0.0.1:{}", port);
+    if let Err(e) = gdbstub::serve(&mut cpu, ("127.0.0.1", port)) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+// Runs a script of monitor commands, or prompts for them on stdin.
+fn monitor(path: &str, script: Option<&str>) {
+    let mut cpu = Cpu::new();
+    load_rom(&mut cpu, path);
+    let mut monitor = Monitor::new(cpu);
+    let stdout = io::stdout();
+
+    if let Some(script) = script {
+        let script = fs::read_to_string(script).unwrap_or_else(|e| {
+            eprintln!("{}: {}", script, e);
+            process::exit(1);
+        });
+        if let Err(e) = monitor.run_script(&script, &mut stdout.lock()) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    while !monitor.is_done() {
+        print!("> ");
+        stdout.lock().flush().unwrap();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        match monitor.execute(&line) {
+            Ok(output) => print!("{}", output),
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+}
+
+// Puts the terminal in raw mode on the alternate screen for as long as it
+// lives, through `stty` to stay free of terminal crates.
+struct RawTerminal {
+    saved: String,
+}
+
+impl RawTerminal {
+    fn enter() -> io::Result<RawTerminal> {
+        let saved = Command::new("stty")
+            .arg("-g")
+            .stdin(Stdio::inherit())
+            .output()?;
+        let saved = String::from_utf8_lossy(&saved.stdout).trim().to_string();
+        Command::new("stty")
+            .args(["raw", "-echo"])
+            .stdin(Stdio::inherit())
+            .status()?;
+        print!("\x1B[?1049h\x1B[?25l");
+        Ok(RawTerminal { saved })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        print!("\x1B[?25h\x1B[?1049l");
+        io::stdout().flush().ok();
+        Command::new("stty")
+            .arg(&self.saved)
+            .stdin(Stdio::inherit())
+            .status()
+            .ok();
+    }
+}
+
+fn tui(path: &str) {
+    let mut cpu = Cpu::new();
+    load_rom(&mut cpu, path);
+    let mut tui = Tui::new(Monitor::new(cpu));
+
+    let terminal = RawTerminal::enter().unwrap_or_else(|e| {
+        eprintln!("failed to set up the terminal: {}", e);
+        process::exit(1);
+    });
+    let (keys, input) = mpsc::channel();
+    thread::spawn(move || {
+        for key in io::stdin().lock().bytes() {
+            match key {
+                Ok(key) => {
+                    if keys.send(key).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+
+    let stdout = io::stdout();
+    while !tui.is_done() {
+        for key in input.try_iter() {
+            tui.handle_key(key);
+        }
+        tui.tick();
+
+        // raw mode doesn't turn \n into \r\n
+        let mut out = stdout.lock();
+        write!(out, "\x1B[H\x1B[2J{}", tui.render().replace('\n', "\r\n")).unwrap();
+        out.flush().unwrap();
+        thread::sleep(HOST_FRAME);
+    }
+    drop(terminal);
+}
+
+// Runs a ROM on the COSMAC VIP model and prints where it ended up, as a
+// reference for `run`.
+fn vip(monitor: &str, interpreter: &str, path: &str, frames: &str) {
+    let frames: u32 = frames.parse().unwrap_or_else(|_| usage());
+    let monitor = read_sized(monitor, vip::MONITOR_SIZE);
+    let interpreter = read_sized(interpreter, vip::INTERPRETER_SIZE);
+    let rom = read_sized(path, vip::MAX_ROM_SIZE);
+    let mut vip = Vip::new(&monitor, &interpreter);
+    vip.load_rom(&rom);
+
+    for _ in 0..frames {
+        vip.run_frame();
+    }
+    for row in vip.screen().chunks(64) {
+        let row: String = row
+            .iter()
+            .map(|pixel| if *pixel == 1 { '#' } else { ' ' })
+            .collect();
+        println!("{}", row);
+    }
+    println!("PC {:03X}  I {:03X}  V {:02X?}", vip.pc(), vip.i(), vip.v());
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
 
-    println!("{}", false as u8);
+    match args.as_slice() {
+        ["run", rom, options @ ..] => run(rom, options),
+        ["trace", rom, count] => trace(rom, count),
+        ["profile", rom, frames] => profile(rom, frames),
+        ["coverage", rom, frames] => coverage(rom, frames, None),
+        ["coverage", rom, frames, output] => coverage(rom, frames, Some(output)),
+        ["analyze", rom, options @ ..] => analyze(rom, options),
+        ["recompile", rom] => print!("{}", recompiler::translate(&read_rom(rom))),
+        ["difftest", rom, frames, options @ ..] => difftest(rom, frames, options),
+        ["gdb", rom] => gdb(rom, "1234"),
+        ["gdb", rom, port] => gdb(rom, port),
+        ["monitor", rom] => monitor(rom, None),
+        ["monitor", rom, script] => monitor(rom, Some(script)),
+        ["tui", rom] => tui(rom),
+        ["vip", monitor, interpreter, rom, frames] => vip(monitor, interpreter, rom, frames),
+        _ => usage(),
+    }
 }
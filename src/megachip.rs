@@ -0,0 +1,198 @@
+/// Display size in MegaChip mode.
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 192;
+
+// rate, 16 bits, length, 24 bits, and a reserved byte before the samples
+const SAMPLE_HEADER: usize = 6;
+
+/// How sprite pixels are mixed with what's on the screen, set by 080N.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Blend {
+    #[default]
+    Normal,
+    Quarter,
+    Half,
+    Add,
+    Multiply,
+}
+
+impl Blend {
+    /// The mode 080N selects, normal for anything past 4.
+    pub fn from_mode(n: u8) -> Blend {
+        match n {
+            1 => Blend::Quarter,
+            2 => Blend::Half,
+            3 => Blend::Add,
+            4 => Blend::Multiply,
+            _ => Blend::Normal,
+        }
+    }
+
+    /// The 080N mode selecting this blend.
+    pub fn mode(self) -> u8 {
+        match self {
+            Blend::Normal => 0,
+            Blend::Quarter => 1,
+            Blend::Half => 2,
+            Blend::Add => 3,
+            Blend::Multiply => 4,
+        }
+    }
+
+    /// Mixes `source`, RGBA, into `target`, RGB. The alpha of the palette
+    /// entry scales how much of it shows in every mode.
+    pub fn mix(self, target: [u8; 3], source: [u8; 4]) -> [u8; 3] {
+        let opacity = source[3] as u32;
+        let mut mixed = [0; 3];
+        for c in 0..3 {
+            let (t, s) = (target[c] as u32, source[c] as u32);
+            let lerp = |weight: u32| (t * (255 - weight) + s * weight) / 255;
+            mixed[c] = match self {
+                Blend::Normal => lerp(opacity),
+                Blend::Quarter => lerp(opacity / 4),
+                Blend::Half => lerp(opacity / 2),
+                Blend::Add => (t + s * opacity / 255).min(255),
+                Blend::Multiply => (t * (255 - opacity) + t * s / 255 * opacity) / 255,
+            } as u8;
+        }
+        mixed
+    }
+}
+
+/// What MegaChip mode adds to the display: the 256 colour palette, the
+/// sprite size, blending and the composited picture.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MegaDisplay {
+    /// RGBA, index 0 being transparent black.
+    pub palette: [[u8; 4]; 256],
+    pub sprite_width: usize,
+    pub sprite_height: usize,
+    pub blend: Blend,
+    /// Fades the whole picture, 255 showing it as is.
+    pub alpha: u8,
+    /// Drawing over a pixel of this index sets VF, 0 meaning none.
+    pub collision: u8,
+    // the screen after blending, RGB
+    pub(crate) picture: Vec<[u8; 3]>,
+}
+
+impl MegaDisplay {
+    pub fn new() -> MegaDisplay {
+        let mut palette = [[0xFF; 4]; 256];
+        palette[0] = [0; 4];
+        MegaDisplay {
+            palette,
+            sprite_width: 0,
+            sprite_height: 0,
+            blend: Blend::Normal,
+            alpha: 0xFF,
+            collision: 0,
+            picture: vec![[0; 3]; WIDTH * HEIGHT],
+        }
+    }
+
+    /// Loads `count` colours from `data`, 4 bytes of ARGB each, into the
+    /// palette from index 1.
+    pub fn load_palette(&mut self, data: &[u8], count: usize) {
+        for (n, argb) in data.chunks(4).take(count).enumerate() {
+            self.palette[n + 1] = [argb[1], argb[2], argb[3], argb[0]];
+        }
+    }
+}
+
+impl Default for MegaDisplay {
+    fn default() -> MegaDisplay {
+        MegaDisplay::new()
+    }
+}
+
+/// A digitised sound played by 060N: unsigned 8-bit samples at a rate of
+/// their own, once or looping.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub(crate) rate: u32,
+    pub(crate) data: Vec<u8>,
+    pub(crate) looping: bool,
+    // position in samples, fractional when resampling
+    pub(crate) position: f64,
+}
+
+impl Sample {
+    /// Bytes taken up by the sound starting at `memory`, the header
+    /// included, or None when the header doesn't fit.
+    pub fn size(memory: &[u8]) -> Option<usize> {
+        let header = memory.get(..SAMPLE_HEADER)?;
+        let length = (header[2] as usize) << 16 | (header[3] as usize) << 8 | header[4] as usize;
+        Some(SAMPLE_HEADER + length)
+    }
+
+    /// The sound at the start of `memory`, which has to hold all of it.
+    pub fn parse(memory: &[u8], looping: bool) -> Sample {
+        let len = Sample::size(memory).expect("a sample header");
+        Sample {
+            rate: (memory[0] as u32) << 8 | memory[1] as u32,
+            data: memory[SAMPLE_HEADER..len].to_vec(),
+            looping,
+            position: 0.0,
+        }
+    }
+
+    /// Resamples the sound to `rate` into `out`, from -1 to 1, padding with
+    /// silence once it ends. Returns whether it still plays.
+    pub fn fill(&mut self, out: &mut [f32], rate: u32) -> bool {
+        let step = self.rate as f64 / rate as f64;
+        for value in out.iter_mut() {
+            let mut index = self.position as usize;
+            if index >= self.data.len() && self.looping && !self.data.is_empty() {
+                self.position %= self.data.len() as f64;
+                index = self.position as usize;
+            }
+            *value = match self.data.get(index) {
+                Some(&sample) => {
+                    self.position += step;
+                    (sample as f32 - 128.0) / 128.0
+                }
+                None => 0.0,
+            };
+        }
+        self.looping || (self.position as usize) < self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Blend, Sample};
+
+    #[test]
+    fn test_blend() {
+        let red = [0xFF, 0, 0, 0xFF];
+        assert_eq!(Blend::Normal.mix([0, 0, 0xFF], red), [0xFF, 0, 0]);
+        assert_eq!(Blend::Half.mix([0, 0, 0xFF], red), [0x7F, 0, 0x80]);
+        assert_eq!(Blend::Add.mix([0x80, 0x10, 0], red), [0xFF, 0x10, 0]);
+        assert_eq!(Blend::Multiply.mix([0x80, 0x10, 0], red), [0x80, 0, 0]);
+        // a transparent palette entry leaves the screen alone
+        assert_eq!(Blend::Normal.mix([1, 2, 3], [0xFF, 0, 0, 0]), [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_sample_playback() {
+        // 8000 Hz, 3 samples
+        let memory = [0x1F, 0x40, 0, 0, 3, 0, 0x80, 0xFF, 0x00, 0x12];
+        assert_eq!(Sample::size(&memory), Some(9));
+
+        let mut sample = Sample::parse(&memory, false);
+        let mut out = [1.0; 8];
+        // played at twice its rate, every sample shows up twice
+        assert!(!sample.fill(&mut out, 16000));
+        assert_eq!(
+            out[..6],
+            [0.0, 0.0, 127.0 / 128.0, 127.0 / 128.0, -1.0, -1.0]
+        );
+        assert_eq!(out[6..], [0.0, 0.0]);
+
+        let mut looping = Sample::parse(&memory, true);
+        assert!(looping.fill(&mut out, 8000));
+        assert_eq!(out[3], 0.0);
+        assert_eq!(out[4], 127.0 / 128.0);
+    }
+}
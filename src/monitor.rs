@@ -0,0 +1,603 @@
+use crate::cheat::{
+    Cheat, CheatError, CheatKind, CheatList, Comparison, Location, Search, UnmappedLocation,
+};
+use crate::cpu::{Cpu, StateError, INSTRUCTIONS_PER_FRAME};
+use crate::debugger::{Debugger, Stop};
+use std::error::Error;
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::io::{self, Write};
+
+// instructions `continue` runs when no limit is given
+const DEFAULT_CONTINUE: u64 = 1_000_000;
+// search candidates listed after each search
+const SEARCH_LISTING: usize = 16;
+
+const HELP: &str = "\
+step [<count>]              execute instructions, ignoring breakpoints
+continue [<count>]          run until a breakpoint, watchpoint or fault
+frame [<count>]             run a frame's worth of instructions
+regs                        show the registers and stack
+set <register> <value>      set V0..VF, I, PC, SP or DT
+mem <address> [<length>]    dump memory
+poke <address> <byte>...    write memory
+disasm [<address>] [<count>]
+break [<address>]           add a breakpoint, or list them
+delete <address>            remove a breakpoint
+watch [<address>]           stop when a byte changes, or list watchpoints
+unwatch <address>           remove a watchpoint
+key down|up <key>           press or release a key
+screen                      show the display
+save <file>                 write a snapshot of the machine
+load <file>                 restore a snapshot
+search                      start a search over memory and V0..VF
+search = <byte>             keep candidates equal to a value
+search changed|unchanged|increased|decreased
+freeze <location> <byte> [<name>]
+patch <location> <byte> [<name>]
+unfreeze <location>         drop the cheats on a register or address
+cheats [save|load <file>]   list, save or load cheats
+quit
+Addresses and bytes are hex, `pc` and `i` standing for the registers. Counts
+are decimal unless prefixed with 0x. Locations are V0..VF or an address.
+Frozen values are written again after every command running the program.
+";
+
+#[derive(Debug)]
+pub enum MonitorError {
+    UnknownCommand(String),
+    // the usage of the command that got bad arguments
+    Usage(&'static str),
+    Io(io::Error),
+    State(StateError),
+    Cheat(CheatError),
+    Unmapped(UnmappedLocation),
+    // a search filter without `search` first
+    NoSearch,
+}
+
+impl fmt::Display for MonitorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MonitorError::UnknownCommand(command) => {
+                write!(f, "unknown command: {} (try help)", command)
+            }
+            MonitorError::Usage(usage) => write!(f, "usage: {}", usage),
+            MonitorError::Io(error) => error.fmt(f),
+            MonitorError::State(error) => error.fmt(f),
+            MonitorError::Cheat(error) => error.fmt(f),
+            MonitorError::Unmapped(error) => error.fmt(f),
+            MonitorError::NoSearch => write!(f, "no search started"),
+        }
+    }
+}
+
+impl Error for MonitorError {}
+
+impl From<io::Error> for MonitorError {
+    fn from(error: io::Error) -> MonitorError {
+        MonitorError::Io(error)
+    }
+}
+
+impl From<StateError> for MonitorError {
+    fn from(error: StateError) -> MonitorError {
+        MonitorError::State(error)
+    }
+}
+
+impl From<CheatError> for MonitorError {
+    fn from(error: CheatError) -> MonitorError {
+        MonitorError::Cheat(error)
+    }
+}
+
+impl From<UnmappedLocation> for MonitorError {
+    fn from(error: UnmappedLocation) -> MonitorError {
+        MonitorError::Unmapped(error)
+    }
+}
+
+/// Machine monitor driving a `Cpu` one command line at a time, as typed at
+/// an interactive prompt or read from a script.
+pub struct Monitor {
+    pub cpu: Cpu,
+    debugger: Debugger,
+    pub cheats: CheatList,
+    search: Option<Search>,
+    done: bool,
+}
+
+impl Monitor {
+    pub fn new(cpu: Cpu) -> Monitor {
+        Monitor {
+            cpu,
+            debugger: Debugger::new(),
+            cheats: CheatList::new(),
+            search: None,
+            done: false,
+        }
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    /// Runs up to `limit` instructions, stopping at breakpoints. Freezes
+    /// are rewritten at the end of every frame.
+    pub fn run(&mut self, limit: u64) -> Stop {
+        let cheats = &self.cheats;
+        // every cheat was checked against the machine when it was added
+        self.debugger.run_frames(&mut self.cpu, limit, |cpu| {
+            cheats.apply_frame(cpu).ok();
+        })
+    }
+
+    /// Whether `quit` was executed.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Executes one command line, returning what it printed. Empty lines and
+    /// `#` comments do nothing.
+    pub fn execute(&mut self, line: &str) -> Result<String, MonitorError> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.first() {
+            Some(word) if !word.starts_with('#') => (),
+            _ => return Ok(String::new()),
+        }
+
+        let mut out = String::new();
+        match words.as_slice() {
+            ["step"] | ["s"] => self.resume(&mut out, 1, true),
+            ["step", count] | ["s", count] => {
+                let count = parse_count(count).ok_or(MonitorError::Usage("step [<count>]"))?;
+                self.resume(&mut out, count, true)
+            }
+            ["continue"] | ["c"] => self.resume(&mut out, DEFAULT_CONTINUE, false),
+            ["continue", count] | ["c", count] => {
+                let count = parse_count(count).ok_or(MonitorError::Usage("continue [<count>]"))?;
+                self.resume(&mut out, count, false)
+            }
+            ["frame"] => self.resume(&mut out, INSTRUCTIONS_PER_FRAME as u64, false),
+            ["frame", count] => {
+                let count = parse_count(count).ok_or(MonitorError::Usage("frame [<count>]"))?;
+                self.resume(&mut out, count * INSTRUCTIONS_PER_FRAME as u64, false)
+            }
+            ["regs"] | ["r"] => self.registers(&mut out),
+            ["set", register, value] => self
+                .set_register(register, value)
+                .ok_or(MonitorError::Usage("set <register> <value>"))?,
+            ["mem", address] => self.dump(&mut out, address, "16")?,
+            ["mem", address, len] => self.dump(&mut out, address, len)?,
+            ["poke", address, bytes @ ..] if !bytes.is_empty() => {
+                self.poke(address, bytes)?;
+            }
+            ["disasm"] => self.disassemble(&mut out, "pc", "10")?,
+            ["disasm", address] => self.disassemble(&mut out, address, "10")?,
+            ["disasm", address, count] => self.disassemble(&mut out, address, count)?,
+            ["break"] => {
+                for address in self.debugger.breakpoints() {
+                    writeln!(out, "{:#05X}", address).unwrap();
+                }
+            }
+            ["break", address] => {
+                let address = self
+                    .address(address)
+                    .ok_or(MonitorError::Usage("break [<address>]"))?;
+                self.debugger.add_breakpoint(address);
+            }
+            ["delete", address] => {
+                let address = self
+                    .address(address)
+                    .ok_or(MonitorError::Usage("delete <address>"))?;
+                self.debugger.remove_breakpoint(address);
+            }
+            ["watch"] => {
+                for address in self.debugger.watchpoints() {
+                    writeln!(out, "{:#05X}", address).unwrap();
+                }
+            }
+            ["watch", address] => {
+                let address = self
+                    .address(address)
+                    .ok_or(MonitorError::Usage("watch [<address>]"))?;
+                self.debugger.add_watchpoint(address);
+            }
+            ["unwatch", address] => {
+                let address = self
+                    .address(address)
+                    .ok_or(MonitorError::Usage("unwatch <address>"))?;
+                self.debugger.remove_watchpoint(address);
+            }
+            ["key", action, key] => {
+                let key = match u8::from_str_radix(key, 16) {
+                    Ok(key) if key < 16 => key,
+                    _ => return Err(MonitorError::Usage("key down|up <key>")),
+                };
+                match *action {
+                    "down" => self.cpu.key_down(key),
+                    "up" => self.cpu.key_up(key),
+                    _ => return Err(MonitorError::Usage("key down|up <key>")),
+                }
+            }
+            ["screen"] => out.push_str(&self.cpu.render_screen()),
+            ["save", path] => fs::write(path, self.cpu.save_state())?,
+            ["load", path] => {
+                self.cpu.load_state(&fs::read(path)?)?;
+                self.current(&mut out);
+            }
+            ["search"] => {
+                let search = Search::new(&self.cpu);
+                writeln!(out, "{} candidates", search.count()).unwrap();
+                self.search = Some(search);
+            }
+            ["search", "=", value] => {
+                let value = parse_hex(value)
+                    .filter(|value| *value <= 0xFF)
+                    .ok_or(MonitorError::Usage("search = <byte>"))?;
+                self.search_filter(&mut out, Comparison::Equal(value as u8))?;
+            }
+            ["search", comparison] => {
+                let comparison = match *comparison {
+                    "changed" => Comparison::Changed,
+                    "unchanged" => Comparison::Unchanged,
+                    "increased" => Comparison::Increased,
+                    "decreased" => Comparison::Decreased,
+                    _ => {
+                        return Err(MonitorError::Usage(
+                            "search changed|unchanged|increased|decreased",
+                        ))
+                    }
+                };
+                self.search_filter(&mut out, comparison)?;
+            }
+            [kind @ "freeze", location, value, name @ ..]
+            | [kind @ "patch", location, value, name @ ..] => {
+                let cheat = match (location.parse(), parse_hex(value)) {
+                    (Ok(location), Some(value)) if value <= 0xFF => Cheat {
+                        kind: if *kind == "freeze" {
+                            CheatKind::Freeze
+                        } else {
+                            CheatKind::Patch
+                        },
+                        location,
+                        value: value as u8,
+                        name: name.join(" "),
+                    },
+                    _ => {
+                        return Err(MonitorError::Usage(
+                            "freeze|patch <location> <byte> [<name>]",
+                        ))
+                    }
+                };
+                cheat.location.check(&self.cpu)?;
+                cheat.location.write(&mut self.cpu, cheat.value);
+                self.cheats.cheats.push(cheat);
+            }
+            ["unfreeze", location] => {
+                let location: Location = location
+                    .parse()
+                    .map_err(|_| MonitorError::Usage("unfreeze <location>"))?;
+                self.cheats
+                    .cheats
+                    .retain(|cheat| cheat.location != location);
+            }
+            ["cheats"] => out.push_str(&self.cheats.to_text()),
+            ["cheats", "save", path] => fs::write(path, self.cheats.to_text())?,
+            ["cheats", "load", path] => {
+                let cheats = CheatList::parse(&fs::read_to_string(path)?)?;
+                cheats.apply_patches(&mut self.cpu)?;
+                cheats.apply_frame(&mut self.cpu)?;
+                self.cheats = cheats;
+            }
+            ["help"] => out.push_str(HELP),
+            ["quit"] | ["q"] => self.done = true,
+            _ => return Err(MonitorError::UnknownCommand(line.trim().to_string())),
+        }
+        Ok(out)
+    }
+
+    /// Executes every line of `script`, echoing each command before its
+    /// output. Errors are reported and the script goes on, until `quit`.
+    pub fn run_script<W: Write>(&mut self, script: &str, out: &mut W) -> io::Result<()> {
+        for line in script.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            writeln!(out, "> {}", line)?;
+            match self.execute(line) {
+                Ok(output) => write!(out, "{}", output)?,
+                Err(error) => writeln!(out, "error: {}", error)?,
+            }
+            if self.done {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn resume(&mut self, out: &mut String, count: u64, single_step: bool) {
+        let cheats = &self.cheats;
+        let freeze = |cpu: &mut Cpu| {
+            cheats.apply_frame(cpu).ok();
+        };
+        let stop = if single_step {
+            let mut stop = Stop::Step;
+            for _ in 0..count {
+                stop = self.debugger.step_frames(&mut self.cpu, freeze);
+                if stop != Stop::Step {
+                    break;
+                }
+            }
+            stop
+        } else {
+            self.debugger.run_frames(&mut self.cpu, count, freeze)
+        };
+
+        match stop {
+            Stop::Step | Stop::Limit => (),
+            stop => writeln!(out, "{}", stop).unwrap(),
+        }
+        self.current(out);
+    }
+
+    fn search_filter(
+        &mut self,
+        out: &mut String,
+        comparison: Comparison,
+    ) -> Result<(), MonitorError> {
+        let search = self.search.as_mut().ok_or(MonitorError::NoSearch)?;
+        search.filter(&self.cpu, comparison);
+        let candidates = search.candidates();
+        writeln!(out, "{} candidates", candidates.len()).unwrap();
+        for (location, value) in candidates.iter().take(SEARCH_LISTING) {
+            writeln!(out, "{} = {:#04X}", location, value).unwrap();
+        }
+        Ok(())
+    }
+
+    // the instruction about to run
+    fn current(&self, out: &mut String) {
+        disassemble_line(out, &self.cpu, self.cpu.pc);
+    }
+
+    fn registers(&self, out: &mut String) {
+        let cpu = &self.cpu;
+        for row in 0..2 {
+            let registers: Vec<String> = (row * 8..row * 8 + 8)
+                .map(|x| format!("V{:X} {:02X}", x, cpu.v[x]))
+                .collect();
+            writeln!(out, "{}", registers.join("  ")).unwrap();
+        }
+        writeln!(
+            out,
+            "I {:04X}  PC {:04X}  SP {:02X}  DT {:02X}",
+            cpu.i, cpu.pc, cpu.sp, cpu.dt
+        )
+        .unwrap();
+        let stack: Vec<String> = cpu.stack[..cpu.sp as usize]
+            .iter()
+            .map(|address| format!("{:#05X}", address))
+            .collect();
+        writeln!(out, "stack [{}]", stack.join(" ")).unwrap();
+    }
+
+    fn set_register(&mut self, register: &str, value: &str) -> Option<()> {
+        let value = parse_hex(value)?;
+        let cpu = &mut self.cpu;
+        match register.to_ascii_lowercase().as_str() {
+            "i" => cpu.i = value,
+            "pc" => cpu.pc = value,
+            "sp" if (value as usize) <= cpu.stack.len() => cpu.sp = value as u8,
+            "dt" => cpu.dt = value as u8,
+            register => {
+                let x = register.strip_prefix('v')?;
+                cpu.v[usize::from_str_radix(x, 16).ok().filter(|x| *x < 16)?] = value as u8;
+            }
+        }
+        Some(())
+    }
+
+    fn dump(&self, out: &mut String, address: &str, len: &str) -> Result<(), MonitorError> {
+        let usage = MonitorError::Usage("mem <address> [<length>]");
+        let (start, len) = match (self.address(address), parse_count(len)) {
+            (Some(start), Some(len)) => (start as usize, len as usize),
+            _ => return Err(usage),
+        };
+        let end = (start + len).min(self.cpu.memory.len());
+        for (row, bytes) in self.cpu.memory[start.min(end)..end].chunks(16).enumerate() {
+            let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            writeln!(out, "{:#05X}  {}", start + row * 16, bytes.join(" ")).unwrap();
+        }
+        Ok(())
+    }
+
+    fn poke(&mut self, address: &str, bytes: &[&str]) -> Result<(), MonitorError> {
+        let usage = || MonitorError::Usage("poke <address> <byte>...");
+        let start = self.address(address).ok_or_else(usage)? as usize;
+        let bytes = bytes
+            .iter()
+            .map(|byte| u8::from_str_radix(byte.trim_start_matches("0x"), 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(usage)?;
+        if start + bytes.len() > self.cpu.memory.len() {
+            return Err(usage());
+        }
+        for (offset, byte) in bytes.into_iter().enumerate() {
+            self.cpu.write_memory(start + offset, byte);
+        }
+        Ok(())
+    }
+
+    fn disassemble(
+        &self,
+        out: &mut String,
+        address: &str,
+        count: &str,
+    ) -> Result<(), MonitorError> {
+        let (start, count) = match (self.address(address), parse_count(count)) {
+            (Some(start), Some(count)) => (start, count),
+            _ => return Err(MonitorError::Usage("disasm [<address>] [<count>]")),
+        };
+        for n in 0..count {
+            let address = start as u64 + n * 2;
+            if address as usize + 1 >= self.cpu.memory.len() {
+                break;
+            }
+            disassemble_line(out, &self.cpu, address as u16);
+        }
+        Ok(())
+    }
+
+    fn address(&self, text: &str) -> Option<u16> {
+        match text.to_ascii_lowercase().as_str() {
+            "pc" => Some(self.cpu.pc),
+            "i" => Some(self.cpu.i),
+            _ => parse_hex(text).filter(|address| (*address as usize) < self.cpu.memory.len()),
+        }
+    }
+}
+
+fn disassemble_line(out: &mut String, cpu: &Cpu, address: u16) {
+    let a = address as usize;
+    match cpu.memory.get(a..a + 2) {
+        Some(bytes) => {
+            let opcode = (bytes[0] as u16) << 8 | bytes[1] as u16;
+            writeln!(
+                out,
+                "{:#05X}  {:04X}  {}",
+                address,
+                opcode,
+                cpu.platform().decode(opcode)
+            )
+            .unwrap()
+        }
+        None => writeln!(out, "{:#05X}  out of memory", address).unwrap(),
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    u16::from_str_radix(text.trim_start_matches("0x"), 16).ok()
+}
+
+fn parse_count(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Monitor, MonitorError};
+    use crate::cpu::Cpu;
+
+    fn monitor() -> Monitor {
+        let mut cpu = Cpu::new();
+        // V0 = 1, V0 += 1, jump 0x202
+        cpu.load_rom(&[0x60, 0x01, 0x70, 0x01, 0x12, 0x02]);
+        Monitor::new(cpu)
+    }
+
+    #[test]
+    fn test_inspect() {
+        let mut monitor = monitor();
+
+        assert_eq!(
+            monitor.execute("mem 0x200 4").unwrap(),
+            "0x200  60 01 70 01\n"
+        );
+        assert_eq!(
+            monitor.execute("disasm pc 2").unwrap(),
+            "0x200  6001  LD V0, 0x01\n0x202  7001  ADD V0, 0x01\n"
+        );
+        monitor.execute("poke 300 ab cd").unwrap();
+        assert_eq!(monitor.execute("mem 300 2").unwrap(), "0x300  AB CD\n");
+        monitor.execute("set v5 7f").unwrap();
+        assert!(monitor.execute("regs").unwrap().contains("V5 7F"));
+        assert!(matches!(
+            monitor.execute("frobnicate"),
+            Err(MonitorError::UnknownCommand(_))
+        ));
+        assert!(matches!(
+            monitor.execute("mem"),
+            Err(MonitorError::UnknownCommand(_))
+        ));
+        assert!(matches!(
+            monitor.execute("mem zz"),
+            Err(MonitorError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_breakpoints_and_stepping() {
+        let mut monitor = monitor();
+
+        assert_eq!(
+            monitor.execute("step").unwrap(),
+            "0x202  7001  ADD V0, 0x01\n"
+        );
+        monitor.execute("break 204").unwrap();
+        assert_eq!(
+            monitor.execute("continue").unwrap(),
+            "breakpoint at 0x204\n0x204  1202  JP 0x202\n"
+        );
+        assert_eq!(monitor.cpu.v[0], 2);
+        assert_eq!(monitor.execute("break").unwrap(), "0x204\n");
+        monitor.execute("delete 204").unwrap();
+        monitor.execute("frame 2").unwrap();
+        assert_eq!(monitor.cpu.cycles(), 22);
+    }
+
+    #[test]
+    fn test_script() {
+        let mut monitor = monitor();
+        let mut out = Vec::new();
+        monitor
+            .run_script("# comment\nstep 2\nbogus\nquit\nstep\n", &mut out)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "> step 2\n0x204  1202  JP 0x202\n\
+             > bogus\nerror: unknown command: bogus (try help)\n\
+             > quit\n"
+        );
+        assert!(monitor.is_done());
+        assert_eq!(monitor.cpu.cycles(), 2);
+    }
+
+    #[test]
+    fn test_cheats() {
+        let mut monitor = monitor();
+
+        monitor.execute("search").unwrap();
+        monitor.execute("step").unwrap();
+        assert_eq!(
+            monitor.execute("search increased").unwrap(),
+            "1 candidates\nV0 = 0x01\n"
+        );
+        monitor.execute("freeze v0 40 stuck").unwrap();
+        assert_eq!(monitor.cpu.v[0], 0x40);
+        // drifts until the frame ends, 10 instructions after the first step
+        monitor.execute("step 8").unwrap();
+        assert_ne!(monitor.cpu.v[0], 0x40);
+        monitor.execute("step").unwrap();
+        assert_eq!(monitor.cpu.v[0], 0x40);
+        monitor.execute("frame 3").unwrap();
+        assert_eq!(monitor.cpu.v[0], 0x40);
+        assert_eq!(monitor.execute("cheats").unwrap(), "freeze V0 0x40 stuck\n");
+        monitor.execute("unfreeze V0").unwrap();
+        assert_eq!(monitor.execute("cheats").unwrap(), "");
+        assert!(matches!(
+            monitor.execute("freeze V0 1ff"),
+            Err(MonitorError::Usage(_))
+        ));
+    }
+}
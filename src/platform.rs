@@ -0,0 +1,541 @@
+use crate::font::BuiltinFont;
+use crate::instruction::Instruction;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// What FX55 and FX65 leave in I.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IndexQuirk {
+    #[default]
+    Unchanged,
+    // I moves past the last register, like on the VIP
+    Increment,
+    // I moves one short of that, an off-by-one of CHIP-48 and SCHIP 1.0
+    IncrementX,
+}
+
+/// Behaviours that differ between interpreters for the same opcodes.
+///
+/// The default is what `Cpu` always did, which matches no single platform
+/// and is kept as `Platform::Legacy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8XY6 and 8XYE shift VY into VX instead of shifting VX in place.
+    pub shift_vy: bool,
+    /// 8XY6 and 8XYE set VF after VX, so that 8FY6 and 8FYE leave the
+    /// shifted out bit in VF rather than the shifted value.
+    pub shift_flag_last: bool,
+    pub load_store: IndexQuirk,
+    /// BNNN jumps to NNN plus VX, X being the first digit of NNN, instead of
+    /// plus V0.
+    pub jump_vx: bool,
+    /// 8XY1, 8XY2 and 8XY3 clear VF.
+    pub vf_reset: bool,
+    /// Sprites are cut off at the edges of the screen instead of wrapping
+    /// around. Where they start always wraps.
+    pub clip: bool,
+}
+
+const VIP_QUIRKS: Quirks = Quirks {
+    shift_vy: true,
+    shift_flag_last: true,
+    load_store: IndexQuirk::Increment,
+    jump_vx: false,
+    vf_reset: true,
+    clip: true,
+};
+
+const CHIP48_QUIRKS: Quirks = Quirks {
+    shift_vy: false,
+    shift_flag_last: true,
+    load_store: IndexQuirk::IncrementX,
+    jump_vx: true,
+    vf_reset: false,
+    clip: true,
+};
+
+const SCHIP_QUIRKS: Quirks = Quirks {
+    load_store: IndexQuirk::Unchanged,
+    ..CHIP48_QUIRKS
+};
+
+const XO_CHIP_QUIRKS: Quirks = Quirks {
+    shift_vy: true,
+    shift_flag_last: true,
+    load_store: IndexQuirk::Increment,
+    jump_vx: false,
+    vf_reset: false,
+    clip: false,
+};
+
+/// The instructions a platform has on top of CHIP-8's.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InstructionSet {
+    /// 0230 clears the 64x64 display of HiRes CHIP-8.
+    pub hires: bool,
+    /// CHIP-8X's colour instructions, second keypad and input/output port.
+    pub chip8x: bool,
+    /// SUPER-CHIP 1.0's 00FD to 00FF, DXY0 drawing 16x16 sprites and FX30.
+    pub schip: bool,
+    /// SUPER-CHIP 1.1's scrolling, 00CN, 00FB and 00FC.
+    pub scroll: bool,
+    /// How many registers FX75 and FX85 can save, 0 when there are no flags.
+    pub flags: usize,
+    /// MegaChip's 0010, 0011 and 01NN to 09NN.
+    pub megachip: bool,
+    /// XO-CHIP's F000 NNNN, 5XY2, 5XY3, FN01 and 00DN. Its sound, F002
+    /// and FX3A, isn't there.
+    pub xochip: bool,
+}
+
+const SCHIP_1_0: InstructionSet = InstructionSet {
+    hires: false,
+    chip8x: false,
+    schip: true,
+    scroll: false,
+    // the HP-48 had 8
+    flags: 8,
+    megachip: false,
+    xochip: false,
+};
+
+const SCHIP_1_1: InstructionSet = InstructionSet {
+    scroll: true,
+    ..SCHIP_1_0
+};
+
+// HiRes CHIP-8 programs start by jumping into the second page of the
+// interpreter, which was loaded along with them
+const HIRES_ENTRY: [u8; 2] = [0x12, 0x60];
+/// Where HiRes CHIP-8 programs start, after the interpreter's second page.
+pub const HIRES_START: u16 = 0x2C0;
+
+/// A CHIP-8 variant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Platform {
+    // what `Cpu::new` always ran: Octo's digits and the default quirks
+    Legacy,
+    Chip8,
+    Chip10,
+    Chip8X,
+    HiRes,
+    Eti660,
+    Dream6800,
+    Chip48,
+    Schip10,
+    Schip11,
+    ModernSchip,
+    XoChip,
+    MegaChip,
+}
+
+/// How a platform lays out the machine.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Descriptor {
+    /// Name frontends select the platform by.
+    pub name: &'static str,
+    pub description: &'static str,
+    pub memory_size: usize,
+    /// Display programs start with. Modes switching to other resolutions
+    /// come with their opcodes.
+    pub width: usize,
+    pub height: usize,
+    /// Where programs are loaded and start.
+    pub load_address: u16,
+    /// Where the hex digits FX29 and FX30 point at are kept.
+    pub font_address: u16,
+    pub font: BuiltinFont,
+    /// Whether the display has a colour layer.
+    pub colour: bool,
+    pub quirks: Quirks,
+    pub instructions: InstructionSet,
+}
+
+impl Platform {
+    pub const ALL: [Platform; 13] = [
+        Platform::Legacy,
+        Platform::Chip8,
+        Platform::Chip10,
+        Platform::Chip8X,
+        Platform::HiRes,
+        Platform::Eti660,
+        Platform::Dream6800,
+        Platform::Chip48,
+        Platform::Schip10,
+        Platform::Schip11,
+        Platform::ModernSchip,
+        Platform::XoChip,
+        Platform::MegaChip,
+    ];
+
+    pub fn descriptor(self) -> Descriptor {
+        let chip8 = Descriptor {
+            name: "chip-8",
+            description: "CHIP-8 on the COSMAC VIP",
+            memory_size: 0x1000,
+            width: 64,
+            height: 32,
+            load_address: 0x200,
+            font_address: 0,
+            font: BuiltinFont::Vip,
+            colour: false,
+            quirks: VIP_QUIRKS,
+            instructions: InstructionSet::default(),
+        };
+        match self {
+            Platform::Legacy => Descriptor {
+                name: "legacy",
+                description: "CHIP-8 the way this interpreter always ran it",
+                font: BuiltinFont::Octo,
+                quirks: Quirks::default(),
+                ..chip8
+            },
+            Platform::Chip8 => chip8,
+            Platform::Chip10 => Descriptor {
+                name: "chip-10",
+                description: "CHIP-10, a 128x64 CHIP-8 for the VIP",
+                width: 128,
+                height: 64,
+                ..chip8
+            },
+            Platform::Chip8X => Descriptor {
+                name: "chip-8x",
+                description: "CHIP-8X, with colour and a second keypad",
+                load_address: 0x300,
+                colour: true,
+                instructions: InstructionSet {
+                    chip8x: true,
+                    ..InstructionSet::default()
+                },
+                ..chip8
+            },
+            Platform::HiRes => Descriptor {
+                name: "hires-chip-8",
+                description: "the two-page 64x64 HiRes CHIP-8",
+                height: 64,
+                instructions: InstructionSet {
+                    hires: true,
+                    ..InstructionSet::default()
+                },
+                ..chip8
+            },
+            // the interpreter and its digits take up the memory below 0x600
+            Platform::Eti660 => Descriptor {
+                name: "eti-660",
+                description: "CHIP-8 on the ETI-660, with a 64x48 display",
+                height: 48,
+                load_address: 0x600,
+                font_address: 0x500,
+                font: BuiltinFont::Eti660,
+                ..chip8
+            },
+            // programs only reach the digits through FX29, so where CHIPOS
+            // keeps them doesn't matter; 0x80 is just clear of the program
+            Platform::Dream6800 => Descriptor {
+                name: "dream-6800",
+                description: "CHIPOS on the DREAM 6800",
+                font_address: 0x80,
+                font: BuiltinFont::Dream6800,
+                ..chip8
+            },
+            // plain CHIP-8 with the quirks SUPER-CHIP inherited
+            Platform::Chip48 => Descriptor {
+                name: "chip-48",
+                description: "CHIP-48 on the HP-48",
+                font: BuiltinFont::Schip,
+                quirks: CHIP48_QUIRKS,
+                ..chip8
+            },
+            Platform::Schip10 => Descriptor {
+                name: "schip-1.0",
+                description: "SUPER-CHIP 1.0 on the HP-48",
+                font: BuiltinFont::Schip,
+                quirks: CHIP48_QUIRKS,
+                instructions: SCHIP_1_0,
+                ..chip8
+            },
+            Platform::Schip11 => Descriptor {
+                name: "schip-1.1",
+                description: "SUPER-CHIP 1.1 on the HP-48",
+                font: BuiltinFont::Schip,
+                quirks: SCHIP_QUIRKS,
+                instructions: SCHIP_1_1,
+                ..chip8
+            },
+            Platform::ModernSchip => Descriptor {
+                name: "schip-modern",
+                description: "SUPER-CHIP as modern interpreters run it",
+                font: BuiltinFont::Schip,
+                quirks: SCHIP_QUIRKS,
+                instructions: SCHIP_1_1,
+                ..chip8
+            },
+            // SUPER-CHIP 1.1 with 64 KiB, four colours and all 16 flags
+            Platform::XoChip => Descriptor {
+                name: "xo-chip",
+                description: "XO-CHIP, from Octo",
+                memory_size: 0x10000,
+                font: BuiltinFont::Octo,
+                quirks: XO_CHIP_QUIRKS,
+                instructions: InstructionSet {
+                    flags: 16,
+                    xochip: true,
+                    ..SCHIP_1_1
+                },
+                ..chip8
+            },
+            Platform::MegaChip => Descriptor {
+                name: "megachip",
+                description: "MegaChip-8, starting out like SUPER-CHIP 1.1",
+                memory_size: 0x1000000,
+                font: BuiltinFont::Schip,
+                quirks: SCHIP_QUIRKS,
+                instructions: InstructionSet {
+                    megachip: true,
+                    ..SCHIP_1_1
+                },
+                ..chip8
+            },
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        self.descriptor().name
+    }
+
+    /// Whether programs for this platform can use `instruction`.
+    pub fn supports(self, instruction: Instruction) -> bool {
+        let set = self.descriptor().instructions;
+        match instruction {
+            Instruction::HiresCls => set.hires,
+            Instruction::Exit
+            | Instruction::LowRes
+            | Instruction::HighRes
+            | Instruction::LargeFont(_) => set.schip,
+            Instruction::ScrollDown(_) | Instruction::ScrollRight | Instruction::ScrollLeft => {
+                set.scroll
+            }
+            Instruction::SaveFlags(x) | Instruction::LoadFlags(x) => x < set.flags,
+            Instruction::Background
+            | Instruction::AddColour(..)
+            | Instruction::ColourZones(..)
+            | Instruction::ColourRows(..)
+            | Instruction::SkipKey2Down(_)
+            | Instruction::SkipKey2Up(_)
+            | Instruction::Output(_)
+            | Instruction::Input(_) => set.chip8x,
+            Instruction::MegaOff
+            | Instruction::MegaOn
+            | Instruction::LongIndex(_)
+            | Instruction::LoadPalette(_)
+            | Instruction::SpriteWidth(_)
+            | Instruction::SpriteHeight(_)
+            | Instruction::ScreenAlpha(_)
+            | Instruction::PlaySample(_)
+            | Instruction::StopSample
+            | Instruction::BlendMode(_)
+            | Instruction::CollisionColour(_) => set.megachip,
+            Instruction::ScrollUp(_)
+            | Instruction::SetLongIndex
+            | Instruction::Planes(_)
+            | Instruction::StoreRange(..)
+            | Instruction::LoadRange(..) => set.xochip,
+            Instruction::Unknown(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Decodes `opcode` the way this platform means it. Most opcodes mean
+    /// the same everywhere, CHIP-8X reuses 5XY1 and BNNN for colour,
+    /// XO-CHIP 5XY2 and 5XY3 for loads and stores and MegaChip takes all of
+    /// 02NN for its palette.
+    pub fn decode(self, opcode: u16) -> Instruction {
+        let instruction = Instruction::decode(opcode);
+        let (x, y, n) = (
+            (opcode >> 8 & 0xF) as usize,
+            (opcode >> 4 & 0xF) as usize,
+            (opcode & 0xF) as u8,
+        );
+        match (self, instruction) {
+            (Platform::Chip8X, Instruction::SkipEqReg(..)) if n == 1 => {
+                Instruction::AddColour(x, y)
+            }
+            (Platform::Chip8X, Instruction::JumpV0(_)) if n == 0 => Instruction::ColourZones(x, y),
+            (Platform::Chip8X, Instruction::JumpV0(_)) => Instruction::ColourRows(x, y, n),
+            (Platform::XoChip, Instruction::SkipEqReg(..)) if n == 2 => {
+                Instruction::StoreRange(x, y)
+            }
+            (Platform::XoChip, Instruction::SkipEqReg(..)) if n == 3 => {
+                Instruction::LoadRange(x, y)
+            }
+            (Platform::MegaChip, Instruction::HiresCls)
+            | (Platform::MegaChip, Instruction::Background) => {
+                Instruction::LoadPalette(opcode as u8)
+            }
+            _ => instruction,
+        }
+    }
+
+    /// The platform a program was written for, when it can be told from the
+    /// program itself. Only HiRes CHIP-8 can, by its `1260` entry jump.
+    pub fn detect(rom: &[u8]) -> Option<Platform> {
+        if rom.starts_with(&HIRES_ENTRY) {
+            Some(Platform::HiRes)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A platform name that isn't in `Platform::ALL`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnknownPlatform(pub String);
+
+impl fmt::Display for UnknownPlatform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = Platform::ALL
+            .iter()
+            .map(|platform| platform.name())
+            .collect();
+        write!(
+            f,
+            "unknown platform {}, expected one of {}",
+            self.0,
+            names.join(", ")
+        )
+    }
+}
+
+impl Error for UnknownPlatform {}
+
+impl FromStr for Platform {
+    type Err = UnknownPlatform;
+
+    /// A platform by name, ignoring case.
+    fn from_str(name: &str) -> Result<Platform, UnknownPlatform> {
+        Platform::ALL
+            .iter()
+            .copied()
+            .find(|platform| platform.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| UnknownPlatform(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IndexQuirk, Platform, Quirks, UnknownPlatform};
+    use crate::font::BuiltinFont;
+    use crate::instruction::Instruction;
+
+    #[test]
+    fn test_names() {
+        for platform in Platform::ALL {
+            assert_eq!(platform.name().parse(), Ok(platform));
+        }
+        assert_eq!("SCHIP-1.1".parse(), Ok(Platform::Schip11));
+        assert_eq!(
+            "chip-9".parse::<Platform>(),
+            Err(UnknownPlatform("chip-9".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_descriptors() {
+        let chip10 = Platform::Chip10.descriptor();
+        assert_eq!((chip10.width, chip10.height), (128, 64));
+        assert_eq!(Platform::Chip8X.descriptor().load_address, 0x300);
+        let eti660 = Platform::Eti660.descriptor();
+        assert_eq!((eti660.height, eti660.load_address), (48, 0x600));
+        assert_eq!(
+            Platform::Dream6800.descriptor().font,
+            BuiltinFont::Dream6800
+        );
+        assert_eq!(Platform::MegaChip.descriptor().memory_size, 0x1000000);
+        let xo_chip = Platform::XoChip.descriptor();
+        assert_eq!(
+            (xo_chip.memory_size, xo_chip.font),
+            (0x10000, BuiltinFont::Octo)
+        );
+        assert!(!xo_chip.quirks.clip);
+        assert_eq!(
+            Platform::Schip10.descriptor().quirks.load_store,
+            IndexQuirk::IncrementX
+        );
+        assert!(Platform::Schip11.descriptor().quirks.jump_vx);
+        // the legacy platform is what `Cpu::new` runs
+        let legacy = Platform::Legacy.descriptor();
+        assert_eq!(
+            (legacy.quirks, legacy.font),
+            (Quirks::default(), BuiltinFont::Octo)
+        );
+    }
+
+    #[test]
+    fn test_instruction_sets() {
+        // CHIP-48 and SUPER-CHIP 1.0 share their quirks, not their opcodes
+        assert!(!Platform::Chip48.supports(Instruction::HighRes));
+        assert!(Platform::Schip10.supports(Instruction::HighRes));
+        assert!(!Platform::Schip10.supports(Instruction::ScrollLeft));
+        assert!(Platform::Schip11.supports(Instruction::ScrollDown(4)));
+        assert!(Platform::MegaChip.supports(Instruction::ScrollRight));
+        assert!(!Platform::Chip8.supports(Instruction::LargeFont(0)));
+
+        assert!(Platform::Schip11.supports(Instruction::SaveFlags(7)));
+        assert!(!Platform::Schip11.supports(Instruction::SaveFlags(8)));
+        assert!(!Platform::Legacy.supports(Instruction::SaveFlags(0)));
+        assert!(Platform::XoChip.supports(Instruction::SaveFlags(15)));
+
+        assert!(Platform::XoChip.supports(Instruction::Planes(3)));
+        assert!(Platform::XoChip.supports(Instruction::HighRes));
+        assert!(!Platform::MegaChip.supports(Instruction::SetLongIndex));
+        assert!(!Platform::Schip11.supports(Instruction::ScrollUp(1)));
+    }
+
+    #[test]
+    fn test_chip8x_decode() {
+        assert_eq!(
+            Platform::Chip8X.decode(0xB120),
+            Instruction::ColourZones(1, 2)
+        );
+        assert_eq!(
+            Platform::Chip8X.decode(0xB125),
+            Instruction::ColourRows(1, 2, 5)
+        );
+        assert_eq!(
+            Platform::Chip8X.decode(0x5121),
+            Instruction::AddColour(1, 2)
+        );
+        assert_eq!(Platform::Chip8.decode(0xB120), Instruction::JumpV0(0x120));
+        assert_eq!(
+            Platform::XoChip.decode(0x5132),
+            Instruction::StoreRange(1, 3)
+        );
+        assert_eq!(
+            Platform::XoChip.decode(0x5313),
+            Instruction::LoadRange(3, 1)
+        );
+        assert_eq!(
+            Platform::XoChip.decode(0x5130),
+            Instruction::SkipEqReg(1, 3)
+        );
+        assert!(!Platform::Chip48.supports(Instruction::Background));
+        assert_eq!(
+            Platform::MegaChip.decode(0x02A0),
+            Instruction::LoadPalette(0xA0)
+        );
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(Platform::detect(&[0x12, 0x60, 0x00]), Some(Platform::HiRes));
+        assert_eq!(Platform::detect(&[0x12, 0x62]), None);
+        assert_eq!(Platform::detect(&[]), None);
+    }
+}
@@ -0,0 +1,224 @@
+use crate::instruction::Instruction;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Call statistics of a 2NNN subroutine.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Routine {
+    pub address: u16,
+    pub calls: u64,
+    // instructions executed from the call up to and including the return
+    pub inclusive_cycles: u64,
+}
+
+/// A loop closed by a backward 1NNN jump.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Loop {
+    pub start: u16,
+    // address of the jump closing the loop
+    pub end: u16,
+    pub iterations: u64,
+    // instructions executed between `start` and `end`
+    pub cycles: u64,
+}
+
+/// Counts what a program spends its time on. Enabled through
+/// `Cpu::enable_profiling`.
+#[derive(Clone)]
+pub struct Profiler {
+    addresses: Vec<u64>,
+    classes: BTreeMap<&'static str, u64>,
+    routines: BTreeMap<u16, Routine>,
+    loops: BTreeMap<(u16, u16), u64>,
+    // routines being executed with the cycle count when they were called
+    calls: Vec<(u16, u64)>,
+}
+
+impl Profiler {
+    /// Counts executions of the first `memory_size` addresses, all PC can
+    /// reach.
+    pub fn new(memory_size: usize) -> Profiler {
+        Profiler {
+            addresses: vec![0; memory_size],
+            classes: BTreeMap::new(),
+            routines: BTreeMap::new(),
+            loops: BTreeMap::new(),
+            calls: Vec::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.addresses
+            .iter_mut()
+            .for_each(|executions| *executions = 0);
+        self.classes.clear();
+        self.routines.clear();
+        self.loops.clear();
+        self.calls.clear();
+    }
+
+    /// Records an instruction executed at `pc`, `cycles` being the CPU's
+    /// cycle count right after it ran.
+    pub fn record(&mut self, pc: u16, instruction: Instruction, cycles: u64) {
+        self.addresses[pc as usize] += 1;
+        *self.classes.entry(instruction.pattern()).or_insert(0) += 1;
+
+        match instruction {
+            Instruction::Call(address) => {
+                let routine = self.routines.entry(address).or_insert(Routine {
+                    address,
+                    calls: 0,
+                    inclusive_cycles: 0,
+                });
+                routine.calls += 1;
+                self.calls.push((address, cycles));
+            }
+            Instruction::Ret => {
+                if let Some((address, called_at)) = self.calls.pop() {
+                    if let Some(routine) = self.routines.get_mut(&address) {
+                        routine.inclusive_cycles += cycles - called_at + 1;
+                    }
+                }
+            }
+            Instruction::Jump(start) if start <= pc => {
+                *self.loops.entry((start, pc)).or_insert(0) += 1;
+            }
+            _ => (),
+        }
+    }
+
+    /// Number of times the instruction at `address` was executed.
+    pub fn executions(&self, address: u16) -> u64 {
+        self.addresses[address as usize]
+    }
+
+    /// The `count` most executed addresses, most executed first.
+    pub fn hot_addresses(&self, count: usize) -> Vec<(u16, u64)> {
+        let mut hot: Vec<(u16, u64)> = self
+            .addresses
+            .iter()
+            .enumerate()
+            .filter(|(_, executions)| **executions > 0)
+            .map(|(address, executions)| (address as u16, *executions))
+            .collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hot.truncate(count);
+        hot
+    }
+
+    /// Executions per instruction class, most executed first.
+    pub fn opcode_histogram(&self) -> Vec<(&'static str, u64)> {
+        let mut histogram: Vec<(&'static str, u64)> =
+            self.classes.iter().map(|(class, n)| (*class, *n)).collect();
+        histogram.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        histogram
+    }
+
+    /// The `count` routines with the most inclusive cycles.
+    pub fn hottest_routines(&self, count: usize) -> Vec<Routine> {
+        let mut routines: Vec<Routine> = self.routines.values().copied().collect();
+        routines.sort_by_key(|routine| Reverse(routine.inclusive_cycles));
+        routines.truncate(count);
+        routines
+    }
+
+    /// The `count` loops in which the most instructions were executed.
+    pub fn hottest_loops(&self, count: usize) -> Vec<Loop> {
+        let mut loops: Vec<Loop> = self
+            .loops
+            .iter()
+            .map(|(&(start, end), &iterations)| Loop {
+                start,
+                end,
+                iterations,
+                cycles: self.addresses[start as usize..=end as usize].iter().sum(),
+            })
+            .collect();
+        loops.sort_by_key(|l| Reverse(l.cycles));
+        loops.truncate(count);
+        loops
+    }
+
+    /// Human readable summary with the top `count` entries of each table.
+    pub fn report(&self, count: usize) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "hot addresses:").unwrap();
+        for (address, executions) in self.hot_addresses(count) {
+            writeln!(out, "  {:#05X} {:>10}", address, executions).unwrap();
+        }
+        writeln!(out, "instructions:").unwrap();
+        for (class, executions) in self.opcode_histogram().iter().take(count) {
+            writeln!(out, "  {} {:>10}", class, executions).unwrap();
+        }
+        writeln!(out, "routines:").unwrap();
+        for routine in self.hottest_routines(count) {
+            writeln!(
+                out,
+                "  {:#05X} {:>10} calls {:>10} cycles",
+                routine.address, routine.calls, routine.inclusive_cycles
+            )
+            .unwrap();
+        }
+        writeln!(out, "loops:").unwrap();
+        for l in self.hottest_loops(count) {
+            writeln!(
+                out,
+                "  {:#05X}-{:#05X} {:>10} iterations {:>10} cycles",
+                l.start, l.end, l.iterations, l.cycles
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Loop, Profiler, Routine};
+    use crate::instruction::Instruction;
+
+    #[test]
+    fn test_routines() {
+        let mut profiler = Profiler::new(4096);
+        profiler.record(0x200, Instruction::Call(0x300), 1);
+        profiler.record(0x300, Instruction::Set(0, 1), 2);
+        profiler.record(0x302, Instruction::Ret, 3);
+
+        assert_eq!(
+            profiler.hottest_routines(1),
+            [Routine {
+                address: 0x300,
+                calls: 1,
+                inclusive_cycles: 3
+            }]
+        );
+        assert_eq!(profiler.opcode_histogram()[0].1, 1);
+    }
+
+    #[test]
+    fn test_loops() {
+        let mut profiler = Profiler::new(4096);
+        for lap in 0..3 {
+            profiler.record(0x200, Instruction::Add(0, 1), lap * 2 + 1);
+            profiler.record(0x202, Instruction::Jump(0x200), lap * 2 + 2);
+        }
+
+        assert_eq!(
+            profiler.hottest_loops(1),
+            [Loop {
+                start: 0x200,
+                end: 0x202,
+                iterations: 3,
+                cycles: 6
+            }]
+        );
+        assert_eq!(profiler.hot_addresses(1), [(0x200, 3)]);
+
+        profiler.clear();
+        assert_eq!(profiler.executions(0x200), 0);
+        assert!(profiler.hottest_loops(1).is_empty());
+    }
+}
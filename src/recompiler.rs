@@ -0,0 +1,278 @@
+use crate::analysis::{ends_block, Analysis, START};
+use crate::cpu::Cpu;
+use crate::instruction::Instruction;
+use crate::platform::Platform;
+use std::fmt::Write;
+
+/// What a translated block tells its caller once it is done.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exit {
+    // the block ran and `pc` points at the next block
+    Continue,
+    // `pc` isn't the start of a translated block
+    Interpret,
+    // the program wrote into its own code, the translation can't be trusted
+    Tainted,
+}
+
+/// Drives a translated program, falling back to the interpreter whenever the
+/// translation can't handle the current `pc`.
+pub struct Recompiled {
+    step: fn(&mut Cpu) -> Exit,
+    tainted: bool,
+}
+
+impl Recompiled {
+    pub fn new(step: fn(&mut Cpu) -> Exit) -> Recompiled {
+        Recompiled {
+            step,
+            tainted: false,
+        }
+    }
+
+    pub fn is_tainted(&self) -> bool {
+        self.tainted
+    }
+
+    /// Runs `count` blocks or interpreted instructions.
+    pub fn run(&mut self, cpu: &mut Cpu, count: u32) {
+        for _ in 0..count {
+            if self.tainted {
+                cpu.process_opcode();
+                continue;
+            }
+            match (self.step)(cpu) {
+                Exit::Continue => (),
+                Exit::Interpret => cpu.process_opcode(),
+                Exit::Tainted => self.tainted = true,
+            }
+        }
+    }
+}
+
+/// Translates a ROM loaded at 0x200 into the source of a Rust module.
+///
+/// The module exposes `pub fn step(cpu: &mut Cpu) -> Exit` and is meant to be
+/// dropped into this crate and driven by `Recompiled`.
+pub fn translate(rom: &[u8]) -> String {
+    let blocks = Analysis::new(rom, Platform::Legacy).blocks;
+    let code_start = START as usize;
+    let code_end = blocks
+        .values()
+        .map(|block| block.end() as usize)
+        .max()
+        .unwrap_or(code_start);
+
+    let mut out = String::new();
+    writeln!(out, "// Generated by the chip-9 recompiler. Do not edit.").unwrap();
+    writeln!(out, "use crate::cpu::Cpu;").unwrap();
+    writeln!(out, "use crate::instruction::Instruction;").unwrap();
+    writeln!(out, "use crate::recompiler::Exit;").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "const CODE_START: usize = {:#05X};", code_start).unwrap();
+    writeln!(out, "const CODE_END: usize = {:#05X};", code_end).unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "pub fn step(cpu: &mut Cpu) -> Exit {{").unwrap();
+    writeln!(out, "    match cpu.pc {{").unwrap();
+    for start in blocks.keys() {
+        writeln!(out, "        {:#05X} => block_{:03x}(cpu),", start, start).unwrap();
+    }
+    writeln!(out, "        _ => Exit::Interpret,").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    for (start, block) in &blocks {
+        writeln!(out).unwrap();
+        writeln!(out, "fn block_{:03x}(cpu: &mut Cpu) -> Exit {{", start).unwrap();
+        for (address, instruction) in &block.instructions {
+            emit(&mut out, *address, *instruction);
+        }
+        if let Some((address, instruction)) = block.instructions.last() {
+            if !ends_block(*instruction) {
+                // falls through into the next block
+                writeln!(out, "    cpu.pc = {:#05X};", address + 2).unwrap();
+                writeln!(out, "    Exit::Continue").unwrap();
+            }
+        }
+        writeln!(out, "}}").unwrap();
+    }
+
+    out
+}
+
+fn emit(out: &mut String, address: u16, instruction: Instruction) {
+    let next = address + 2;
+    let skip = address + 4;
+    let line = match instruction {
+        Instruction::Cls => "cpu.display.cls();".to_string(),
+        // the stack is checked by the interpreter, which also sets up `pc`
+        Instruction::Ret | Instruction::Call(_) | Instruction::WaitKey(_) => format!(
+            "cpu.pc = {:#05X};\n    {}\n    Exit::Continue",
+            address,
+            execute(instruction, address)
+        ),
+        Instruction::Jump(nnn) => format!("cpu.pc = {:#05X};\n    Exit::Continue", nnn),
+        Instruction::SkipEq(x, nn) => branch(format!("cpu.v[{:#X}] == {:#04X}", x, nn), skip, next),
+        Instruction::SkipNe(x, nn) => branch(format!("cpu.v[{:#X}] != {:#04X}", x, nn), skip, next),
+        Instruction::SkipEqReg(x, y) => {
+            branch(format!("cpu.v[{:#X}] == cpu.v[{:#X}]", x, y), skip, next)
+        }
+        Instruction::SkipNeReg(x, y) => {
+            branch(format!("cpu.v[{:#X}] != cpu.v[{:#X}]", x, y), skip, next)
+        }
+        Instruction::SkipKeyDown(x) => branch(
+            format!("cpu.keypad.is_key_down(cpu.v[{:#X}])", x),
+            skip,
+            next,
+        ),
+        Instruction::SkipKeyUp(x) => branch(
+            format!("!cpu.keypad.is_key_down(cpu.v[{:#X}])", x),
+            skip,
+            next,
+        ),
+        Instruction::Set(x, nn) => format!("cpu.v[{:#X}] = {:#04X};", x, nn),
+        Instruction::Add(x, nn) => format!(
+            "cpu.v[{:#X}] = cpu.v[{:#X}].wrapping_add({:#04X});",
+            x, x, nn
+        ),
+        Instruction::Assign(x, y) => format!("cpu.v[{:#X}] = cpu.v[{:#X}];", x, y),
+        Instruction::Or(x, y) => format!("cpu.v[{:#X}] |= cpu.v[{:#X}];", x, y),
+        Instruction::And(x, y) => format!("cpu.v[{:#X}] &= cpu.v[{:#X}];", x, y),
+        Instruction::Xor(x, y) => format!("cpu.v[{:#X}] ^= cpu.v[{:#X}];", x, y),
+        Instruction::SetIndex(nnn) => format!("cpu.i = {:#05X};", nnn),
+        Instruction::AddIndex(x) => format!("cpu.i = cpu.i.wrapping_add(cpu.v[{:#X}] as u16);", x),
+        Instruction::GetDelay(x) => format!("cpu.v[{:#X}] = cpu.dt;", x),
+        Instruction::SetDelay(x) => format!("cpu.dt = cpu.v[{:#X}];", x),
+        Instruction::JumpV0(nnn) => {
+            format!(
+                "cpu.pc = {:#05X} + cpu.v[0] as u16;\n    Exit::Continue",
+                nnn
+            )
+        }
+        Instruction::Bcd(_) | Instruction::Store(_) => {
+            let len = match instruction {
+                Instruction::Store(x) => x + 1,
+                _ => 3,
+            };
+            format!(
+                "{}\n    \
+                 if (cpu.i as usize) < CODE_END && cpu.i as usize + {} > CODE_START {{\n        \
+                 cpu.pc = {:#05X};\n        \
+                 return Exit::Tainted;\n    \
+                 }}",
+                execute(instruction, address),
+                len,
+                next
+            )
+        }
+        Instruction::Unknown(_) => format!("cpu.pc = {:#05X};\n    Exit::Interpret", address),
+        // everything else has side effects on the display, keypad or rng
+        // that are easier to leave to the interpreter
+        _ => execute(instruction, address),
+    };
+    writeln!(out, "    // {:#05X}", address).unwrap();
+    writeln!(out, "    {}", line).unwrap();
+}
+
+// Runs an instruction through the interpreter. Failed instructions don't
+// change any state, so on error the interpreter can take over from `address`
+// and report the fault itself.
+fn execute(instruction: Instruction, address: u16) -> String {
+    format!(
+        "if cpu.execute(Instruction::{:?}).is_err() {{\n        \
+         cpu.pc = {:#05X};\n        \
+         return Exit::Interpret;\n    \
+         }}",
+        instruction, address
+    )
+}
+
+fn branch(condition: String, taken: u16, not_taken: u16) -> String {
+    format!(
+        "cpu.pc = if {} {{ {:#05X} }} else {{ {:#05X} }};\n    Exit::Continue",
+        condition, taken, not_taken
+    )
+}
+
+// `FIXTURE_ROM` as `translate` renders it, compiled to check the generated
+// code against the interpreter
+#[cfg(test)]
+#[rustfmt::skip]
+#[path = "recompiler_fixture.rs"]
+mod fixture;
+
+#[cfg(test)]
+mod tests {
+    use super::{fixture, translate, Exit, Recompiled};
+    use crate::cpu::Cpu;
+
+    // counts to 6 adding to V0, stores its digits, loads them back in a
+    // subroutine, draws and halts at 0x214
+    const FIXTURE_ROM: [u8; 26] = [
+        0x60, 0x05, 0x61, 0x00, 0x71, 0x01, 0x80, 0x14, 0x31, 0x06, 0x12, 0x04, 0xA3, 0x00, 0xF0,
+        0x33, 0x22, 0x16, 0xD0, 0x15, 0x12, 0x14, 0xF2, 0x65, 0x00, 0xEE,
+    ];
+
+    #[test]
+    fn test_translate() {
+        // 0x200: V0 = 5, V0 += 1, skip if V0 == 8, jump 0x202, return
+        let rom = [0x60, 0x05, 0x70, 0x01, 0x30, 0x08, 0x12, 0x02, 0x00, 0xEE];
+        let source = translate(&rom);
+
+        assert!(source.contains("0x200 => block_200(cpu),"));
+        assert!(source.contains("0x202 => block_202(cpu),"));
+        assert!(source.contains("0x206 => block_206(cpu),"));
+        assert!(source.contains("0x208 => block_208(cpu),"));
+        assert!(source.contains("cpu.v[0x0] = cpu.v[0x0].wrapping_add(0x01);"));
+        assert!(source.contains("cpu.pc = if cpu.v[0x0] == 0x08 { 0x208 } else { 0x206 };"));
+    }
+
+    #[test]
+    fn test_interpreter_fallback() {
+        let mut cpu = Cpu::new();
+        cpu.reset();
+        cpu.memory[0x200..0x204].copy_from_slice(&[0x60, 0x05, 0x70, 0x01]);
+
+        let mut recompiled = Recompiled::new(|_| Exit::Interpret);
+        recompiled.run(&mut cpu, 2);
+        assert_eq!(cpu.v[0], 6);
+        assert!(!recompiled.is_tainted());
+    }
+
+    #[test]
+    fn test_fixture_is_current() {
+        // regenerate with `chip-9 recompile` when the translation changes
+        assert_eq!(
+            translate(&FIXTURE_ROM),
+            include_str!("recompiler_fixture.rs")
+        );
+    }
+
+    #[test]
+    fn test_recompiled_matches_interpreter() {
+        let mut interpreted = Cpu::new();
+        interpreted.load_rom(&FIXTURE_ROM);
+        interpreted.run(100);
+
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&FIXTURE_ROM);
+        let mut recompiled = Recompiled::new(fixture::step);
+        recompiled.run(&mut cpu, 50);
+
+        assert!(!recompiled.is_tainted());
+        assert_eq!(cpu.pc, 0x214);
+        assert_eq!(cpu.v[..3], [0, 2, 6]);
+        assert_eq!(
+            (cpu.pc, cpu.i, cpu.v, cpu.sp, cpu.stack),
+            (
+                interpreted.pc,
+                interpreted.i,
+                interpreted.v,
+                interpreted.sp,
+                interpreted.stack
+            )
+        );
+        assert_eq!(cpu.memory, interpreted.memory);
+        assert_eq!(cpu.screen(), interpreted.screen());
+    }
+}
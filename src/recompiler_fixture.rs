@@ -0,0 +1,100 @@
+// Generated by the chip-9 recompiler. Do not edit.
+use crate::cpu::Cpu;
+use crate::instruction::Instruction;
+use crate::recompiler::Exit;
+
+const CODE_START: usize = 0x200;
+const CODE_END: usize = 0x21A;
+
+pub fn step(cpu: &mut Cpu) -> Exit {
+    match cpu.pc {
+        0x200 => block_200(cpu),
+        0x204 => block_204(cpu),
+        0x20A => block_20a(cpu),
+        0x20C => block_20c(cpu),
+        0x212 => block_212(cpu),
+        0x214 => block_214(cpu),
+        0x216 => block_216(cpu),
+        _ => Exit::Interpret,
+    }
+}
+
+fn block_200(cpu: &mut Cpu) -> Exit {
+    // 0x200
+    cpu.v[0x0] = 0x05;
+    // 0x202
+    cpu.v[0x1] = 0x00;
+    cpu.pc = 0x204;
+    Exit::Continue
+}
+
+fn block_204(cpu: &mut Cpu) -> Exit {
+    // 0x204
+    cpu.v[0x1] = cpu.v[0x1].wrapping_add(0x01);
+    // 0x206
+    if cpu.execute(Instruction::AddReg(0, 1)).is_err() {
+        cpu.pc = 0x206;
+        return Exit::Interpret;
+    }
+    // 0x208
+    cpu.pc = if cpu.v[0x1] == 0x06 { 0x20C } else { 0x20A };
+    Exit::Continue
+}
+
+fn block_20a(cpu: &mut Cpu) -> Exit {
+    // 0x20A
+    cpu.pc = 0x204;
+    Exit::Continue
+}
+
+fn block_20c(cpu: &mut Cpu) -> Exit {
+    // 0x20C
+    cpu.i = 0x300;
+    // 0x20E
+    if cpu.execute(Instruction::Bcd(0)).is_err() {
+        cpu.pc = 0x20E;
+        return Exit::Interpret;
+    }
+    if (cpu.i as usize) < CODE_END && cpu.i as usize + 3 > CODE_START {
+        cpu.pc = 0x210;
+        return Exit::Tainted;
+    }
+    // 0x210
+    cpu.pc = 0x210;
+    if cpu.execute(Instruction::Call(534)).is_err() {
+        cpu.pc = 0x210;
+        return Exit::Interpret;
+    }
+    Exit::Continue
+}
+
+fn block_212(cpu: &mut Cpu) -> Exit {
+    // 0x212
+    if cpu.execute(Instruction::Draw(0, 1, 5)).is_err() {
+        cpu.pc = 0x212;
+        return Exit::Interpret;
+    }
+    cpu.pc = 0x214;
+    Exit::Continue
+}
+
+fn block_214(cpu: &mut Cpu) -> Exit {
+    // 0x214
+    cpu.pc = 0x214;
+    Exit::Continue
+}
+
+fn block_216(cpu: &mut Cpu) -> Exit {
+    // 0x216
+    if cpu.execute(Instruction::Load(2)).is_err() {
+        cpu.pc = 0x216;
+        return Exit::Interpret;
+    }
+    // 0x218
+    cpu.pc = 0x218;
+    if cpu.execute(Instruction::Ret).is_err() {
+        cpu.pc = 0x218;
+        return Exit::Interpret;
+    }
+    Exit::Continue
+}
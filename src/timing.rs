@@ -0,0 +1,172 @@
+//! Timing of the COSMAC VIP's CHIP-8 interpreter, in CDP1802 machine cycles.
+//!
+//! The documented costs come from Laurence Scotford's annotated disassembly
+//! of the interpreter, "Chip-8 on the COSMAC VIP", which counts the cycles of
+//! each instruction including fetch and dispatch.
+
+use crate::instruction::Instruction;
+
+/// Machine cycles of the VIP's CDP1802 per 60 Hz frame, at 1.76 MHz and 8
+/// clock pulses per machine cycle.
+pub const FRAME_CYCLES: u32 = 3668;
+// cycles the 1861 display steals each frame to fetch 128 lines of 8 bytes
+const DISPLAY_DMA: u32 = 1024;
+// the interrupt routine running the timers and setting up the display
+const INTERRUPT: u32 = 50;
+
+/// Cycles left to the interpreter each frame.
+pub const INTERPRETER_CYCLES: u32 = FRAME_CYCLES - DISPLAY_DMA - INTERRUPT;
+
+/// Machine cycles the VIP interpreter takes to execute `instruction`, with
+/// `v` the registers before it runs.
+///
+/// 00E0, 00EE, 1NNN, 2NNN and BNNN take the documented cycles, the others
+/// approximations of them rounded to whole machine cycles. 00E0 clears the
+/// 256 bytes of display memory one at a time, DXYN gets slower with the
+/// sprite height and the number of bits each row has to be shifted by, FX33
+/// with the digits it has to count out and FX55/FX65 with the number of
+/// registers.
+pub fn cycles(instruction: Instruction, v: &[u8; 16]) -> u32 {
+    match instruction {
+        // 24 to set up, then 3078 for the loop over the display memory
+        Instruction::Cls | Instruction::HiresCls => 24 + 3078,
+        Instruction::Background | Instruction::Output(_) | Instruction::Input(_) => 10,
+        Instruction::SkipKey2Down(_) | Instruction::SkipKey2Up(_) => 16,
+        Instruction::AddColour(..) => 44,
+        // the colour board's memory is written zone by zone
+        Instruction::ColourZones(..) | Instruction::ColourRows(..) => 68,
+        Instruction::Ret => 10,
+        Instruction::Jump(_) => 12,
+        Instruction::Call(_) => 26,
+        Instruction::JumpV0(_) => 22,
+        Instruction::SkipEq(..) | Instruction::SkipNe(..) | Instruction::SetIndex(_) => 12,
+        Instruction::SkipEqReg(..)
+        | Instruction::SkipNeReg(..)
+        | Instruction::SkipKeyDown(_)
+        | Instruction::SkipKeyUp(_) => 16,
+        Instruction::Set(..) => 6,
+        Instruction::Add(..)
+        | Instruction::GetDelay(_)
+        | Instruction::SetDelay(_)
+        | Instruction::SetSound(_)
+        | Instruction::WaitKey(_) => 10,
+        Instruction::Assign(..)
+        | Instruction::Or(..)
+        | Instruction::And(..)
+        | Instruction::Xor(..)
+        | Instruction::AddReg(..)
+        | Instruction::SubReg(..)
+        | Instruction::ShiftRight(..)
+        | Instruction::SubN(..)
+        | Instruction::ShiftLeft(..) => 44,
+        Instruction::Random(..) => 36,
+        Instruction::Draw(x, _, n) => {
+            let shift = (v[x] % 8) as u32;
+            68 + n as u32 * (34 + 8 * shift)
+        }
+        Instruction::AddIndex(_) => 19,
+        Instruction::Font(_) | Instruction::LargeFont(_) => 20,
+        Instruction::Bcd(x) => {
+            let value = v[x] as u32;
+            84 + 16 * (value / 100 + value / 10 % 10 + value % 10)
+        }
+        Instruction::Store(x) | Instruction::Load(x) => 18 + 14 * (x as u32 + 1),
+        // not on the VIP
+        Instruction::ScrollDown(_)
+        | Instruction::ScrollUp(_)
+        | Instruction::ScrollRight
+        | Instruction::ScrollLeft
+        | Instruction::Exit
+        | Instruction::LowRes
+        | Instruction::HighRes
+        | Instruction::MegaOff
+        | Instruction::MegaOn
+        | Instruction::LongIndex(_)
+        | Instruction::LoadPalette(_)
+        | Instruction::SpriteWidth(_)
+        | Instruction::SpriteHeight(_)
+        | Instruction::ScreenAlpha(_)
+        | Instruction::PlaySample(_)
+        | Instruction::StopSample
+        | Instruction::BlendMode(_)
+        | Instruction::CollisionColour(_)
+        | Instruction::SaveFlags(_)
+        | Instruction::LoadFlags(_)
+        | Instruction::SetLongIndex
+        | Instruction::Planes(_)
+        | Instruction::StoreRange(..)
+        | Instruction::LoadRange(..)
+        | Instruction::Unknown(_) => 20,
+    }
+}
+
+/// Budgets execution by VIP machine cycles. An instruction running past the
+/// end of a frame is paid for out of the next one, and DXYN waits for the
+/// vertical blank, so it always starts a frame.
+#[derive(Clone, Debug, Default)]
+pub struct Timing {
+    // cycles left in the current frame, negative after an overrun
+    credit: i64,
+    // cycles spent since the last reset, waiting included
+    total: u64,
+}
+
+impl Timing {
+    pub fn new() -> Timing {
+        Timing::default()
+    }
+
+    pub fn reset(&mut self) {
+        *self = Timing::new();
+    }
+
+    pub(crate) fn start_frame(&mut self) {
+        self.credit += INTERPRETER_CYCLES as i64;
+    }
+
+    pub(crate) fn has_budget(&self) -> bool {
+        self.credit > 0
+    }
+
+    pub(crate) fn spend(&mut self, cycles: u32) {
+        self.credit -= cycles as i64;
+        self.total += cycles as u64;
+    }
+
+    pub(crate) fn wait_for_vblank(&mut self) {
+        self.total += self.credit.max(0) as u64;
+        self.credit = 0;
+    }
+
+    /// Machine cycles the interpreter used since the last reset.
+    pub fn machine_cycles(&self) -> u64 {
+        self.total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cycles;
+    use crate::instruction::Instruction;
+
+    #[test]
+    fn test_costs() {
+        let mut v = [0; 16];
+        assert_eq!(cycles(Instruction::Cls, &v), 3102);
+        assert_eq!(cycles(Instruction::Ret, &v), 10);
+        assert_eq!(cycles(Instruction::Jump(0x200), &v), 12);
+        assert_eq!(cycles(Instruction::Call(0x200), &v), 26);
+        assert_eq!(cycles(Instruction::JumpV0(0x200), &v), 22);
+        assert_eq!(cycles(Instruction::Set(0, 1), &v), 6);
+
+        // taller sprites and unaligned ones take longer
+        let aligned = cycles(Instruction::Draw(0, 1, 5), &v);
+        assert!(cycles(Instruction::Draw(0, 1, 1), &v) < aligned);
+        v[0] = 3;
+        assert!(cycles(Instruction::Draw(0, 1, 5), &v) > aligned);
+
+        v[2] = 199;
+        assert!(cycles(Instruction::Bcd(2), &v) > cycles(Instruction::Bcd(0), &v));
+        assert!(cycles(Instruction::Store(15), &v) > cycles(Instruction::Store(0), &v));
+    }
+}
@@ -0,0 +1,178 @@
+use crate::cpu::{Cpu, CpuError};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+
+const DEFAULT_HISTORY: usize = 64;
+
+#[derive(Debug)]
+pub enum TraceError {
+    Cpu(CpuError),
+    Io(io::Error),
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceError::Cpu(error) => error.fmt(f),
+            TraceError::Io(error) => write!(f, "failed to write trace: {}", error),
+        }
+    }
+}
+
+impl Error for TraceError {}
+
+impl From<CpuError> for TraceError {
+    fn from(error: CpuError) -> TraceError {
+        TraceError::Cpu(error)
+    }
+}
+
+impl From<io::Error> for TraceError {
+    fn from(error: io::Error) -> TraceError {
+        TraceError::Io(error)
+    }
+}
+
+/// Logs executed instructions, one line each, in a fixed format meant to be
+/// diffed against the logs of other emulators:
+///
+/// ```text
+/// 00000042 0212 6A02 LD VA, 0x02      V 00 01 .. 0F I 02EA SP 01 DT 00
+/// ```
+///
+/// That is the cycle count, PC, opcode and disassembly of the instruction
+/// about to run, followed by V0 to VF, I, SP and DT before it runs.
+///
+/// The last instructions are always kept around and written to the sink when
+/// the CPU faults, so a tracer that doesn't log everything is still useful
+/// to find out how a program got into trouble.
+pub struct Tracer<W: Write> {
+    sink: W,
+    log_all: bool,
+    history: VecDeque<String>,
+    capacity: usize,
+}
+
+impl<W: Write> Tracer<W> {
+    /// Logs every instruction to `sink`.
+    pub fn new(sink: W) -> Tracer<W> {
+        Tracer {
+            sink,
+            log_all: true,
+            history: VecDeque::with_capacity(DEFAULT_HISTORY),
+            capacity: DEFAULT_HISTORY,
+        }
+    }
+
+    /// Only keeps the last `capacity` instructions and writes them to `sink`
+    /// when the CPU faults.
+    pub fn on_fault(sink: W, capacity: usize) -> Tracer<W> {
+        Tracer {
+            sink,
+            log_all: false,
+            history: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.sink
+    }
+
+    /// Executes one instruction, tracing it.
+    pub fn step(&mut self, cpu: &mut Cpu) -> Result<(), TraceError> {
+        let line = trace_line(cpu);
+        if self.log_all {
+            writeln!(self.sink, "{}", line)?;
+        }
+        if self.history.len() == self.capacity {
+            self.history.pop_front();
+        }
+        if self.capacity > 0 {
+            self.history.push_back(line);
+        }
+
+        if let Err(error) = cpu.step() {
+            self.dump(error)?;
+            return Err(error.into());
+        }
+        Ok(())
+    }
+
+    fn dump(&mut self, error: CpuError) -> io::Result<()> {
+        writeln!(self.sink, "fault: {}", error)?;
+        if !self.log_all {
+            writeln!(self.sink, "last {} instructions:", self.history.len())?;
+            for line in &self.history {
+                writeln!(self.sink, "{}", line)?;
+            }
+        }
+        self.sink.flush()
+    }
+}
+
+/// Formats the trace line for the instruction at `pc`.
+pub fn trace_line(cpu: &Cpu) -> String {
+    let pc = cpu.pc as usize;
+    let opcode = match cpu.memory.get(pc..pc + 2) {
+        Some(bytes) => (bytes[0] as u16) << 8 | bytes[1] as u16,
+        None => 0,
+    };
+    let registers: Vec<String> = cpu.v.iter().map(|v| format!("{:02X}", v)).collect();
+
+    format!(
+        "{:08} {:04X} {:04X} {:<16} V {} I {:04X} SP {:02X} DT {:02X}",
+        cpu.cycles(),
+        cpu.pc,
+        opcode,
+        cpu.platform().decode(opcode).to_string(),
+        registers.join(" "),
+        cpu.i,
+        cpu.sp,
+        cpu.dt
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{trace_line, TraceError, Tracer};
+    use crate::cpu::{Cpu, CpuError};
+
+    #[test]
+    fn test_trace_line() {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&[0x6A, 0x02]);
+        cpu.v[1] = 0x01;
+        cpu.i = 0x2EA;
+
+        assert_eq!(
+            trace_line(&cpu),
+            "00000000 0200 6A02 LD VA, 0x02      \
+             V 00 01 00 00 00 00 00 00 00 00 00 00 00 00 00 00 I 02EA SP 00 DT 00"
+        );
+    }
+
+    #[test]
+    fn test_history_dumped_on_fault() {
+        let mut cpu = Cpu::new();
+        // V0 = 1, V1 = 2, return with an empty stack
+        cpu.load_rom(&[0x60, 0x01, 0x61, 0x02, 0x00, 0xEE]);
+        let mut tracer = Tracer::on_fault(Vec::new(), 2);
+
+        tracer.step(&mut cpu).unwrap();
+        tracer.step(&mut cpu).unwrap();
+        match tracer.step(&mut cpu) {
+            Err(TraceError::Cpu(CpuError::StackUnderflow { pc: 0x204 })) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let log = String::from_utf8(tracer.into_inner()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "fault: stack underflow at 0x204");
+        assert!(lines[2].starts_with("00000001 0202 6102 LD V1, 0x02"));
+        assert!(lines[3].starts_with("00000002 0204 00EE RET"));
+    }
+}
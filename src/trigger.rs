@@ -0,0 +1,406 @@
+use crate::cheat::Location;
+use crate::cpu::Cpu;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+/// The value a condition tests.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    Byte(Location),
+    // big-endian, at an address and the next
+    Word(u32),
+    // value at the previous evaluation
+    Prev(Location),
+    // change since the previous evaluation, signed
+    Delta(Location),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Condition {
+    pub operand: Operand,
+    pub op: Op,
+    pub value: i32,
+}
+
+/// A named rule: conditions that all have to hold for `frames` consecutive
+/// evaluations.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trigger {
+    pub name: String,
+    pub conditions: Vec<Condition>,
+    pub frames: u32,
+    // fires at most once, like an achievement
+    pub once: bool,
+    // consecutive evaluations the conditions held
+    held: u32,
+    fired: u32,
+}
+
+impl Trigger {
+    /// Number of times the trigger fired.
+    pub fn fired(&self) -> u32 {
+        self.fired
+    }
+}
+
+/// A trigger firing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub name: String,
+    // `Cpu::frame_count` when it fired
+    pub frame: u32,
+}
+
+/// Why trigger definitions couldn't be parsed, `line` counting from 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TriggerError {
+    pub line: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for TriggerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl Error for TriggerError {}
+
+/// Evaluates conditions on memory and the V registers once per frame and
+/// fires events when they start to hold. Definitions are text, one trigger
+/// per line:
+///
+/// ```text
+/// # name: conditions [for <n> frames] [once]
+/// level 10: byte 0x3F0 >= 10 for 3 frames once
+/// scored: delta V5 > 0
+/// landed: V3 == 0x1F && prev V3 != 0x1F
+/// ```
+///
+/// Operands are `byte <location>` (or a bare location), `word <address>`,
+/// `prev <location>` and `delta <location>`, locations being V0..VF or a hex
+/// address. Values are decimal or 0x-prefixed hex. `#` starts a comment.
+///
+/// A trigger fires on the evaluation its conditions have held for `for`
+/// frames, and only fires again once they stopped holding. Conditions on
+/// memory the machine doesn't have never hold.
+#[derive(Clone, Debug, Default)]
+pub struct TriggerSet {
+    triggers: Vec<Trigger>,
+    // memory size and the values of the locations `prev` and `delta`
+    // look at, at the previous evaluation
+    previous: Option<(usize, BTreeMap<Location, u8>)>,
+}
+
+impl TriggerSet {
+    pub fn parse(text: &str) -> Result<TriggerSet, TriggerError> {
+        let mut triggers = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            triggers.push(parse_trigger(line).map_err(|reason| TriggerError {
+                line: n + 1,
+                reason,
+            })?);
+        }
+        Ok(TriggerSet {
+            triggers,
+            previous: None,
+        })
+    }
+
+    pub fn triggers(&self) -> &[Trigger] {
+        &self.triggers
+    }
+
+    /// Forgets the previous values and how long conditions held, e.g.
+    /// after the program was reset.
+    pub fn reset(&mut self) {
+        self.previous = None;
+        for trigger in &mut self.triggers {
+            trigger.held = 0;
+        }
+    }
+
+    /// Evaluates every trigger against the machine, once per frame,
+    /// returning those that fired.
+    pub fn evaluate(&mut self, cpu: &Cpu) -> Vec<Event> {
+        // memory changes size with the platform
+        let previous = self
+            .previous
+            .take()
+            .filter(|(len, _)| *len == cpu.memory.len())
+            .map(|(_, values)| values)
+            .unwrap_or_else(|| self.snapshot(cpu));
+        let mut events = Vec::new();
+
+        for trigger in &mut self.triggers {
+            let holds = trigger
+                .conditions
+                .iter()
+                .all(|condition| condition.holds(cpu, &previous));
+            if !holds {
+                trigger.held = 0;
+                continue;
+            }
+            trigger.held = trigger.held.saturating_add(1);
+            if trigger.held == trigger.frames && !(trigger.once && trigger.fired > 0) {
+                trigger.fired += 1;
+                events.push(Event {
+                    name: trigger.name.clone(),
+                    frame: cpu.frame_count(),
+                });
+            }
+        }
+
+        self.previous = Some((cpu.memory.len(), self.snapshot(cpu)));
+        events
+    }
+
+    fn snapshot(&self, cpu: &Cpu) -> BTreeMap<Location, u8> {
+        self.triggers
+            .iter()
+            .flat_map(|trigger| &trigger.conditions)
+            .filter_map(|condition| match condition.operand {
+                Operand::Prev(location) | Operand::Delta(location) => Some(location),
+                _ => None,
+            })
+            .filter(|location| location.check(cpu).is_ok())
+            .map(|location| (location, location.read(cpu)))
+            .collect()
+    }
+}
+
+impl Condition {
+    fn holds(&self, cpu: &Cpu, previous: &BTreeMap<Location, u8>) -> bool {
+        let (location, len) = match self.operand {
+            Operand::Word(address) => (Location::Memory(address), 2),
+            Operand::Byte(location) | Operand::Prev(location) | Operand::Delta(location) => {
+                (location, 1)
+            }
+        };
+        if let Location::Memory(address) = location {
+            if address as usize + len > cpu.memory.len() {
+                return false;
+            }
+        }
+        let before = |location: Location| previous[&location];
+        let value = match self.operand {
+            Operand::Byte(location) => location.read(cpu) as i32,
+            Operand::Word(address) => {
+                let a = address as usize;
+                (cpu.memory[a] as i32) << 8 | cpu.memory[a + 1] as i32
+            }
+            Operand::Prev(location) => before(location) as i32,
+            Operand::Delta(location) => location.read(cpu) as i32 - before(location) as i32,
+        };
+        match self.op {
+            Op::Eq => value == self.value,
+            Op::Ne => value != self.value,
+            Op::Lt => value < self.value,
+            Op::Le => value <= self.value,
+            Op::Gt => value > self.value,
+            Op::Ge => value >= self.value,
+        }
+    }
+}
+
+fn parse_trigger(line: &str) -> Result<Trigger, &'static str> {
+    let (name, rest) = line
+        .split_once(':')
+        .ok_or("expected <name>: <conditions>")?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("missing trigger name");
+    }
+
+    let mut words: Vec<&str> = rest.split_whitespace().collect();
+    let once = words.last() == Some(&"once");
+    if once {
+        words.pop();
+    }
+    if words.last() == Some(&"frames") || words.last() == Some(&"frame") {
+        words.pop();
+    }
+    let mut frames = 1;
+    if words.len() >= 2 && words[words.len() - 2] == "for" {
+        frames = words[words.len() - 1]
+            .parse()
+            .ok()
+            .filter(|frames| *frames > 0)
+            .ok_or("expected a frame count after for")?;
+        words.truncate(words.len() - 2);
+    }
+
+    let conditions = words
+        .split(|word| *word == "&&")
+        .map(parse_condition)
+        .collect::<Result<Vec<Condition>, &'static str>>()?;
+    Ok(Trigger {
+        name: name.to_string(),
+        conditions,
+        frames,
+        once,
+        held: 0,
+        fired: 0,
+    })
+}
+
+fn parse_condition(words: &[&str]) -> Result<Condition, &'static str> {
+    let parse_location = |text: &str| {
+        text.parse::<Location>()
+            .map_err(|_| "expected a register or address")
+    };
+    let (operand, rest) = match words {
+        ["byte", location, rest @ ..] => (Operand::Byte(parse_location(location)?), rest),
+        ["prev", location, rest @ ..] => (Operand::Prev(parse_location(location)?), rest),
+        ["delta", location, rest @ ..] => (Operand::Delta(parse_location(location)?), rest),
+        ["word", address, rest @ ..] => match parse_location(address)? {
+            Location::Memory(address) => (Operand::Word(address), rest),
+            _ => return Err("word needs an address"),
+        },
+        [location, rest @ ..] => (Operand::Byte(parse_location(location)?), rest),
+        [] => return Err("empty condition"),
+    };
+
+    let (op, value) = match rest {
+        [op, value] => (*op, *value),
+        _ => return Err("expected <operand> <comparison> <value>"),
+    };
+    let op = match op {
+        "==" => Op::Eq,
+        "!=" => Op::Ne,
+        "<" => Op::Lt,
+        "<=" => Op::Le,
+        ">" => Op::Gt,
+        ">=" => Op::Ge,
+        _ => return Err("expected ==, !=, <, <=, > or >="),
+    };
+    let value = match value.strip_prefix("0x") {
+        Some(hex) => i32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+    .ok_or("expected a number")?;
+
+    Ok(Condition { operand, op, value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Condition, Op, Operand, TriggerError, TriggerSet};
+    use crate::cheat::Location;
+    use crate::cpu::Cpu;
+    use crate::platform::Platform;
+
+    #[test]
+    fn test_parse() {
+        let set = TriggerSet::parse(
+            "# comment\nlevel 10: byte 0x3F0 >= 10 for 3 frames once\nscored: delta V5 > 0 && word 200 != 0x1234\n",
+        )
+        .unwrap();
+        let triggers = set.triggers();
+
+        assert_eq!(triggers[0].name, "level 10");
+        assert_eq!((triggers[0].frames, triggers[0].once), (3, true));
+        assert_eq!(
+            triggers[1].conditions,
+            [
+                Condition {
+                    operand: Operand::Delta(Location::Register(5)),
+                    op: Op::Gt,
+                    value: 0
+                },
+                Condition {
+                    operand: Operand::Word(0x200),
+                    op: Op::Ne,
+                    value: 0x1234
+                }
+            ]
+        );
+        assert_eq!(
+            TriggerSet::parse("a: V0 = 1").unwrap_err(),
+            TriggerError {
+                line: 1,
+                reason: "expected ==, !=, <, <=, > or >="
+            }
+        );
+        assert!(TriggerSet::parse("no colon").is_err());
+    }
+
+    #[test]
+    fn test_held_for_frames() {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&[]);
+        let mut set = TriggerSet::parse("level: 3F0 >= 10 for 3").unwrap();
+
+        let mut fired = Vec::new();
+        for frame in 0..8 {
+            cpu.memory[0x3F0] = if frame == 5 { 0 } else { 10 };
+            fired.push(set.evaluate(&cpu).len());
+        }
+        // fires once held for 3 frames, and again after it stopped holding
+        assert_eq!(fired, [0, 0, 1, 0, 0, 0, 0, 0]);
+        cpu.memory[0x3F0] = 10;
+        assert_eq!(set.evaluate(&cpu).len(), 1);
+        assert_eq!(set.triggers()[0].fired(), 2);
+    }
+
+    #[test]
+    fn test_delta_and_once() {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&[]);
+        let mut set =
+            TriggerSet::parse("scored: delta V5 > 0 once\nchanged: delta V5 != 0").unwrap();
+
+        assert!(set.evaluate(&cpu).is_empty());
+        cpu.v[5] = 1;
+        let events = set.evaluate(&cpu);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].name, "scored");
+        // still changing, so no new edge
+        cpu.v[5] = 0;
+        assert!(set.evaluate(&cpu).is_empty());
+        assert!(set.evaluate(&cpu).is_empty());
+        cpu.v[5] = 2;
+        let events = set.evaluate(&cpu);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].name, "changed");
+    }
+
+    #[test]
+    fn test_prev_memory() {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&[]);
+        let mut set = TriggerSet::parse("grew: delta 0x300 > 0 && prev 0x300 == 1").unwrap();
+
+        cpu.memory[0x300] = 1;
+        assert!(set.evaluate(&cpu).is_empty());
+        cpu.memory[0x300] = 4;
+        assert_eq!(set.evaluate(&cpu).len(), 1);
+    }
+
+    #[test]
+    fn test_memory_size() {
+        let mut set = TriggerSet::parse("high: word 0xFFFE == 0x1234").unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&[]);
+        assert!(set.evaluate(&cpu).is_empty());
+
+        cpu.set_platform(Platform::MegaChip);
+        cpu.memory[0xFFFE..0x10000].copy_from_slice(&[0x12, 0x34]);
+        assert_eq!(set.evaluate(&cpu).len(), 1);
+    }
+}
@@ -0,0 +1,372 @@
+use crate::cpu::INSTRUCTIONS_PER_FRAME;
+use crate::debugger::Stop;
+use crate::monitor::Monitor;
+
+const DISPLAY_WIDTH: usize = 64;
+// panes below the display
+const PANE_HEIGHT: usize = 16;
+const DISASSEMBLY_WIDTH: usize = 30;
+const MEMORY_WIDTH: usize = 30;
+const MEMORY_ROW: usize = 8;
+const STACK_WIDTH: usize = 12;
+const BREAKPOINTS_WIDTH: usize = 10;
+// lines up the right border of both rows, each pane having two borders
+const REGISTERS_WIDTH: usize =
+    DISASSEMBLY_WIDTH + MEMORY_WIDTH + STACK_WIDTH + BREAKPOINTS_WIDTH + 4 - DISPLAY_WIDTH;
+// instructions shown before PC in the disassembly
+const DISASSEMBLY_CONTEXT: u16 = 6;
+
+const KEYS: &str =
+    "s step  n frame  r run/stop  b break at PC  [ ] memory  m memory at I  : command  q quit";
+
+/// Full screen debugger: the display, disassembly around PC, registers,
+/// call stack, memory and breakpoints, driven one key at a time.
+///
+/// Rendering only produces text with box drawing characters, putting the
+/// terminal in raw mode and reading keys is up to the caller. Commands typed
+/// after `:` go to the `Monitor`.
+pub struct Tui {
+    pub monitor: Monitor,
+    running: bool,
+    memory_start: u16,
+    // command being typed after `:`
+    command: Option<String>,
+    message: String,
+}
+
+impl Tui {
+    pub fn new(monitor: Monitor) -> Tui {
+        Tui {
+            monitor,
+            running: false,
+            memory_start: 0x200,
+            command: None,
+            message: String::new(),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.monitor.is_done()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn handle_key(&mut self, key: u8) {
+        if let Some(command) = self.command.as_mut() {
+            match key {
+                b'\r' | b'\n' => {
+                    let command = self.command.take().unwrap_or_default();
+                    self.execute(&command);
+                }
+                // escape
+                0x1B => self.command = None,
+                // backspace or delete
+                0x08 | 0x7F => {
+                    command.pop();
+                }
+                b' '..=b'~' => command.push(key as char),
+                _ => (),
+            }
+            return;
+        }
+
+        match key {
+            b's' => self.execute("step"),
+            b'n' => self.execute("frame"),
+            b'r' => {
+                self.running = !self.running;
+                self.message.clear();
+            }
+            b'b' => {
+                let pc = self.monitor.cpu.pc;
+                let debugger = self.monitor.debugger_mut();
+                if !debugger.remove_breakpoint(pc) {
+                    debugger.add_breakpoint(pc);
+                }
+            }
+            b'[' => self.memory_start = self.memory_start.saturating_sub(0x40),
+            b']' => self.memory_start = (self.memory_start + 0x40).min(self.last_memory_start()),
+            b'm' => {
+                let row = self.monitor.cpu.i & !(MEMORY_ROW as u16 - 1);
+                self.memory_start = row.min(self.last_memory_start());
+            }
+            b':' => self.command = Some(String::new()),
+            b'q' => self.execute("quit"),
+            _ => (),
+        }
+    }
+
+    /// Runs a frame's worth of instructions when running, stopping at
+    /// breakpoints, watchpoints and faults.
+    pub fn tick(&mut self) {
+        if !self.running {
+            return;
+        }
+        let stop = self.monitor.run(INSTRUCTIONS_PER_FRAME as u64);
+        if stop != Stop::Limit {
+            self.running = false;
+            self.message = stop.to_string();
+        }
+    }
+
+    fn execute(&mut self, command: &str) {
+        self.message = match self.monitor.execute(command) {
+            // the last line, as the panes already show the rest
+            Ok(output) => output.lines().last().unwrap_or_default().to_string(),
+            Err(error) => format!("error: {}", error),
+        };
+    }
+
+    /// The whole screen, lines separated by `\n`.
+    pub fn render(&self) -> String {
+        let top = side_by_side(&[
+            boxed("display", &self.display(), DISPLAY_WIDTH, PANE_HEIGHT),
+            boxed("registers", &self.registers(), REGISTERS_WIDTH, PANE_HEIGHT),
+        ]);
+        let bottom = side_by_side(&[
+            boxed(
+                "disassembly",
+                &self.disassembly(),
+                DISASSEMBLY_WIDTH,
+                PANE_HEIGHT,
+            ),
+            boxed("memory", &self.memory(), MEMORY_WIDTH, PANE_HEIGHT),
+            boxed("stack", &self.stack(), STACK_WIDTH, PANE_HEIGHT),
+            boxed(
+                "breaks",
+                &self.breakpoints(),
+                BREAKPOINTS_WIDTH,
+                PANE_HEIGHT,
+            ),
+        ]);
+
+        let mut lines = top;
+        lines.extend(bottom);
+        lines.push(match &self.command {
+            Some(command) => format!(":{}", command),
+            None => format!(
+                "{}  {}",
+                if self.running { "running" } else { "stopped" },
+                self.message
+            ),
+        });
+        lines.push(KEYS.to_string());
+        lines.join("\n")
+    }
+
+    // two pixel rows per line with half blocks, displays larger than 64x32
+    // showing every second, third... pixel
+    fn display(&self) -> Vec<String> {
+        let cpu = &self.monitor.cpu;
+        let (width, height) = (cpu.display_width(), cpu.display_height());
+        let scale = width
+            .div_ceil(DISPLAY_WIDTH)
+            .max(height.div_ceil(PANE_HEIGHT * 2))
+            .max(1);
+        let screen = cpu.screen();
+        let pixel = |x: usize, y: usize| screen.get(y * width + x).copied().unwrap_or(0);
+        (0..height.div_ceil(scale * 2))
+            .map(|line| {
+                let y = line * scale * 2;
+                (0..width.div_ceil(scale))
+                    .map(|column| {
+                        let x = column * scale;
+                        match (pixel(x, y), pixel(x, y + scale)) {
+                            (0, 0) => ' ',
+                            (_, 0) => '▀',
+                            (0, _) => '▄',
+                            _ => '█',
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn registers(&self) -> Vec<String> {
+        let cpu = &self.monitor.cpu;
+        let mut lines: Vec<String> = (0..8)
+            .map(|x| {
+                format!(
+                    "V{:X} {:02X}    V{:X} {:02X}",
+                    x,
+                    cpu.v[x],
+                    x + 8,
+                    cpu.v[x + 8]
+                )
+            })
+            .collect();
+        lines.push(String::new());
+        lines.push(format!("I  {:04X}  PC {:04X}", cpu.i, cpu.pc));
+        lines.push(format!("SP {:02X}    DT {:02X}", cpu.sp, cpu.dt));
+        lines.push(format!("cycles {}", cpu.cycles()));
+        lines
+    }
+
+    fn disassembly(&self) -> Vec<String> {
+        let cpu = &self.monitor.cpu;
+        let start = cpu.pc - cpu.pc.min(DISASSEMBLY_CONTEXT * 2);
+        (0..PANE_HEIGHT as u16)
+            .map(|n| start + n * 2)
+            .take_while(|address| (*address as usize) + 1 < cpu.memory.len())
+            .map(|address| {
+                let a = address as usize;
+                let opcode = (cpu.memory[a] as u16) << 8 | cpu.memory[a + 1] as u16;
+                format!(
+                    "{}{}{:03X} {:04X} {}",
+                    if address == cpu.pc { '>' } else { ' ' },
+                    if self.monitor.debugger().has_breakpoint(address) {
+                        '*'
+                    } else {
+                        ' '
+                    },
+                    address,
+                    opcode,
+                    cpu.platform().decode(opcode)
+                )
+            })
+            .collect()
+    }
+
+    // where the memory pane starts when showing the last rows of the 16-bit
+    // address space, or of memory when it's smaller
+    fn last_memory_start(&self) -> u16 {
+        let len = self.monitor.cpu.memory.len().min(0x10000);
+        (len - MEMORY_ROW * PANE_HEIGHT) as u16
+    }
+
+    fn memory(&self) -> Vec<String> {
+        let len = self.monitor.cpu.memory.len();
+        let start = (self.memory_start as usize).min(len);
+        let end = (start + MEMORY_ROW * PANE_HEIGHT).min(len);
+        self.monitor.cpu.memory[start..end]
+            .chunks(MEMORY_ROW)
+            .enumerate()
+            .map(|(row, bytes)| {
+                let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                format!("{:03X}  {}", start + row * MEMORY_ROW, bytes.join(" "))
+            })
+            .collect()
+    }
+
+    // innermost call first
+    fn stack(&self) -> Vec<String> {
+        let cpu = &self.monitor.cpu;
+        cpu.stack[..cpu.sp as usize]
+            .iter()
+            .rev()
+            .map(|address| format!("{:#05X}", address))
+            .collect()
+    }
+
+    fn breakpoints(&self) -> Vec<String> {
+        self.monitor
+            .debugger()
+            .breakpoints()
+            .map(|address| format!("{:#05X}", address))
+            .collect()
+    }
+}
+
+// Draws a box around `lines`, cut or padded to `width` by `height`.
+fn boxed(title: &str, lines: &[String], width: usize, height: usize) -> Vec<String> {
+    let mut out = Vec::with_capacity(height + 2);
+    out.push(format!(
+        "┌{}{}┐",
+        title,
+        "─".repeat(width.saturating_sub(title.chars().count()))
+    ));
+    for n in 0..height {
+        let line: String = lines
+            .get(n)
+            .map_or("", String::as_str)
+            .chars()
+            .take(width)
+            .collect();
+        let padding = width - line.chars().count();
+        out.push(format!("│{}{}│", line, " ".repeat(padding)));
+    }
+    out.push(format!("└{}┘", "─".repeat(width)));
+    out
+}
+
+fn side_by_side(panes: &[Vec<String>]) -> Vec<String> {
+    let height = panes.iter().map(Vec::len).max().unwrap_or(0);
+    (0..height)
+        .map(|n| panes.iter().map(|pane| pane[n].as_str()).collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tui;
+    use crate::cpu::Cpu;
+    use crate::monitor::Monitor;
+
+    fn tui() -> Tui {
+        let mut cpu = Cpu::new();
+        // V0 = 1, call 0x206, 0x204: jump 0x204, 0x206: draw the 0 glyph
+        cpu.load_rom(&[0x60, 0x01, 0x22, 0x06, 0x12, 0x04, 0xD0, 0x05, 0x00, 0xEE]);
+        Tui::new(Monitor::new(cpu))
+    }
+
+    #[test]
+    fn test_render() {
+        let mut tui = tui();
+        tui.handle_key(b's');
+        tui.handle_key(b's');
+        tui.handle_key(b's');
+
+        let screen = tui.render();
+        let lines: Vec<&str> = screen.lines().collect();
+        let width = lines[0].chars().count();
+        assert!(lines[..36].iter().all(|line| line.chars().count() == width));
+        assert!(lines[0].starts_with("┌display─"));
+        // the 0 glyph drawn at 1, 1
+        assert!(lines[1].starts_with("│ ▄▄▄▄ "));
+        assert!(lines[2].starts_with("│ █  █ "));
+        assert!(screen.contains("V0 01"));
+        assert!(screen.contains("> 208 00EE RET"));
+        assert!(screen.contains("│0x204"));
+        assert!(lines[36].starts_with("stopped  0x208  00EE  RET"));
+    }
+
+    #[test]
+    fn test_memory_pane_stays_in_memory() {
+        let mut cpu = Cpu::new();
+        // I = 0xFFF, V0 = 0x10, I += V0
+        cpu.load_rom(&[0xAF, 0xFF, 0x60, 0x10, 0xF0, 0x1E]);
+        let mut tui = Tui::new(Monitor::new(cpu));
+        for _ in 0..3 {
+            tui.handle_key(b's');
+        }
+        assert_eq!(tui.monitor.cpu.i, 0x100F);
+
+        tui.handle_key(b'm');
+        assert!(tui.render().contains("│F80  00 00"));
+    }
+
+    #[test]
+    fn test_breakpoints_and_running() {
+        let mut tui = tui();
+        tui.handle_key(b':');
+        for key in b"break 204\r" {
+            tui.handle_key(*key);
+        }
+        assert!(tui.render().contains("│0x204"));
+
+        tui.handle_key(b'r');
+        assert!(tui.is_running());
+        tui.tick();
+        assert!(!tui.is_running());
+        assert_eq!(tui.monitor.cpu.pc, 0x204);
+        assert!(tui.render().contains("breakpoint at 0x204"));
+
+        tui.handle_key(b'b');
+        assert!(!tui.render().contains("│0x204"));
+        tui.handle_key(b'q');
+        assert!(tui.is_done());
+    }
+}
@@ -0,0 +1,340 @@
+use crate::cdp1802::{Bus, Cdp1802};
+
+const RAM_SIZE: usize = 4096;
+/// Largest monitor ROM `Vip::new` takes.
+pub const MONITOR_SIZE: usize = 512;
+/// Largest interpreter `Vip::new` takes, loaded below the program at 0x200.
+pub const INTERPRETER_SIZE: usize = 0x200;
+/// Largest program `Vip::load_rom` takes, filling the RAM from 0x200.
+pub const MAX_ROM_SIZE: usize = RAM_SIZE - 0x200;
+// the monitor ROM answers from 0x8000 up
+const ROM_START: u16 = 0x8000;
+
+// The 1861 counts 14 machine cycles per line and 262 lines per frame, 128 of
+// which are displayed.
+const LINE_CYCLES: u32 = 14;
+const FRAME_LINES: u32 = 262;
+const DISPLAY_START: u32 = 80;
+const DISPLAY_END: u32 = DISPLAY_START + VIDEO_LINES as u32;
+// the interrupt is requested for the two lines before the display starts
+const INTERRUPT_LINES: u32 = 2;
+// EF1 warns of the first and last 4 lines of the display
+const EF1_LINES: u32 = 4;
+
+pub const VIDEO_WIDTH: usize = 64;
+pub const VIDEO_LINES: usize = 128;
+
+struct VipBus {
+    ram: Vec<u8>,
+    monitor: Vec<u8>,
+    // the monitor shows up everywhere after a reset, until an address in the
+    // ROM is accessed
+    rom_latch: bool,
+    keys: [bool; 16],
+    // key selected with OUT 2, read back through EF3
+    key_latch: u8,
+    display_on: bool,
+    // line the 1861 is on
+    line: u32,
+}
+
+impl Bus for VipBus {
+    fn read(&mut self, address: u16) -> u8 {
+        if address >= ROM_START {
+            self.rom_latch = false;
+        }
+        if address >= ROM_START || self.rom_latch {
+            self.monitor[address as usize % MONITOR_SIZE]
+        } else {
+            self.ram[address as usize % RAM_SIZE]
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if address < ROM_START {
+            self.ram[address as usize % RAM_SIZE] = value;
+        }
+    }
+
+    // INP 1 turns the display on, OUT 1 turns it off
+    fn input(&mut self, port: u8) -> u8 {
+        if port == 1 {
+            self.display_on = true;
+        }
+        0
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            1 => self.display_on = false,
+            2 => self.key_latch = value & 0xF,
+            _ => {}
+        }
+    }
+
+    fn flag(&mut self, n: u8) -> bool {
+        match n {
+            1 => {
+                (DISPLAY_START - EF1_LINES..DISPLAY_START).contains(&self.line)
+                    || (DISPLAY_END - EF1_LINES..DISPLAY_END).contains(&self.line)
+            }
+            3 => self.keys[self.key_latch as usize],
+            _ => false,
+        }
+    }
+}
+
+/// RCA COSMAC VIP with 4K of RAM, running the original CHIP-8 interpreter
+/// on a CDP1802, as a reference for the high-level `Cpu`.
+///
+/// The monitor ROM and the interpreter are the images of the real machine,
+/// supplied by the user. After a reset the monitor runs, finds out the RAM
+/// size and, the C key not being held, starts the interpreter at 0x0000,
+/// which runs the program at 0x200. The 1861 fetches the display by DMA in
+/// the middle of the frame and interrupts the CPU right before, the
+/// interpreter's display routine timing itself against both.
+pub struct Vip {
+    cpu: Cdp1802,
+    bus: VipBus,
+    interpreter: Vec<u8>,
+    // machine cycles into the current frame
+    cycle: u32,
+    // display line fetched last
+    dma_line: Option<u32>,
+    // what the 1861 displayed, one bit per pixel
+    video: [u8; VIDEO_WIDTH / 8 * VIDEO_LINES],
+    frames: u64,
+}
+
+impl Vip {
+    pub fn new(monitor: &[u8], interpreter: &[u8]) -> Vip {
+        assert!(monitor.len() <= MONITOR_SIZE, "the monitor is 512 bytes");
+        assert!(
+            interpreter.len() <= INTERPRETER_SIZE,
+            "the interpreter is 512 bytes"
+        );
+        let mut rom = monitor.to_vec();
+        rom.resize(MONITOR_SIZE, 0);
+
+        let mut vip = Vip {
+            cpu: Cdp1802::new(),
+            bus: VipBus {
+                ram: vec![0; RAM_SIZE],
+                monitor: rom,
+                rom_latch: true,
+                keys: [false; 16],
+                key_latch: 0,
+                display_on: false,
+                line: 0,
+            },
+            interpreter: interpreter.to_vec(),
+            cycle: 0,
+            dma_line: None,
+            video: [0; VIDEO_WIDTH / 8 * VIDEO_LINES],
+            frames: 0,
+        };
+        vip.load_rom(&[]);
+        vip
+    }
+
+    /// Clears the RAM, loads the interpreter and a program at 0x200, and
+    /// resets the machine.
+    pub fn load_rom(&mut self, rom: &[u8]) {
+        assert!(
+            rom.len() <= MAX_ROM_SIZE,
+            "the program is past the end of RAM"
+        );
+        let ram = &mut self.bus.ram;
+        ram.iter_mut().for_each(|byte| *byte = 0);
+        ram[..self.interpreter.len()].copy_from_slice(&self.interpreter);
+        ram[0x200..0x200 + rom.len()].copy_from_slice(rom);
+        self.reset();
+    }
+
+    /// What the RESET switch does, the RAM being kept.
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.bus.rom_latch = true;
+        self.bus.display_on = false;
+        self.cycle = 0;
+        self.dma_line = None;
+        self.video = [0; VIDEO_WIDTH / 8 * VIDEO_LINES];
+        self.frames = 0;
+    }
+
+    pub fn key_down(&mut self, index: u8) {
+        self.bus.keys[index as usize] = true;
+    }
+
+    pub fn key_up(&mut self, index: u8) {
+        self.bus.keys[index as usize] = false;
+    }
+
+    /// Runs the machine for one frame of the 1861, 3668 machine cycles.
+    /// Instructions straddling frames are paid for out of the next one.
+    pub fn run_frame(&mut self) {
+        let frame_cycles = FRAME_LINES * LINE_CYCLES;
+        while self.cycle < frame_cycles {
+            let line = self.cycle / LINE_CYCLES;
+            self.bus.line = line;
+
+            if (DISPLAY_START..DISPLAY_END).contains(&line) && self.dma_line != Some(line) {
+                self.dma_line = Some(line);
+                self.fetch_line(line - DISPLAY_START);
+                continue;
+            }
+            if self.bus.display_on
+                && (DISPLAY_START - INTERRUPT_LINES..DISPLAY_START).contains(&line)
+                && self.cpu.interrupt()
+            {
+                self.cycle += 1;
+                continue;
+            }
+            if self.cpu.idle {
+                // nothing happens before the next line
+                self.cycle = (line + 1) * LINE_CYCLES;
+                continue;
+            }
+            self.cycle += self.cpu.step(&mut self.bus);
+        }
+        self.cycle -= frame_cycles;
+        self.dma_line = None;
+        self.frames += 1;
+    }
+
+    // Each line is 8 bytes of DMA, 8 machine cycles. With the display off,
+    // the line stays dark and the CPU runs on.
+    fn fetch_line(&mut self, line: u32) {
+        let start = line as usize * VIDEO_WIDTH / 8;
+        let bytes = &mut self.video[start..start + VIDEO_WIDTH / 8];
+        if !self.bus.display_on {
+            bytes.iter_mut().for_each(|byte| *byte = 0);
+            return;
+        }
+        for byte in bytes {
+            *byte = self.cpu.dma_out(&mut self.bus);
+        }
+        self.cycle += 8;
+    }
+
+    pub fn cpu(&self) -> &Cdp1802 {
+        &self.cpu
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.bus.ram
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// The 64x128 picture of the last frame, one bit per pixel, most
+    /// significant first.
+    pub fn video(&self) -> &[u8] {
+        &self.video
+    }
+
+    /// The picture as the 64x32 CHIP-8 display, one byte per pixel like
+    /// `Cpu::screen`, the interpreter showing every row on 4 lines.
+    pub fn screen(&self) -> Vec<u8> {
+        let mut screen = Vec::with_capacity(VIDEO_WIDTH * VIDEO_LINES / 4);
+        for row in 0..VIDEO_LINES / 4 {
+            let line = &self.video[row * 4 * VIDEO_WIDTH / 8..][..VIDEO_WIDTH / 8];
+            for x in 0..VIDEO_WIDTH {
+                screen.push(line[x / 8] >> (7 - x % 8) & 1);
+            }
+        }
+        screen
+    }
+
+    /// Whether the tone is on, Q driving the speaker.
+    pub fn sound(&self) -> bool {
+        self.cpu.q
+    }
+
+    // The interpreter keeps the CHIP-8 program counter in R5, I in RA and
+    // V0 to VF at the top of the page below the display, the last one.
+
+    pub fn pc(&self) -> u16 {
+        self.cpu.r[5]
+    }
+
+    pub fn i(&self) -> u16 {
+        self.cpu.r[0xA]
+    }
+
+    pub fn v(&self) -> [u8; 16] {
+        let mut v = [0; 16];
+        v.copy_from_slice(&self.bus.ram[RAM_SIZE - 0x110..RAM_SIZE - 0x100]);
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bus, Vip, VIDEO_LINES};
+
+    // Stands in for the monitor: the reset code jumps into the ROM, which
+    // hands over to the RAM at 0x0000 with R0 cleared.
+    const MONITOR: [u8; 13] = [
+        0xF8, 0x80, 0xB2, 0xF8, 0x08, 0xA2, 0xE2, 0xD2, //
+        0xF8, 0x00, 0xA0, 0xB0, 0xD0,
+    ];
+
+    // Stands in for the interpreter: turns the display on and idles, the
+    // interrupt routine pointing the DMA at 0x0F00 and waiting for the
+    // display to start, when the interrupt request ends.
+    fn interpreter() -> Vec<u8> {
+        let mut code = vec![
+            // 0x00: R3 = 0x0008, SEP 3
+            0xF8, 0x00, 0xB3, 0xF8, 0x08, 0xA3, 0xD3, 0x00, //
+            // 0x08: R2 = 0x0EFF, R1 = 0x0041
+            0xF8, 0x0E, 0xB2, 0xF8, 0xFF, 0xA2, 0xF8, 0x00, 0xB1, 0xF8, 0x41, 0xA1,
+            // 0x14: SEX 2, INP 1, IDL, BR 0x16
+            0xE2, 0x69, 0x00, 0x30, 0x16,
+        ];
+        code.resize(0x40, 0);
+        code.extend_from_slice(&[
+            // 0x40: RET, entered at 0x41: DEC 2, SAV, R0 = 0x0F00
+            0x70, 0x22, 0x78, 0xF8, 0x0F, 0xB0, 0xF8, 0x00, 0xA0,
+            // 0x49: B1 0x49, BR 0x40
+            0x34, 0x49, 0x30, 0x40,
+        ]);
+        code
+    }
+
+    #[test]
+    fn test_display_dma() {
+        let mut vip = Vip::new(&MONITOR, &interpreter());
+        let mut rom = vec![0; 0xD10];
+        rom[0xD00..].copy_from_slice(&[0xFF, 0, 0, 0, 0, 0, 0, 0x81, 0x80, 0, 0, 0, 0, 0, 0, 0]);
+        vip.load_rom(&rom);
+
+        vip.run_frame();
+        assert_eq!(&vip.video()[..16], &rom[0xD00..]);
+        // the routine sets up the DMA once per frame, it runs through 1024
+        // bytes
+        assert_eq!(vip.cpu().r[0], 0x0F00 + 8 * VIDEO_LINES as u16);
+        assert_eq!((vip.cpu().p, vip.cpu().x), (3, 2));
+
+        vip.run_frame();
+        let screen = vip.screen();
+        assert_eq!(&screen[..8], &[1; 8]);
+        assert_eq!(screen[63], 1);
+        assert_eq!(vip.frames(), 2);
+    }
+
+    #[test]
+    fn test_keypad_latch() {
+        let mut vip = Vip::new(&MONITOR, &interpreter());
+        vip.key_down(0xC);
+        vip.bus.output(2, 0x0C);
+        assert!(vip.bus.flag(3));
+        vip.bus.output(2, 0x0B);
+        assert!(!vip.bus.flag(3));
+        vip.key_up(0xC);
+        vip.bus.output(2, 0x0C);
+        assert!(!vip.bus.flag(3));
+    }
+}
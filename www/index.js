@@ -4,6 +4,26 @@ import { memory } from "chip-9/chip_9_bg";
 
 const cpu = Cpu.new();
 
+// ?platform=eti-660&font=vip&rom=NAME picks the machine, its font and the
+// program to run
+const params = new URLSearchParams(window.location.search);
+if (params.has("platform") && !cpu.select_platform(params.get("platform"))) {
+  console.error(`unknown platform ${params.get("platform")}`);
+}
+if (params.has("font") && !cpu.select_font(params.get("font"))) {
+  console.error(`unknown font ${params.get("font")}`);
+}
+
+// SUPER-CHIP flags, e.g. high scores, kept in local storage by ROM hash
+cpu.set_flags_hook({
+  load: (key) => {
+    const flags = localStorage.getItem(`flags-${key}`);
+    return flags === null ? undefined : Uint8Array.from(JSON.parse(flags));
+  },
+  save: (key, flags) =>
+    localStorage.setItem(`flags-${key}`, JSON.stringify(Array.from(flags))),
+});
+
 const translateKeys = {
   49: 0x1, // 1
   50: 0x2, // 2
@@ -23,62 +43,124 @@ const translateKeys = {
   86: 0xf // V
 };
 
+// the second CHIP-8X keypad, on the numeric keypad
+const translateSecondKeys = {
+  103: 0x1, // 7
+  104: 0x2, // 8
+  105: 0x3, // 9
+  111: 0xc, // /
+  100: 0x4, // 4
+  101: 0x5, // 5
+  102: 0x6, // 6
+  106: 0xd, // *
+  97: 0x7, // 1
+  98: 0x8, // 2
+  99: 0x9, // 3
+  109: 0xe, // -
+  96: 0xa, // 0
+  110: 0x0, // .
+  13: 0xb, // Enter
+  107: 0xf // +
+};
+
 
 const run = async () => {
-  const WIDTH = 64;
-  const HEIGHT = 32;
   const canvas = document.getElementById("chip-8");
   const ctx = canvas.getContext("2d");
 
-  const programMemory = new Uint8Array(memory.buffer, cpu.memory_ptr(), 4096);
-  const displayMemory = new Uint8Array(memory.buffer, cpu.display_ptr(), 4096);
-
-  ctx.fillStyle = "black";
-  ctx.fillRect(0, 0, WIDTH, HEIGHT);
+  // loading a program or switching modes can resize the display
+  let width, height;
+  const resize = () => {
+    width = cpu.display_width();
+    height = cpu.display_height();
+    canvas.width = width;
+    canvas.height = height;
+    ctx.fillStyle = "black";
+    ctx.fillRect(0, 0, width, height);
+  };
+  resize();
 
   const loadRom = (rom) =>
     fetch(`roms/${rom}`)
       .then((r) => r.arrayBuffer())
       .then((buffer) => {
-        cpu.reset();
-        const rom = new DataView(buffer, 0, buffer.byteLength);
-        for (let i = 0; i < rom.byteLength; i++) {
-          programMemory[0x200 + i] = rom.getUint8(i);
+        if (!cpu.load_rom(new Uint8Array(buffer))) {
+          console.error(`${rom} doesn't fit in memory`);
         }
-
         updateDisplay();
       });
 
   const updateDisplay = () => {
-    const imageData = ctx.createImageData(WIDTH, HEIGHT);
-    for (let i = 0; i < displayMemory.length; i++) {
-      imageData.data[i * 4] = displayMemory[i] === 1 ? 0x33 : 0;
-      imageData.data[i * 4 + 1] = displayMemory[i] === 1 ? 0xff : 0;
-      imageData.data[i * 4 + 2] = displayMemory[i] === 1 ? 0x66 : 0;
-      imageData.data[i * 4 + 3] = 255;
+    if (cpu.display_width() !== width || cpu.display_height() !== height) {
+      resize();
     }
-    ctx.putImageData(imageData, 0, 0);
+    cpu.update_framebuffer();
+    const pixels = new Uint8ClampedArray(
+      memory.buffer,
+      cpu.framebuffer_ptr(),
+      width * height * 4
+    );
+    ctx.putImageData(new ImageData(pixels, width, height), 0, 0);
   };
 
   const runloop = () => {
-    for (let i = 0; i < 10; i++) {
-      cpu.process_opcode();
-    }
-    cpu.decrement_timers();
+    cpu.tick();
     updateDisplay();
     window.requestAnimationFrame(runloop);
   };
 
-  loadRom("TETRIS");
+  loadRom(params.get("rom") || "TETRIS");
   window.requestAnimationFrame(runloop);
 
+  // MegaChip sounds, pulled from the CPU on the main thread since that's
+  // where it lives. Browsers only start audio after a key press.
+  const audio = new AudioContext();
+  const sound = audio.createScriptProcessor(1024, 0, 1);
+  sound.onaudioprocess = event => {
+    const out = event.outputBuffer.getChannelData(0);
+    cpu.fill_audio(out, audio.sampleRate);
+  };
+  sound.connect(audio.destination);
+  document.addEventListener("keydown", () => audio.resume(), { once: true });
+
+  const speedKeys = {
+    80: () => (cpu.is_paused() ? cpu.resume() : cpu.pause()), // P
+    78: () => cpu.advance_frame(), // N
+    219: () => cpu.slow_down(), // [
+    221: () => cpu.set_speed(cpu.speed() * 2), // ]
+    48: () => cpu.set_speed(1) // 0
+  };
+
   document.addEventListener("keydown", event => {
+    if (speedKeys[event.keyCode]) {
+      speedKeys[event.keyCode]();
+      return;
+    }
+    if (event.keyCode in translateSecondKeys) {
+      cpu.second_key_down(translateSecondKeys[event.keyCode]);
+      return;
+    }
     cpu.key_down(translateKeys[event.keyCode]);
   });
 
   document.addEventListener("keyup", event => {
+    if (event.keyCode in translateSecondKeys) {
+      cpu.second_key_up(translateSecondKeys[event.keyCode]);
+      return;
+    }
     cpu.key_up(translateKeys[event.keyCode]);
   });
+
+  // characters typed into the port field go to the CHIP-8X input port, the
+  // way the VIP's ASCII keyboard sent them, one for each FXFB
+  const port = document.getElementById("port");
+  port.addEventListener("keydown", event => {
+    event.stopPropagation();
+    if (event.key.length === 1) {
+      cpu.set_port_input(event.key.charCodeAt(0) & 0xff);
+    }
+  });
+  port.addEventListener("keyup", event => event.stopPropagation());
 };
 
 run();
\ No newline at end of file